@@ -38,6 +38,7 @@ data_path="$(pwd)/data-equestrian"
 py_path=""
 gc_path="$6"
 database="isabelle"
+store="mongo"
 gh_login=""
 gh_password=""
 plugin_dir=""
@@ -76,6 +77,10 @@ while test -n "$1" ; do
             database="$2"
             shift 1
             ;;
+        --store)
+            store="$2"
+            shift 1
+            ;;
         --gh-login)
             gh_login="$2"
             shift 1
@@ -130,6 +135,7 @@ RUST_LOG=info RUST_BACKTRACE=1 "${binary}" \
     --data-path "${data_path}" \
     --gc-path "${gc_path}" \
     --database "${database}" \
+    --store "${store}" \
     --py-path "${py_path}" \
     ${cookie_http_insecure:+--cookie-http-insecure} \
     ${plugin_dir+--plugin-dir} ${plugin_dir:+"${plugin_dir}"} \
@@ -21,11 +21,11 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 /// Isabelle - high-performant server for web applications
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
     /// Data path
     #[arg(long, default_value("sample-data"))]
@@ -39,6 +39,10 @@ pub struct Args {
     #[arg(long, default_value("localhost"))]
     pub pub_fqdn: String,
 
+    /// Store backend: local, mongo or sqlite
+    #[arg(long, default_value("mongo"))]
+    pub store: String,
+
     /// Database URL
     #[arg(long, default_value("mongodb://127.0.0.1:27017"))]
     pub db_url: String,
@@ -48,8 +52,8 @@ pub struct Args {
     pub db_name: String,
 
     /// Plugins directory
-    #[arg(long)]
-    pub plugin_dir: String,
+    #[arg(long, required = true)]
+    pub plugin_dir: Option<String>,
 
     /// Google Calendar path
     #[arg(long, default_value(""))]
@@ -64,8 +68,8 @@ pub struct Args {
     pub bind_addr: String,
 
     /// Port number
-    #[arg(long, visible_alias("port"))]
-    pub bind_port: u16,
+    #[arg(long, visible_alias("port"), required = true)]
+    pub bind_port: Option<u16>,
 
     /// First run
     #[arg(long, default_value_t = false)]
@@ -74,4 +78,86 @@ pub struct Args {
     /// Set http-secure on cookies to false
     #[arg(long, default_value_t = false)]
     pub cookie_http_insecure: bool,
+
+    /// Maintenance command to run instead of the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Maintenance commands: they work on the store and exit
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Check items against collection schemas and print the report
+    Validate {
+        /// Collection to check, all collections with schemas by default
+        #[arg(long)]
+        collection: Option<String>,
+    },
+
+    /// Export all items of the collection as JSON Lines or CSV
+    Export {
+        /// Exported collection
+        #[arg(long)]
+        collection: String,
+
+        /// Format: jsonl or csv
+        #[arg(long, default_value("jsonl"))]
+        format: String,
+
+        /// Output file, standard output by default
+        #[arg(long)]
+        output: Option<String>,
+    },
+
+    /// Import items from JSON Lines or CSV into the collection, without hooks
+    Import {
+        /// Collection receiving items
+        #[arg(long)]
+        collection: String,
+
+        /// Format: jsonl or csv
+        #[arg(long, default_value("jsonl"))]
+        format: String,
+
+        /// Input file, standard input by default
+        #[arg(long)]
+        input: Option<String>,
+
+        /// Handling of existing IDs: skip, overwrite or merge
+        #[arg(long, default_value("skip"))]
+        conflict: String,
+    },
+
+    /// Copy collections, settings and internals to another store and verify
+    /// them. Running it again resumes interrupted migration.
+    Migrate {
+        /// Target store backend: local, mongo or sqlite
+        #[arg(long)]
+        to_store: String,
+
+        /// Target database URL, the source one by default
+        #[arg(long)]
+        to_db_url: Option<String>,
+
+        /// Target database name, the source one by default
+        #[arg(long)]
+        to_db_name: Option<String>,
+
+        /// Target data path, the source one by default
+        #[arg(long)]
+        to_data_path: Option<String>,
+
+        /// Only print differences without writing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Remove target items missing in the source
+        #[arg(long, default_value_t = false)]
+        prune: bool,
+
+        /// Collection to migrate, all of them along with settings and
+        /// internals by default
+        #[arg(long)]
+        collection: Option<String>,
+    },
 }
@@ -23,7 +23,6 @@
  */
 use crate::handler::route_call::*;
 use crate::handler::web_response::conv_response;
-use crate::state::store::Store;
 use crate::State;
 use actix_identity::Identity;
 use actix_multipart::Multipart;
@@ -45,7 +44,6 @@ pub async fn url_route(
     let srv_lock = data.server.lock();
     let mut srv = srv_lock.borrow_mut();
     let routes = srv
-        .rw
         .get_internals()
         .await
         .safe_strstr("extra_route", &HashMap::new());
@@ -75,7 +73,6 @@ pub async fn url_post_route(
     let mut srv = srv_lock.borrow_mut();
 
     let routes = srv
-        .rw
         .get_internals()
         .await
         .safe_strstr("extra_route", &HashMap::new());
@@ -104,7 +101,6 @@ pub async fn url_unprotected_route(
     let srv_lock = data.server.lock();
     let mut srv = srv_lock.borrow_mut();
     let routes = srv
-        .rw
         .get_internals()
         .await
         .safe_strstr("extra_unprotected_route", &HashMap::new());
@@ -134,7 +130,6 @@ pub async fn url_unprotected_post_route(
     let mut srv = srv_lock.borrow_mut();
 
     let routes = srv
-        .rw
         .get_internals()
         .await
         .safe_strstr("extra_unprotected_route", &HashMap::new());
@@ -187,7 +182,6 @@ pub async fn url_generic_rest_route(
     let srv_lock = data.server.lock();
     let mut srv = srv_lock.borrow_mut();
     let routes = srv
-        .rw
         .get_internals()
         .await
         .safe_strstr("extra_rest_route", &HashMap::new());
@@ -23,6 +23,7 @@
  */
 use crate::handler::web_response::*;
 use crate::server::user_control::*;
+use crate::state::query::Filter;
 use actix_identity::Identity;
 use actix_multipart::Multipart;
 use actix_web::HttpResponse;
@@ -38,6 +39,22 @@ use std::io::Write;
 use std::path::Path;
 use uuid::Uuid;
 
+/// State handle that plugins set with `fn_set_state` to roll back the
+/// running transaction, optionally giving the reason as `String`.
+pub const TRANSACTION_ABORT_STATE: &str = "transaction_abort";
+
+/// Check if any plugin asked to roll back the transaction, clearing the
+/// request. Returns the reason.
+pub fn take_transaction_abort(srv: &mut crate::state::data::Data) -> Option<String> {
+    match srv.opaque_data.remove(TRANSACTION_ABORT_STATE) {
+        Some(Some(value)) => match value.downcast_ref::<String>() {
+            Some(reason) => Some(reason.clone()),
+            None => Some("Rolled back by plugin".to_string()),
+        },
+        _ => None,
+    }
+}
+
 /// Call hook associated with pre-editing of item data.
 pub async fn call_item_pre_edit_hook(
     srv: &mut crate::state::data::Data,
@@ -134,6 +151,8 @@ pub async fn call_item_list_filter_hook(
     }
 }
 
+/// Call list database filter hook, allowing for narrowing down the query.
+/// Filters returned by plugins must be valid, otherwise the error is given.
 pub async fn call_item_list_db_filter_hook(
     srv: &mut crate::state::data::Data,
     hndl: &str,
@@ -141,7 +160,7 @@ pub async fn call_item_list_db_filter_hook(
     collection: &str,
     context: &str,
     filter_type: &str,
-) -> Vec<String> {
+) -> Result<Vec<Filter>, String> {
     let mut filters = Vec::new();
     for plugin in &mut srv.plugin_pool.plugins {
         let filter = plugin.item_list_db_filter_hook(
@@ -153,10 +172,19 @@ pub async fn call_item_list_db_filter_hook(
             filter_type,
         );
         if filter != "" {
-            filters.push(filter);
+            match Filter::from_json_str(&filter) {
+                Ok(f) => filters.push(f),
+                Err(e) => {
+                    error!(
+                        "Filter hook {} gave malformed filter {}: {}",
+                        hndl, filter, e
+                    );
+                    return Err("Malformed filter from ".to_owned() + hndl + ": " + &e);
+                }
+            }
         }
     }
-    return filters;
+    return Ok(filters);
 }
 
 /// Call HTTP url hook, allowing for responses to web requests.
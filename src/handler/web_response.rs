@@ -21,7 +21,9 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+use crate::state::store::StoreError;
 use actix_web::HttpResponse;
+use isabelle_dm::data_model::process_result::ProcessResult;
 use isabelle_plugin_api::api::WebResponse;
 use std::path::Path;
 
@@ -65,3 +67,23 @@ pub async fn conv_response(resp: WebResponse) -> HttpResponse {
         WebResponse::NotImplemented => todo!(),
     }
 }
+
+/// Convert store error to HttpResponse with failed ProcessResult
+pub fn store_error_response(e: &StoreError) -> HttpResponse {
+    let mut resp = match e {
+        StoreError::NotFound(_) => HttpResponse::NotFound(),
+        StoreError::Invalid(_) => HttpResponse::BadRequest(),
+        StoreError::Conflict(_) | StoreError::Referenced(_) | StoreError::Duplicate(_) => {
+            HttpResponse::Conflict()
+        }
+        StoreError::Unavailable(_) => HttpResponse::ServiceUnavailable(),
+        StoreError::Serialization(_) | StoreError::Failed(_) => HttpResponse::InternalServerError(),
+    };
+    return resp.body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: false,
+            error: e.to_string(),
+        })
+        .unwrap(),
+    );
+}
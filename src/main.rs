@@ -21,7 +21,7 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-use crate::args::Args;
+use crate::args::{Args, Command};
 use chrono::Timelike;
 #[macro_use]
 extern crate lazy_static;
@@ -32,9 +32,11 @@ use std::{str::FromStr, time::Duration};
 
 use crate::notif::email::send_email;
 
-#[cfg(not(feature = "full_file_database"))]
-use crate::state::merger::merge_database;
-use crate::state::store::Store;
+use crate::state::migrate::{migrate, MigrateOptions};
+use crate::state::schema::validate_database;
+use crate::state::store::{open_store, Store};
+use crate::state::transfer::{export_command, import_command};
+use crate::state::webhook::run_webhooks;
 
 mod args;
 mod handler;
@@ -51,9 +53,15 @@ use crate::handler::route::url_unprotected_post_route;
 use crate::handler::route::url_unprotected_route;
 use crate::handler::route_call::call_periodic_job_hook;
 use crate::notif::gcal::*;
+use crate::server::admin::*;
+use crate::server::events::*;
+use crate::server::history::*;
 use crate::server::itm::*;
 use crate::server::login::*;
+use crate::server::search::*;
 use crate::server::user_control::*;
+use crate::server::webhook::*;
+use crate::server::ws::*;
 use std::collections::HashMap;
 
 use crate::server::setting::*;
@@ -67,7 +75,7 @@ use actix_session::SessionMiddleware;
 use actix_web::web::Data;
 use actix_web::{cookie::Key, cookie::SameSite, rt, web, App, HttpServer};
 use clap::Parser;
-use log::info;
+use log::{error, info};
 use std::ops::DerefMut;
 use std::thread;
 
@@ -117,34 +125,146 @@ async fn main() -> std::io::Result<()> {
         srv.py_path = args.py_path.to_string();
         srv.data_path = args.data_path.to_string();
         srv.public_url = args.pub_url.to_string();
-        srv.port = args.bind_port;
+        srv.port = args.bind_port.unwrap_or(0);
 
         info!("Data storage: connecting");
         // Put options to internal structures and connect to database
-        #[cfg(not(feature = "full_file_database"))]
-        {
-            srv.file_rw.connect(&args.data_path, "").await;
-            srv.rw.database_name = args.db_name.clone();
-            srv.rw.connect(&args.db_url, &args.data_path).await;
+        match open_store(&args.store, &args.db_url, &args.db_name, &args.data_path).await {
+            Ok(store) => srv.rw = store,
+            Err(e) => {
+                error!("Data storage: failed to connect: {}", e);
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+            }
         }
 
         info!("Data storage: connected");
 
-        #[cfg(feature = "full_file_database")]
-        {
-            info!(
-                "Database url {} name {} unused",
-                database_name.clone(),
-                db_url.clone()
-            );
-            srv.rw.connect(&data_path, "").await;
+        // Maintenance commands don't need the rest of the server
+        match &args.command {
+            Some(Command::Validate { collection }) => {
+                return match validate_database(srv.rw.as_mut(), collection).await {
+                    Ok(0) => Ok(()),
+                    Ok(cnt) => {
+                        error!("Validation: {} invalid items", cnt);
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            "validation failed",
+                        ))
+                    }
+                    Err(e) => {
+                        error!("Validation: failed: {}", e);
+                        Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                    }
+                };
+            }
+            Some(Command::Export {
+                collection,
+                format,
+                output,
+            }) => {
+                return match export_command(srv.rw.as_mut(), collection, format, output).await {
+                    Ok(cnt) => {
+                        info!("Export: {} items of {}", cnt, collection);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        error!("Export: failed: {}", e);
+                        Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                    }
+                };
+            }
+            Some(Command::Import {
+                collection,
+                format,
+                input,
+                conflict,
+            }) => {
+                let res =
+                    import_command(srv.rw.as_mut(), collection, format, input, conflict).await;
+                return match res {
+                    Ok(stats) => {
+                        info!(
+                            "Import: {} imported, {} skipped, {} failed",
+                            stats.imported, stats.skipped, stats.failed
+                        );
+                        for e in &stats.errors {
+                            error!("Import: {}", e);
+                        }
+                        if stats.failed == 0 {
+                            Ok(())
+                        } else {
+                            Err(std::io::Error::new(
+                                std::io::ErrorKind::Other,
+                                "import failed",
+                            ))
+                        }
+                    }
+                    Err(e) => {
+                        error!("Import: failed: {}", e);
+                        Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                    }
+                };
+            }
+            Some(Command::Migrate {
+                to_store,
+                to_db_url,
+                to_db_name,
+                to_data_path,
+                dry_run,
+                prune,
+                collection,
+            }) => {
+                let to_db_url = to_db_url.as_ref().unwrap_or(&args.db_url);
+                let to_db_name = to_db_name.as_ref().unwrap_or(&args.db_name);
+                let to_data_path = to_data_path.as_ref().unwrap_or(&args.data_path);
+                if *to_store == args.store
+                    && *to_db_url == args.db_url
+                    && *to_db_name == args.db_name
+                    && *to_data_path == args.data_path
+                {
+                    error!("Migration: source and target are the same");
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "same store",
+                    ));
+                }
+
+                let res = open_store(to_store, to_db_url, to_db_name, to_data_path).await;
+                let mut target = match res {
+                    Ok(target) => target,
+                    Err(e) => {
+                        error!("Migration: failed to connect to target: {}", e);
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+                    }
+                };
+                let opts = MigrateOptions {
+                    dry_run: *dry_run,
+                    prune: *prune,
+                    collection: collection.clone(),
+                };
+                return match migrate(srv.rw.as_mut(), target.as_mut(), &opts).await {
+                    Ok(0) => Ok(()),
+                    Ok(cnt) => {
+                        error!("Migration: {} collections failed verification", cnt);
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            "verification failed",
+                        ))
+                    }
+                    Err(e) => {
+                        error!("Migration: failed: {}", e);
+                        Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                    }
+                };
+            }
+            None => {}
         }
 
         // Load plugins
         info!("Plugins: loading");
         {
             let s = &mut srv;
-            s.plugin_pool.load_plugins(&args.plugin_dir);
+            s.plugin_pool.load_plugins(args.plugin_dir.as_deref().unwrap_or(""));
             info!("Plugins: ensuring operation");
             s.plugin_pool.ping_plugins();
         }
@@ -163,7 +283,6 @@ async fn main() -> std::io::Result<()> {
         // Get all extra routes and put them to map
         {
             let routes = srv
-                .rw
                 .get_internals()
                 .await
                 .safe_strstr("extra_route", &HashMap::new());
@@ -175,7 +294,6 @@ async fn main() -> std::io::Result<()> {
         }
         {
             let routes = srv
-                .rw
                 .get_internals()
                 .await
                 .safe_strstr("extra_unprotected_route", &HashMap::new());
@@ -187,7 +305,6 @@ async fn main() -> std::io::Result<()> {
         }
         {
             let routes = srv
-                .rw
                 .get_internals()
                 .await
                 .safe_strstr("extra_rest_route", &HashMap::new());
@@ -198,12 +315,32 @@ async fn main() -> std::io::Result<()> {
             }
         }
 
-        // If it is a first run, merge database.
-        #[cfg(not(feature = "full_file_database"))]
-        if args.first_run {
+        // If it is a first run, migrate data folder to the database. Local
+        // store already works on top of the data folder, so there is
+        // nothing to migrate.
+        if args.first_run && args.store != "local" {
             let m = &mut srv;
-            info!("Flow: first run - merge database and exit");
-            merge_database(&mut m.file_rw, &mut m.rw).await;
+            info!("Flow: first run - migrate database and exit");
+            // Data folder is opened only for the migration
+            if let Err(e) = m.file_rw.connect(&args.data_path, "").await {
+                error!("Data storage: failed to open {}: {}", &args.data_path, e);
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+            }
+            let opts = MigrateOptions::default();
+            match migrate(&mut m.file_rw, m.rw.as_mut(), &opts).await {
+                Ok(0) => {}
+                Ok(cnt) => {
+                    error!("Flow: {} collections failed verification", cnt);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "verification failed",
+                    ));
+                }
+                Err(e) => {
+                    error!("Flow: failed to migrate database: {}", e);
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+                }
+            }
         }
     }
 
@@ -216,6 +353,12 @@ async fn main() -> std::io::Result<()> {
     let data_clone = data.clone();
     info!("Flow: Starting server");
 
+    // Expire items of TTL indexes
+    rt::spawn(run_expiry(data.clone()));
+
+    // Deliver item changes to webhooks
+    rt::spawn(run_webhooks(data.clone()));
+
     // periodic tasks
     thread::spawn(move || {
         let expression = "*   *   *     *       *  *  *";
@@ -251,8 +394,25 @@ async fn main() -> std::io::Result<()> {
                 args.cookie_http_insecure,
             ))
             .route("/itm/edit", web::post().to(itm_edit))
+            .route("/itm/bulk_edit", web::post().to(itm_bulk_edit))
             .route("/itm/del", web::post().to(itm_del))
             .route("/itm/list", web::get().to(itm_list))
+            .route("/itm/search", web::get().to(itm_search))
+            .route("/itm/events", web::get().to(itm_events))
+            .route("/ws", web::get().to(ws_connect))
+            .route("/itm/restore", web::post().to(itm_restore))
+            .route("/itm/history", web::get().to(itm_history))
+            .route("/itm/revert", web::post().to(itm_revert))
+            .route("/itm/purge_deleted", web::post().to(itm_purge_deleted))
+            .route("/itm/indexes", web::get().to(itm_indexes))
+            .route("/admin/export", web::get().to(admin_export))
+            .route("/admin/import", web::post().to(admin_import))
+            .route("/webhook/list", web::get().to(webhook_list))
+            .route("/webhook/edit", web::post().to(webhook_edit))
+            .route("/webhook/del", web::post().to(webhook_del))
+            .route("/webhook/deliveries", web::get().to(webhook_deliveries))
+            .route("/webhook/redeliver", web::post().to(webhook_redeliver))
+            .route("/webhook/test", web::post().to(webhook_test))
             .route("/login", web::post().to(login))
             .route("/register", web::post().to(register))
             .route("/gen_otp", web::post().to(gen_otp))
@@ -291,7 +451,7 @@ async fn main() -> std::io::Result<()> {
         }
         app
     })
-    .bind((args.bind_addr, args.bind_port))?
+    .bind((args.bind_addr, args.bind_port.unwrap_or(0)))?
     .run();
     let th = rt::spawn(srv);
     let _ = th.await;
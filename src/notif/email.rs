@@ -22,7 +22,6 @@
  * DEALINGS IN THE SOFTWARE.
  */
 
-use crate::state::store::Store;
 use lettre::message::header::ContentType;
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{Message, SmtpTransport, Transport};
@@ -32,7 +31,7 @@ use log::{error, info};
 pub async fn send_email(srv: &mut crate::state::data::Data, to: &str, subject: &str, body: &str) {
     info!("Checking options...");
 
-    let settings = srv.rw.get_settings().await.clone();
+    let settings = srv.get_settings().await.clone();
 
     let smtp_server = settings.safe_str("smtp_server", "");
     let smtp_login = settings.safe_str("smtp_login", "");
@@ -39,7 +39,7 @@ pub async fn sync_with_google(
     name: String,
     date_time: String,
 ) {
-    let settings = srv.rw.get_settings().await;
+    let settings = srv.get_settings().await;
     if !settings.safe_bool("sync_google_cal", false)
         || settings.safe_str("sync_google_creds", "") == ""
         || settings.safe_str("sync_google_email", "") == ""
@@ -84,7 +84,7 @@ pub async fn sync_with_google(
 
 /// Initialize Google Calendar
 pub async fn init_google(srv: &mut crate::state::data::Data) -> String {
-    let settings = srv.rw.get_settings().await;
+    let settings = srv.get_settings().await;
     if !settings.safe_bool("sync_google_cal", false)
         || settings.safe_str("sync_google_creds", "") == ""
         || settings.safe_str("sync_google_email", "") == ""
@@ -130,7 +130,7 @@ pub async fn init_google(srv: &mut crate::state::data::Data) -> String {
 
 /// Authenticate Google
 pub async fn auth_google(srv: &mut crate::state::data::Data) -> String {
-    let settings = srv.rw.get_settings().await;
+    let settings = srv.get_settings().await;
     if !settings.safe_bool("sync_google_cal", false)
         || settings.safe_str("sync_google_creds", "") == ""
     {
@@ -190,7 +190,7 @@ pub async fn auth_google_end(
     state: String,
     code: String,
 ) -> String {
-    let settings = srv.rw.get_settings().await;
+    let settings = srv.get_settings().await;
     info!("Ending Google authentication...");
     if !settings.safe_bool("sync_google_cal", false)
         || settings.safe_str("sync_google_creds", "") == ""
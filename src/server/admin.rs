@@ -0,0 +1,346 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::handler::web_response::store_error_response;
+use crate::server::itm::edit_item;
+use crate::server::user_control::*;
+use crate::state::state::*;
+use crate::state::store::{next_page, read_page};
+use crate::state::transfer::*;
+use actix_identity::Identity;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::{stream, StreamExt};
+use isabelle_dm::data_model::item::Item;
+use isabelle_dm::data_model::process_result::ProcessResult;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Query of export endpoint
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ExportQuery {
+    /// Exported collection
+    pub collection: String,
+
+    /// Format: jsonl (default) or csv
+    pub format: Option<String>,
+}
+
+/// Query of import endpoint
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ImportQuery {
+    /// Collection receiving items
+    pub collection: String,
+
+    /// Format: jsonl (default) or csv
+    pub format: Option<String>,
+
+    /// Handling of existing IDs: skip (default), overwrite or merge
+    pub conflict: Option<String>,
+
+    /// Import through edit hooks, as /itm/edit does
+    pub hooks: Option<bool>,
+}
+
+/// Result of the import
+#[derive(Serialize)]
+pub struct ImportResult {
+    #[serde(flatten)]
+    pub result: ProcessResult,
+
+    #[serde(flatten)]
+    pub stats: ImportStats,
+}
+
+/// Respond with bad request and the error
+fn bad_request(error: &str) -> HttpResponse {
+    return HttpResponse::BadRequest().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: false,
+            error: error.to_string(),
+        })
+        .unwrap(),
+    );
+}
+
+/// Exported collection streamed page by page
+struct Exporter {
+    data: web::Data<State>,
+    collection: String,
+    format: Format,
+    columns: Vec<String>,
+    header: Option<String>,
+    next: Option<u64>,
+}
+
+impl Exporter {
+    /// Get the next chunk: the header or the next page of items
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, std::io::Error>> {
+        if let Some(header) = self.header.take() {
+            return Some(Ok(Bytes::from(header)));
+        }
+
+        let id_min = self.next?;
+        let res = {
+            let srv_lock = self.data.server.lock();
+            let mut srv = srv_lock.borrow_mut();
+            read_page(srv.rw.as_mut(), &self.collection, id_min).await
+        };
+        let itms = match res {
+            Ok(itms) => itms,
+            Err(e) => {
+                // The response has started already, so it's cut short
+                error!("Failed to export {}: {}", self.collection, e);
+                self.next = None;
+                return Some(Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                )));
+            }
+        };
+
+        self.next = next_page(&itms);
+        if itms.is_empty() {
+            return None;
+        }
+        let text: String = itms
+            .iter()
+            .map(|itm| format_item(itm, self.format, &self.columns))
+            .collect();
+        return Some(Ok(Bytes::from(text)));
+    }
+}
+
+/// Export all items of the collection, deleted ones included, streaming
+/// them as JSON Lines or CSV. Admins only.
+pub async fn admin_export(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Collections can't be exported by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    let eq = match serde_qs::from_str::<ExportQuery>(&req.query_string()) {
+        Ok(eq) => eq,
+        Err(e) => return bad_request(&format!("Bad query: {}", e)),
+    };
+    let format = match Format::parse(eq.format.as_deref().unwrap_or("jsonl")) {
+        Ok(format) => format,
+        Err(e) => return bad_request(&e),
+    };
+
+    if !srv.has_collection(&eq.collection) {
+        error!("Collection {} doesn't exist", eq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let (columns, header) = if format == Format::Csv {
+        match csv_columns(srv.rw.as_mut(), &eq.collection).await {
+            Ok(columns) => {
+                let header = csv_header(&columns);
+                (columns, Some(header))
+            }
+            Err(e) => {
+                error!("Failed to export {}: {}", eq.collection, e);
+                return store_error_response(&e);
+            }
+        }
+    } else {
+        (Vec::new(), None)
+    };
+
+    info!("Collection {} export started", eq.collection);
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        eq.collection,
+        format.extension()
+    );
+    let exporter = Exporter {
+        data: data.clone(),
+        collection: eq.collection,
+        format,
+        columns,
+        header,
+        next: Some(0),
+    };
+    let chunks = stream::unfold(exporter, |mut exporter| async move {
+        let chunk = exporter.next_chunk().await?;
+        return Some((chunk, exporter));
+    });
+
+    return HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(("Content-Disposition", disposition))
+        .streaming(chunks);
+}
+
+/// Import items given in request body as JSON Lines or CSV into the
+/// collection, record by record. Records are written directly or through
+/// edit hooks, existing IDs are handled as requested. Admins only.
+pub async fn admin_import(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+    mut body: web::Payload,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Collections can't be imported by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    let iq = match serde_qs::from_str::<ImportQuery>(&req.query_string()) {
+        Ok(iq) => iq,
+        Err(e) => return bad_request(&format!("Bad query: {}", e)),
+    };
+    let format = match Format::parse(iq.format.as_deref().unwrap_or("jsonl")) {
+        Ok(format) => format,
+        Err(e) => return bad_request(&e),
+    };
+    let conflict = match Conflict::parse(iq.conflict.as_deref().unwrap_or("skip")) {
+        Ok(conflict) => conflict,
+        Err(e) => return bad_request(&e),
+    };
+    let hooks = iq.hooks.unwrap_or(false);
+
+    if !srv.has_collection(&iq.collection) {
+        error!("Collection {} doesn't exist", iq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let mut stats = ImportStats::default();
+    let mut splitter = RecordSplitter::new(format);
+    let mut parser = RecordParser::new(format);
+    let mut record_no = 0;
+    let mut eof = false;
+    while !eof {
+        let chunk = match body.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => {
+                error!("Failed to read import of {}: {}", iq.collection, e);
+                return bad_request(&format!("Failed to read request: {}", e));
+            }
+            None => {
+                eof = true;
+                Bytes::new()
+            }
+        };
+        splitter.push(&chunk);
+
+        while let Some(record) = splitter.next_record(eof) {
+            record_no += 1;
+            let itm: Item = match record.and_then(|record| parser.parse(&record)) {
+                Ok(Some(itm)) => itm,
+                Ok(None) => continue,
+                Err(e) => {
+                    stats.fail(record_no, &e);
+                    continue;
+                }
+            };
+
+            let merge =
+                match resolve_conflict(srv.rw.as_mut(), &iq.collection, &itm, conflict).await {
+                    Ok(Some(merge)) => merge,
+                    Ok(None) => {
+                        stats.skipped += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Failed to import {}: {}", iq.collection, e);
+                        return store_error_response(&e);
+                    }
+                };
+
+            if !hooks {
+                if let Err(e) = srv
+                    .write_items(&usr, &iq.collection, &vec![itm], merge)
+                    .await
+                {
+                    error!("Failed to import {}: {}", iq.collection, e);
+                    return store_error_response(&e);
+                }
+                stats.imported += 1;
+                continue;
+            }
+
+            let resp = edit_item(&mut srv, &usr, &iq.collection, itm, merge, None).await;
+            if resp.status().is_success() {
+                stats.imported += 1;
+                continue;
+            }
+            let status = resp.status().as_u16();
+            let error = match actix_web::body::to_bytes(resp.into_body()).await {
+                Ok(body) => serde_json::from_slice::<Value>(&body)
+                    .ok()
+                    .and_then(|v| v["error"].as_str().map(|e| e.to_string()))
+                    .unwrap_or(format!("HTTP {}", status)),
+                Err(_e) => format!("HTTP {}", status),
+            };
+            stats.fail(record_no, &error);
+        }
+    }
+
+    info!(
+        "Collection {} import: {} imported, {} skipped, {} failed",
+        iq.collection, stats.imported, stats.skipped, stats.failed
+    );
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&ImportResult {
+            result: ProcessResult {
+                succeeded: stats.failed == 0,
+                error: if stats.failed == 0 {
+                    "".to_string()
+                } else {
+                    format!("{} records failed", stats.failed)
+                },
+            },
+            stats,
+        })
+        .unwrap(),
+    );
+}
@@ -0,0 +1,228 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::handler::route_call::*;
+use crate::server::user_control::*;
+use crate::state::data::Data;
+use crate::state::events::ItemEvent;
+use crate::state::query::Filter;
+use crate::state::state::*;
+use actix_identity::Identity;
+use actix_web::web::Bytes;
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use futures_util::stream;
+use isabelle_dm::data_model::item::Item;
+use isabelle_dm::data_model::process_result::ProcessResult;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+/// Period of keep-alive comments sent to idle subscribers
+const KEEP_ALIVE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Query of events endpoint
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct EventsQuery {
+    /// Collection to watch
+    pub collection: String,
+
+    /// Context passed to filter hooks
+    pub context: Option<String>,
+}
+
+/// Subscriber of events endpoint
+struct Subscriber {
+    data: web::Data<State>,
+    receiver: Receiver<ItemEvent>,
+    user: Option<Item>,
+    collection: String,
+    context: String,
+    filter: Filter,
+}
+
+/// Get filter narrowing down items of the collection the user can see,
+/// built by database filter hooks as for listing
+pub async fn visibility_filter(
+    srv: &mut Data,
+    usr: &Option<Item>,
+    collection: &str,
+    context: &str,
+) -> Result<Filter, String> {
+    let mut filter = Filter::All;
+    let routes = srv
+        .get_internals()
+        .await
+        .safe_strstr("itm_list_db_filter_hook", &HashMap::new());
+    for route in routes {
+        let filters =
+            call_item_list_db_filter_hook(srv, &route.1, usr, collection, context, "mongo").await?;
+        for filt in filters {
+            filter = filter.and(filt);
+        }
+    }
+    return Ok(filter);
+}
+
+/// Check if the changed item is visible to the user, the same way as it
+/// would be listed: it must match visibility filter and pass list filter
+/// hooks
+pub async fn event_visible(
+    data: &web::Data<State>,
+    usr: &Option<Item>,
+    context: &str,
+    filter: &Filter,
+    event: &ItemEvent,
+) -> bool {
+    let itm = match &event.item {
+        Some(itm) => itm.clone(),
+        None => {
+            let mut itm = Item::new();
+            itm.id = event.id;
+            itm
+        }
+    };
+    let doc = serde_json::to_value(&itm).unwrap_or(Value::Null);
+    if !filter.matches(&doc) {
+        return false;
+    }
+
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let mut map: HashMap<u64, Item> = HashMap::new();
+    map.insert(event.id, itm);
+    let routes = srv
+        .get_internals()
+        .await
+        .safe_strstr("itm_list_filter_hook", &HashMap::new());
+    let mut sorted_routes: Vec<_> = routes.iter().collect();
+    sorted_routes.sort_by(|a, b| a.0.cmp(b.0));
+    for route in sorted_routes {
+        call_item_list_filter_hook(
+            &mut srv,
+            &route.1,
+            usr,
+            &event.collection,
+            context,
+            &mut map,
+        )
+        .await;
+    }
+    return map.contains_key(&event.id);
+}
+
+impl Subscriber {
+    /// Wait for the next chunk to send: visible event or keep-alive comment.
+    /// None when the bus is gone.
+    async fn next_chunk(&mut self) -> Option<Bytes> {
+        loop {
+            let event = match rt::time::timeout(KEEP_ALIVE_PERIOD, self.receiver.recv()).await {
+                Ok(Ok(event)) => event,
+                Ok(Err(RecvError::Lagged(cnt))) => {
+                    info!("Events subscriber missed {} events", cnt);
+                    continue;
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_elapsed) => return Some(Bytes::from(": keep-alive\n\n")),
+            };
+
+            if event.collection != self.collection
+                || !event_visible(&self.data, &self.user, &self.context, &self.filter, &event).await
+            {
+                continue;
+            }
+            let text = match serde_json::to_string(&event) {
+                Ok(text) => text,
+                Err(e) => {
+                    error!("Failed to serialize event: {}", e);
+                    continue;
+                }
+            };
+            return Some(Bytes::from(format!("event: item\ndata: {}\n\n", text)));
+        }
+    }
+}
+
+/// Stream changes of collection items as Server-Sent Events. Only items
+/// the user could list are reported.
+pub async fn itm_events(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    let eq = match serde_qs::from_str::<EventsQuery>(&req.query_string()) {
+        Ok(eq) => eq,
+        Err(e) => {
+            error!("Malformed events query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+    let context = eq.context.unwrap_or("".to_string());
+
+    if !srv.has_collection(&eq.collection) {
+        error!("Collection {} doesn't exist", eq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let filter = match visibility_filter(&mut srv, &usr, &eq.collection, &context).await {
+        Ok(filter) => filter,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(
+                serde_json::to_string(&ProcessResult {
+                    succeeded: false,
+                    error: e,
+                })
+                .unwrap_or("{}".to_string()),
+            );
+        }
+    };
+
+    info!("Collection {} events subscribed", eq.collection);
+    let subscriber = Subscriber {
+        data: data.clone(),
+        receiver: srv.events.subscribe(),
+        user: usr,
+        collection: eq.collection,
+        context,
+        filter,
+    };
+    let events = stream::unfold(subscriber, |mut subscriber| async move {
+        let chunk = subscriber.next_chunk().await?;
+        return Some((Ok::<Bytes, std::io::Error>(chunk), subscriber));
+    });
+
+    return HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events);
+}
@@ -0,0 +1,428 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::handler::route_call::*;
+use crate::handler::web_response::store_error_response;
+use crate::server::itm::check_schema;
+use crate::server::user_control::*;
+use crate::state::data::Data;
+use crate::state::events::action_name;
+use crate::state::query::Filter;
+use crate::state::state::*;
+use crate::state::store::{Store, StoreResult};
+use actix_identity::Identity;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use isabelle_dm::data_model::data_object_action::DataObjectAction;
+use isabelle_dm::data_model::item::Item;
+use isabelle_dm::data_model::list_result::ListResult;
+use isabelle_dm::data_model::process_result::ProcessResult;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::ops::DerefMut;
+
+/// Collection keeping previous versions of items
+pub const HISTORY_COLLECTION: &str = "__history";
+
+/// Number of revisions kept for every item
+const HISTORY_LIMIT: usize = 50;
+
+/// Query of history endpoints
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct HistoryQuery {
+    /// Collection of the item
+    pub collection: String,
+
+    /// Item ID
+    pub id: u64,
+
+    /// Revision to revert to
+    pub rev: Option<u64>,
+}
+
+/// Filter selecting revisions of the item
+fn revisions_filter(collection: &str, id: u64) -> Filter {
+    return Filter::eq("strs.collection", Value::String(collection.to_string()))
+        .and(Filter::eq("u64s.item_id", Value::from(id)));
+}
+
+/// Get all revisions of the item ordered from oldest to newest
+async fn get_revisions(srv: &mut Data, collection: &str, id: u64) -> StoreResult<Vec<Item>> {
+    srv.rw.ensure_collection(HISTORY_COLLECTION).await?;
+    let lr = srv
+        .rw
+        .get_all_items(HISTORY_COLLECTION, "id", &revisions_filter(collection, id))
+        .await?;
+    let mut revs: Vec<Item> = lr.map.into_values().collect();
+    revs.sort_by_key(|rev| rev.id);
+    return Ok(revs);
+}
+
+/// Record previous version of the item before it is changed. Only the last
+/// revisions are kept. History is secondary to the change itself, so
+/// failures are only logged.
+pub async fn record_history(
+    srv: &mut Data,
+    user: &Option<Item>,
+    collection: &str,
+    id: u64,
+    old_itm: &Option<Item>,
+    action: &str,
+) {
+    if let Err(e) = write_history(srv, user, collection, id, old_itm, action).await {
+        error!(
+            "Failed to record history of {} item {}: {}",
+            collection, id, e
+        );
+    }
+}
+
+/// Write history record and drop the oldest ones
+async fn write_history(
+    srv: &mut Data,
+    user: &Option<Item>,
+    collection: &str,
+    id: u64,
+    old_itm: &Option<Item>,
+    action: &str,
+) -> StoreResult<()> {
+    srv.rw.ensure_collection(HISTORY_COLLECTION).await?;
+
+    let mut rev = Item::new();
+    rev.id = u64::MAX;
+    rev.strs
+        .insert("collection".to_string(), collection.to_string());
+    rev.strs.insert("action".to_string(), action.to_string());
+    rev.u64s.insert("item_id".to_string(), id);
+    rev.u64s
+        .insert("timestamp".to_string(), Utc::now().timestamp() as u64);
+    if let Some(usr) = user {
+        rev.u64s.insert("user_id".to_string(), usr.id);
+        rev.strs
+            .insert("user".to_string(), usr.safe_str("login", ""));
+    }
+    if let Some(old) = old_itm {
+        rev.strs.insert(
+            "item".to_string(),
+            serde_json::to_string(old).unwrap_or("{}".to_string()),
+        );
+    }
+    srv.rw.set_item(HISTORY_COLLECTION, &rev, false).await?;
+
+    // Drop the oldest revisions, reading only them
+    let filter = revisions_filter(collection, id);
+    let total = srv
+        .rw
+        .get_items(HISTORY_COLLECTION, u64::MAX, u64::MAX, "id", &filter, 0, 1)
+        .await?
+        .total_count;
+    if total > HISTORY_LIMIT as u64 {
+        let lr = srv
+            .rw
+            .get_items(
+                HISTORY_COLLECTION,
+                u64::MAX,
+                u64::MAX,
+                "id",
+                &filter,
+                0,
+                total - HISTORY_LIMIT as u64,
+            )
+            .await?;
+        let old_ids: Vec<u64> = lr.map.into_keys().collect();
+        srv.rw.del_items(HISTORY_COLLECTION, &old_ids).await?;
+    }
+
+    return Ok(());
+}
+
+/// Check if the user may see the item, using list filter hooks
+async fn may_see_item(srv: &mut Data, user: &Option<Item>, collection: &str, itm: Item) -> bool {
+    let mut map: HashMap<u64, Item> = HashMap::new();
+    let id = itm.id;
+    map.insert(id, itm);
+
+    let routes = srv
+        .get_internals()
+        .await
+        .safe_strstr("itm_list_filter_hook", &HashMap::new());
+    let mut sorted_routes: Vec<_> = routes.iter().collect();
+    sorted_routes.sort_by(|a, b| a.0.cmp(b.0));
+    for route in sorted_routes {
+        call_item_list_filter_hook(srv, &route.1, user, collection, "history", &mut map).await;
+    }
+
+    return map.contains_key(&id);
+}
+
+/// Action that gives away revision history of the item
+pub async fn itm_history(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    let hq = match serde_qs::from_str::<HistoryQuery>(&req.query_string()) {
+        Ok(hq) => hq,
+        Err(e) => {
+            error!("Bad history query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    let srv_mut = srv.deref_mut();
+    if !srv_mut.has_collection(&hq.collection) {
+        error!("Collection {} doesn't exist", hq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    // History is visible to those who can see the item itself. Visibility
+    // of removed items can't be checked, so only admins see their history.
+    match srv_mut.rw.get_item(&hq.collection, hq.id).await {
+        Ok(Some(itm)) => {
+            if !may_see_item(srv_mut, &usr, &hq.collection, itm).await {
+                return HttpResponse::Forbidden().into();
+            }
+        }
+        Ok(None) => {
+            if !check_role(srv_mut, &usr, "admin").await {
+                return HttpResponse::NotFound().into();
+            }
+        }
+        Err(e) => {
+            error!("Failed to read {} item {}: {}", hq.collection, hq.id, e);
+            return store_error_response(&e);
+        }
+    }
+
+    let revs = match get_revisions(srv_mut, &hq.collection, hq.id).await {
+        Ok(revs) => revs,
+        Err(e) => {
+            error!("Failed to read history: {}", e);
+            return store_error_response(&e);
+        }
+    };
+    let mut lr = ListResult {
+        map: HashMap::new(),
+        total_count: 0,
+    };
+    for rev in revs {
+        lr.map.insert(rev.id, rev);
+        lr.total_count += 1;
+    }
+
+    info!(
+        "Collection {} element {} history: {} revisions",
+        hq.collection, hq.id, lr.total_count
+    );
+    HttpResponse::Ok().body(serde_json::to_string(&lr).unwrap())
+}
+
+/// Action that reverts the item to given revision. It goes through the same
+/// auth and edit hooks as editing.
+pub async fn itm_revert(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    let hq = match serde_qs::from_str::<HistoryQuery>(&req.query_string()) {
+        Ok(hq) => hq,
+        Err(e) => {
+            error!("Bad revert query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    let srv_mut = srv.deref_mut();
+    if !srv_mut.has_collection(&hq.collection) {
+        error!("Collection {} doesn't exist", hq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    // Find the revision and the item state it keeps
+    let rev_id = hq.rev.unwrap_or(u64::MAX);
+    let rev = match get_revisions(srv_mut, &hq.collection, hq.id).await {
+        Ok(revs) => revs.into_iter().find(|rev| rev.id == rev_id),
+        Err(e) => {
+            error!("Failed to read history: {}", e);
+            return store_error_response(&e);
+        }
+    };
+    let mut new_itm = match rev {
+        Some(rev) if rev.strs.contains_key("item") => {
+            match serde_json::from_str::<Item>(&rev.strs["item"]) {
+                Ok(itm) => itm,
+                Err(e) => {
+                    error!("Revision {} is broken: {}", rev_id, e);
+                    return HttpResponse::InternalServerError().into();
+                }
+            }
+        }
+        _ => {
+            return HttpResponse::BadRequest().body(
+                serde_json::to_string(&ProcessResult {
+                    succeeded: false,
+                    error: "Revision doesn't exist".to_string(),
+                })
+                .unwrap(),
+            );
+        }
+    };
+    new_itm.id = hq.id;
+
+    /* call auth hooks */
+    {
+        let routes = srv_mut
+            .get_internals()
+            .await
+            .safe_strstr("itm_auth_hook", &HashMap::new());
+        for route in routes {
+            if !call_item_auth_hook(
+                srv_mut,
+                &route.1,
+                &usr,
+                &hq.collection,
+                hq.id,
+                Some(new_itm.clone()),
+                false,
+            )
+            .await
+            {
+                return HttpResponse::Forbidden().into();
+            }
+        }
+    }
+
+    let old_itm = match srv_mut.rw.get_item(&hq.collection, hq.id).await {
+        Ok(old_itm) => old_itm,
+        Err(e) => {
+            error!("Failed to read {} item {}: {}", hq.collection, hq.id, e);
+            return store_error_response(&e);
+        }
+    };
+
+    if let Some(resp) = check_schema(srv_mut, &hq.collection, &new_itm, &old_itm, false).await {
+        return resp;
+    }
+
+    let action = if old_itm.is_some() {
+        DataObjectAction::Modify
+    } else {
+        DataObjectAction::Create
+    };
+
+    /* call pre edit hooks */
+    {
+        let routes = srv_mut
+            .get_internals()
+            .await
+            .safe_strstr("item_pre_edit_hook", &HashMap::new());
+        for route in &routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == hq.collection {
+                let res = call_item_pre_edit_hook(
+                    srv_mut,
+                    parts[1],
+                    &usr,
+                    &hq.collection,
+                    old_itm.clone(),
+                    &mut new_itm,
+                    action.clone(),
+                    false,
+                )
+                .await;
+                if !res.succeeded {
+                    info!("Item pre edit hook failed: {} - {}", parts[1], res.error);
+                    let s = serde_json::to_string(&res);
+                    return HttpResponse::Ok().body(s.unwrap_or("{}".to_string()));
+                }
+            }
+        }
+    }
+
+    record_history(
+        srv_mut,
+        &usr,
+        &hq.collection,
+        hq.id,
+        &old_itm,
+        action_name(&action),
+    )
+    .await;
+    if let Err(e) = srv_mut.rw.set_item(&hq.collection, &new_itm, false).await {
+        error!("Failed to revert {} item {}: {}", hq.collection, hq.id, e);
+        return store_error_response(&e);
+    }
+    info!(
+        "Collection {} element {} reverted to revision {}",
+        hq.collection, hq.id, rev_id
+    );
+
+    /* call hooks */
+    {
+        let routes = srv_mut
+            .get_internals()
+            .await
+            .safe_strstr("item_post_edit_hook", &HashMap::new());
+        for route in routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == hq.collection {
+                call_item_post_edit_hook(
+                    srv_mut,
+                    &parts[1],
+                    &hq.collection,
+                    old_itm.clone(),
+                    hq.id,
+                    action.clone(),
+                )
+                .await;
+            }
+        }
+    }
+    srv_mut
+        .events
+        .emit(&hq.collection, hq.id, action, &usr, Some(new_itm));
+
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: true,
+            error: "".to_string(),
+        })
+        .unwrap(),
+    );
+}
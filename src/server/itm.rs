@@ -22,12 +22,20 @@
  * DEALINGS IN THE SOFTWARE.
  */
 use crate::handler::route_call::*;
+use crate::handler::web_response::store_error_response;
+use crate::server::history::*;
 use crate::server::user_control::*;
+use crate::state::data::Data;
+use crate::state::events::{action_name, RESTORE_ACTION};
+use crate::state::index::{declared_indexes, IndexSpec};
+use crate::state::query::{Filter, DELETED_FIELD};
+use crate::state::schema::{enforce_references, validate_item, FieldError};
 use crate::state::state::*;
-use crate::state::store::Store;
+use crate::state::store::{keep_reserved, strip_reserved, Store, StoreError};
 use actix_identity::Identity;
 use actix_multipart::Multipart;
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use chrono::Utc;
 use futures_util::TryStreamExt;
 use isabelle_dm::data_model::data_object_action::DataObjectAction;
 use isabelle_dm::data_model::item::Item;
@@ -36,9 +44,87 @@ use isabelle_dm::data_model::list_result::ListResult;
 use isabelle_dm::data_model::merge_coll::MergeColl;
 use isabelle_dm::data_model::process_result::ProcessResult;
 use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use serde_qs;
 use std::collections::HashMap;
 use std::ops::DerefMut;
+use std::time::Duration;
+
+/// Period of checking TTL indexes for expired items
+const EXPIRY_PERIOD: Duration = Duration::from_secs(60);
+
+/// Result of editing with version check
+#[derive(Serialize)]
+pub struct EditResult {
+    /// Generic result
+    #[serde(flatten)]
+    pub result: ProcessResult,
+
+    /// Item was changed by someone else in the meantime
+    pub conflict: bool,
+
+    /// Version expected by the client
+    pub expected_version: u64,
+
+    /// Version stored in the database after the attempt
+    pub current_version: u64,
+}
+
+/// Result of editing rejected by collection schema
+#[derive(Serialize)]
+pub struct ValidationResult {
+    /// Generic result
+    #[serde(flatten)]
+    pub result: ProcessResult,
+
+    /// Fields violating the schema
+    pub field_errors: Vec<FieldError>,
+}
+
+/// Check the item as it will be stored against collection schema. Returns
+/// the response to give away if the item can't be stored.
+pub async fn check_schema(
+    srv: &mut Data,
+    collection: &str,
+    itm: &Item,
+    old_itm: &Option<Item>,
+    merge: bool,
+) -> Option<HttpResponse> {
+    let mut checked_itm = itm.clone();
+    if let (true, Some(old_itm)) = (merge, old_itm) {
+        checked_itm = old_itm.clone();
+        checked_itm.merge(itm);
+    }
+
+    let field_errors = match validate_item(srv.rw.as_mut(), collection, &checked_itm).await {
+        Ok(field_errors) => field_errors,
+        Err(e) => {
+            error!("Failed to validate {} item {}: {}", collection, itm.id, e);
+            return Some(store_error_response(&e));
+        }
+    };
+    if field_errors.is_empty() {
+        return None;
+    }
+
+    info!(
+        "Collection {} element {} doesn't match schema",
+        collection, itm.id
+    );
+    return Some(
+        HttpResponse::BadRequest().body(
+            serde_json::to_string(&ValidationResult {
+                result: ProcessResult {
+                    succeeded: false,
+                    error: "Item doesn't match collection schema".to_string(),
+                },
+                field_errors,
+            })
+            .unwrap(),
+        ),
+    );
+}
 
 /// Action that is called on editing items. This function unrolls the
 /// multipart data, all needed hooks, and eventually prepare response.
@@ -48,12 +134,36 @@ pub async fn itm_edit(
     req: HttpRequest,
     mut payload: Multipart,
 ) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
     let srv_lock = data.server.lock();
     let mut srv = srv_lock.borrow_mut();
-    let usr = get_user(&mut srv, user.id().unwrap()).await;
+    let usr = get_user(&mut srv, login).await;
+
+    let (mc, mut itm) = match (
+        serde_qs::from_str::<MergeColl>(&req.query_string()),
+        serde_qs::from_str::<Item>(&req.query_string()),
+    ) {
+        (Ok(mc), Ok(itm)) => (mc, itm),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Malformed edit query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
 
-    let mc = serde_qs::from_str::<MergeColl>(&req.query_string()).unwrap();
-    let mut itm = serde_qs::from_str::<Item>(&req.query_string()).unwrap();
+    // Version check is done only on request
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+    pub struct VersionQuery {
+        pub expected_version: Option<u64>,
+    }
+    let expected_version = serde_qs::from_str::<VersionQuery>(&req.query_string())
+        .map(|vq| vq.expected_version)
+        .unwrap_or(None);
 
     while let Ok(Some(mut field)) = payload.try_next().await {
         while let Ok(Some(chunk)) = field.try_next().await {
@@ -67,20 +177,43 @@ pub async fn itm_edit(
             }
         }
     }
+    strip_reserved(&mut itm);
+
+    return edit_item(
+        &mut srv,
+        &usr,
+        &mc.collection,
+        itm,
+        mc.merge,
+        expected_version,
+    )
+    .await;
+}
 
+/// Edit the item on behalf of the user: call auth hooks, check schema, call
+/// pre edit hooks, write the item and call post edit hooks. This is the
+/// path of every item edit requested by users. Reserved fields the
+/// replacing item lacks are kept.
+pub async fn edit_item(
+    srv: &mut Data,
+    usr: &Option<Item>,
+    collection: &str,
+    mut itm: Item,
+    merge: bool,
+    expected_version: Option<u64>,
+) -> HttpResponse {
     /* call auth hooks */
     {
         let routes = srv
-            .rw
             .get_internals()
             .await
             .safe_strstr("itm_auth_hook", &HashMap::new());
         for route in routes {
             if !call_item_auth_hook(
-                &mut srv,
+                srv,
                 &route.1,
-                &usr,
-                &mc.collection,
+                usr,
+                collection,
                 itm.id,
                 Some(itm.clone()),
                 false,
@@ -94,26 +227,56 @@ pub async fn itm_edit(
 
     itm.normalize_negated();
 
-    if srv.has_collection(&mc.collection) {
-        let srv_mut = unsafe { &mut (*srv_lock.as_ptr()) };
+    if srv.has_collection(collection) {
         let mut itm_clone = itm.clone();
 
-        let old_itm = srv_mut.rw.get_item(&mc.collection, itm.id).await;
+        // Hooks and the write may run in one transaction, so that hooks
+        // can roll back everything done so far
+        let in_transaction = srv
+            .get_internals()
+            .await
+            .safe_bool("itm_edit_transaction", false)
+            && match srv.rw.begin_transaction().await {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("Editing without transaction: {}", e);
+                    false
+                }
+            };
+        take_transaction_abort(srv);
+
+        let old_itm = match srv.rw.get_item(collection, itm.id).await {
+            Ok(old_itm) => old_itm,
+            Err(e) => {
+                error!("Failed to read {} item {}: {}", collection, itm.id, e);
+                if in_transaction {
+                    srv.rw.abort_transaction().await;
+                }
+                return store_error_response(&e);
+            }
+        };
+
+        if let Some(resp) = check_schema(srv, collection, &itm_clone, &old_itm, merge).await {
+            if in_transaction {
+                srv.rw.abort_transaction().await;
+            }
+            return resp;
+        }
+
         /* call pre edit hooks */
         {
-            let routes = (*srv_mut)
-                .rw
+            let routes = srv
                 .get_internals()
                 .await
                 .safe_strstr("item_pre_edit_hook", &HashMap::new());
             for route in &routes {
                 let parts: Vec<&str> = route.1.split(":").collect();
-                if parts[0] == mc.collection {
+                if parts[0] == collection {
                     let res = call_item_pre_edit_hook(
-                        &mut (*srv_mut),
+                        srv,
                         parts[1],
-                        &usr,
-                        &mc.collection,
+                        usr,
+                        collection,
                         old_itm.clone(),
                         &mut itm_clone,
                         if old_itm.is_some() {
@@ -121,11 +284,14 @@ pub async fn itm_edit(
                         } else {
                             DataObjectAction::Create
                         },
-                        mc.merge,
+                        merge,
                     )
                     .await;
                     if !res.succeeded {
                         info!("Item pre edit hook failed: {} - {}", parts[1], res.error);
+                        if in_transaction {
+                            srv.rw.abort_transaction().await;
+                        }
                         let s = serde_json::to_string(&res);
                         return HttpResponse::Ok().body(s.unwrap_or("{}".to_string()));
                     }
@@ -133,28 +299,93 @@ pub async fn itm_edit(
             }
         }
 
-        (*srv_mut)
-            .rw
-            .set_item(&mc.collection, &itm_clone, mc.merge)
-            .await;
-        info!("Collection {} element {} set", mc.collection, itm.id);
+        if !merge {
+            keep_reserved(&mut itm_clone, &old_itm);
+        }
+
+        let mut new_version = 0;
+        let id = if let Some(expected) = expected_version {
+            match srv
+                .rw
+                .set_item_versioned(collection, &itm_clone, merge, expected)
+                .await
+            {
+                Ok((id, version)) => {
+                    new_version = version;
+                    id
+                }
+                Err(StoreError::Conflict(current)) => {
+                    info!(
+                        "Collection {} element {} conflict: expected version {}, got {}",
+                        collection, itm.id, expected, current
+                    );
+                    if in_transaction {
+                        srv.rw.abort_transaction().await;
+                    }
+                    return HttpResponse::Conflict().body(
+                        serde_json::to_string(&EditResult {
+                            result: ProcessResult {
+                                succeeded: false,
+                                error: "Item was changed by someone else".to_string(),
+                            },
+                            conflict: true,
+                            expected_version: expected,
+                            current_version: current,
+                        })
+                        .unwrap(),
+                    );
+                }
+                Err(e) => {
+                    error!("Failed to set {} item {}: {}", collection, itm.id, e);
+                    if in_transaction {
+                        srv.rw.abort_transaction().await;
+                    }
+                    return store_error_response(&e);
+                }
+            }
+        } else {
+            match srv.rw.set_item(collection, &itm_clone, merge).await {
+                Ok(id) => id,
+                Err(e) => {
+                    error!("Failed to set {} item {}: {}", collection, itm.id, e);
+                    if in_transaction {
+                        srv.rw.abort_transaction().await;
+                    }
+                    return store_error_response(&e);
+                }
+            }
+        };
+        info!("Collection {} element {} set", collection, id);
+
+        record_history(
+            srv,
+            usr,
+            collection,
+            id,
+            &old_itm,
+            action_name(if old_itm.is_some() {
+                &DataObjectAction::Modify
+            } else {
+                &DataObjectAction::Create
+            }),
+        )
+        .await;
 
         /* call hooks */
         {
-            let routes = (*srv_mut)
-                .rw
+            let routes = srv
                 .get_internals()
                 .await
                 .safe_strstr("item_post_edit_hook", &HashMap::new());
             for route in routes {
                 let parts: Vec<&str> = route.1.split(":").collect();
-                if parts[0] == mc.collection {
+                if parts[0] == collection {
                     call_item_post_edit_hook(
-                        &mut (*srv_mut),
+                        srv,
                         &parts[1],
-                        &mc.collection,
+                        collection,
                         old_itm.clone(),
-                        itm.id,
+                        id,
                         if old_itm.is_some() {
                             DataObjectAction::Modify
                         } else {
@@ -166,6 +397,40 @@ pub async fn itm_edit(
             }
         }
 
+        if in_transaction {
+            if let Some(reason) = take_transaction_abort(srv) {
+                info!("Item edit rolled back: {}", reason);
+                srv.rw.abort_transaction().await;
+                return HttpResponse::Ok().body(
+                    serde_json::to_string(&ProcessResult {
+                        succeeded: false,
+                        error: reason,
+                    })
+                    .unwrap(),
+                );
+            }
+            if let Err(e) = srv.rw.commit_transaction().await {
+                error!("Failed to commit {} item {}: {}", collection, itm.id, e);
+                return store_error_response(&e);
+            }
+        }
+        srv.emit_change(collection, id, &itm_clone, &old_itm, merge, usr);
+
+        if let Some(expected) = expected_version {
+            return HttpResponse::Ok().body(
+                serde_json::to_string(&EditResult {
+                    result: ProcessResult {
+                        succeeded: true,
+                        error: "".to_string(),
+                    },
+                    conflict: false,
+                    expected_version: expected,
+                    current_version: new_version,
+                })
+                .unwrap(),
+            );
+        }
+
         return HttpResponse::Ok().body(
             serde_json::to_string(&ProcessResult {
                 succeeded: true,
@@ -174,26 +439,213 @@ pub async fn itm_edit(
             .unwrap(),
         );
     } else {
-        error!("Collection {} doesn't exist", mc.collection);
+        error!("Collection {} doesn't exist", collection);
     }
 
     return HttpResponse::BadRequest().into();
 }
 
+/// Action that is called on editing many items at once. The items are
+/// given as JSON array in "items" multipart field. Auth and edit hooks are
+/// called for every item, and nothing is written if any of them fails.
+pub async fn itm_bulk_edit(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+    mut payload: Multipart,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    let mc = match serde_qs::from_str::<MergeColl>(&req.query_string()) {
+        Ok(mc) => mc,
+        Err(e) => {
+            error!("Malformed bulk edit query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+    let mut itms: Vec<Item> = Vec::new();
+
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        while let Ok(Some(chunk)) = field.try_next().await {
+            let data = chunk;
+
+            if field.name() == "items" {
+                let v = &data.to_vec();
+                let strv = std::str::from_utf8(v).unwrap_or("[]");
+                let mut new_itms: Vec<Item> = serde_json::from_str(strv).unwrap_or(Vec::new());
+                new_itms.iter_mut().for_each(strip_reserved);
+                itms.extend(new_itms);
+            }
+        }
+    }
+
+    let srv_mut = srv.deref_mut();
+    if !srv_mut.has_collection(&mc.collection) {
+        error!("Collection {} doesn't exist", mc.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let internals = srv_mut.get_internals().await;
+    let auth_routes = internals.safe_strstr("itm_auth_hook", &HashMap::new());
+    let pre_routes = internals.safe_strstr("item_pre_edit_hook", &HashMap::new());
+    let post_routes = internals.safe_strstr("item_post_edit_hook", &HashMap::new());
+
+    let mut old_itms: Vec<Option<Item>> = Vec::new();
+    for itm in &mut itms {
+        itm.normalize_negated();
+
+        /* call auth hooks */
+        for route in &auth_routes {
+            if !call_item_auth_hook(
+                srv_mut,
+                &route.1,
+                &usr,
+                &mc.collection,
+                itm.id,
+                Some(itm.clone()),
+                false,
+            )
+            .await
+            {
+                return HttpResponse::Forbidden().into();
+            }
+        }
+
+        let old_itm = match srv_mut.rw.get_item(&mc.collection, itm.id).await {
+            Ok(old_itm) => old_itm,
+            Err(e) => {
+                error!("Failed to read {} item {}: {}", mc.collection, itm.id, e);
+                return store_error_response(&e);
+            }
+        };
+
+        if let Some(resp) = check_schema(srv_mut, &mc.collection, itm, &old_itm, mc.merge).await {
+            return resp;
+        }
+
+        /* call pre edit hooks */
+        for route in &pre_routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == mc.collection {
+                let res = call_item_pre_edit_hook(
+                    srv_mut,
+                    parts[1],
+                    &usr,
+                    &mc.collection,
+                    old_itm.clone(),
+                    itm,
+                    if old_itm.is_some() {
+                        DataObjectAction::Modify
+                    } else {
+                        DataObjectAction::Create
+                    },
+                    mc.merge,
+                )
+                .await;
+                if !res.succeeded {
+                    info!("Item pre edit hook failed: {} - {}", parts[1], res.error);
+                    let s = serde_json::to_string(&res);
+                    return HttpResponse::Ok().body(s.unwrap_or("{}".to_string()));
+                }
+            }
+        }
+
+        if !mc.merge {
+            keep_reserved(itm, &old_itm);
+        }
+        old_itms.push(old_itm);
+    }
+
+    let ids = match srv_mut.rw.set_items(&mc.collection, &itms, mc.merge).await {
+        Ok(ids) => ids,
+        Err(e) => {
+            error!("Failed to set {} items: {}", mc.collection, e);
+            return store_error_response(&e);
+        }
+    };
+    info!("Collection {}: {} elements set", mc.collection, itms.len());
+
+    for ((itm, old_itm), id) in itms.iter().zip(old_itms.iter()).zip(ids) {
+        let action = if old_itm.is_some() {
+            DataObjectAction::Modify
+        } else {
+            DataObjectAction::Create
+        };
+
+        record_history(
+            srv_mut,
+            &usr,
+            &mc.collection,
+            id,
+            old_itm,
+            action_name(&action),
+        )
+        .await;
+
+        /* call hooks */
+        for route in &post_routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == mc.collection {
+                call_item_post_edit_hook(
+                    srv_mut,
+                    &parts[1],
+                    &mc.collection,
+                    old_itm.clone(),
+                    id,
+                    action.clone(),
+                )
+                .await;
+            }
+        }
+        srv_mut.emit_change(&mc.collection, id, itm, old_itm, mc.merge, &usr);
+    }
+
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: true,
+            error: "".to_string(),
+        })
+        .unwrap(),
+    );
+}
+
 /// Action that is called on removing the item. This function calls
 /// all necessary hooks and actually performs removal.
 pub async fn itm_del(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
     let srv_lock = data.server.lock();
     let mut srv = srv_lock.borrow_mut();
-    let usr = get_user(&mut srv, user.id().unwrap()).await;
+    let usr = get_user(&mut srv, login).await;
 
-    let mc = serde_qs::from_str::<MergeColl>(&req.query_string()).unwrap();
-    let itm = serde_qs::from_str::<Item>(&req.query_string()).unwrap();
+    let (mc, itm) = match (
+        serde_qs::from_str::<MergeColl>(&req.query_string()),
+        serde_qs::from_str::<Item>(&req.query_string()),
+    ) {
+        (Ok(mc), Ok(itm)) => (mc, itm),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Malformed removal query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
 
     /* call auth hooks */
     {
         let routes = srv
-            .rw
             .get_internals()
             .await
             .safe_strstr("itm_auth_hook", &HashMap::new());
@@ -206,30 +658,51 @@ pub async fn itm_del(user: Identity, data: web::Data<State>, req: HttpRequest) -
         }
     }
 
-    let srv_mut = srv.deref_mut();
-    if srv_mut.has_collection(&mc.collection) {
-        let old_itm = srv_mut.rw.get_item(&mc.collection, itm.id).await;
+    return delete_item(&mut srv, &usr, &mc.collection, itm.id, mc.merge).await;
+}
+
+/// Remove the item on behalf of the user: call pre edit hooks, apply
+/// reference constraints, mark the item as deleted and call post edit
+/// hooks. Auth hooks are up to the caller.
+pub async fn delete_item(
+    srv: &mut Data,
+    usr: &Option<Item>,
+    collection: &str,
+    id: u64,
+    merge: bool,
+) -> HttpResponse {
+    if srv.has_collection(collection) {
+        let old_itm = match srv.rw.get_item(collection, id).await {
+            Ok(Some(old_itm)) => Some(old_itm),
+            Ok(None) => {
+                let e = StoreError::NotFound(format!("{} item {}", collection, id));
+                return store_error_response(&e);
+            }
+            Err(e) => {
+                error!("Failed to read {} item {}: {}", collection, id, e);
+                return store_error_response(&e);
+            }
+        };
         let mut new_itm = Item::new();
 
         /* call pre edit hooks before removal */
         {
-            let routes = (*srv_mut)
-                .rw
+            let routes = srv
                 .get_internals()
                 .await
                 .safe_strstr("item_pre_edit_hook", &HashMap::new());
             for route in &routes {
                 let parts: Vec<&str> = route.1.split(":").collect();
-                if parts[0] == mc.collection {
+                if parts[0] == collection {
                     let res = call_item_pre_edit_hook(
-                        &mut (*srv_mut),
+                        srv,
                         parts[1],
-                        &usr,
-                        &mc.collection,
+                        usr,
+                        collection,
                         old_itm.clone(),
                         &mut new_itm,
                         DataObjectAction::Delete,
-                        mc.merge,
+                        merge,
                     )
                     .await;
                     if !res.succeeded {
@@ -241,50 +714,471 @@ pub async fn itm_del(user: Identity, data: web::Data<State>, req: HttpRequest) -
             }
         }
 
-        if srv_mut.rw.del_item(&mc.collection, itm.id).await {
-            info!("Collection {} element {} removed", mc.collection, itm.id);
+        let changes = match enforce_references(srv.rw.as_mut(), collection, &vec![id], true).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                info!(
+                    "Collection {} element {} can't be removed: {}",
+                    collection, id, e
+                );
+                return store_error_response(&e);
+            }
+        };
+        srv.emit_references(changes, usr);
+
+        // Items are only marked as deleted, so they can be restored later
+        let mut deleted: Option<Item> = None;
+        if let Some(mut deleted_itm) = old_itm.clone() {
+            record_history(
+                srv,
+                usr,
+                collection,
+                id,
+                &old_itm,
+                action_name(&DataObjectAction::Delete),
+            )
+            .await;
+            deleted_itm.bools.insert("__deleted".to_string(), true);
+            deleted_itm
+                .u64s
+                .insert("__deleted_at".to_string(), Utc::now().timestamp() as u64);
+            if let Err(e) = srv.rw.set_item(collection, &deleted_itm, false).await {
+                error!("Failed to remove {} item {}: {}", collection, id, e);
+                return store_error_response(&e);
+            }
+            info!("Collection {} element {} removed", collection, id);
+            deleted = Some(deleted_itm);
         }
 
         /* call hooks */
         {
-            let routes = srv_mut
-                .rw
+            let routes = srv
                 .get_internals()
                 .await
                 .safe_strstr("item_post_edit_hook", &HashMap::new());
             for route in routes {
                 let parts: Vec<&str> = route.1.split(":").collect();
-                if parts[0] == mc.collection {
+                if parts[0] == collection {
                     call_item_post_edit_hook(
-                        srv_mut,
+                        srv,
                         &parts[1],
-                        &mc.collection,
+                        collection,
                         old_itm.clone(),
-                        itm.id,
+                        id,
                         DataObjectAction::Delete,
                     )
                     .await;
                 }
             }
         }
+        if deleted.is_some() {
+            srv.events
+                .emit(collection, id, DataObjectAction::Delete, usr, deleted);
+        }
 
         return HttpResponse::Ok().into();
     } else {
-        error!("Collection {} doesn't exist", mc.collection);
+        error!("Collection {} doesn't exist", collection);
     }
 
     return HttpResponse::BadRequest().into();
 }
 
+/// Remove items expired according to TTL indexes once in a period, going
+/// through the regular removal path without user and purging them right
+/// after. Background tasks share the thread, so the state is left alone
+/// while another task borrows it.
+pub async fn run_expiry(data: web::Data<State>) {
+    loop {
+        rt::time::sleep(EXPIRY_PERIOD).await;
+        let expired = {
+            let srv_lock = data.server.lock();
+            let mut srv = match srv_lock.try_borrow_mut() {
+                Ok(srv) => srv,
+                Err(_) => continue,
+            };
+            srv.expired_items().await
+        };
+
+        for (collection, ids) in expired {
+            let mut removed: Vec<u64> = Vec::new();
+            for id in ids {
+                let srv_lock = data.server.lock();
+                let mut srv = match srv_lock.try_borrow_mut() {
+                    Ok(srv) => srv,
+                    Err(_) => continue,
+                };
+                let resp = delete_item(&mut srv, &None, &collection, id, false).await;
+                if resp.status().is_success() {
+                    removed.push(id);
+                } else {
+                    error!(
+                        "Failed to remove expired {} item {}: {}",
+                        collection,
+                        id,
+                        resp.status()
+                    );
+                }
+            }
+            if removed.is_empty() {
+                continue;
+            }
+
+            // Expired data must not stay stored, unlike deleted items
+            let srv_lock = data.server.lock();
+            let mut srv = match srv_lock.try_borrow_mut() {
+                Ok(srv) => srv,
+                Err(_) => {
+                    error!("{}: expired items are left to purge", collection);
+                    continue;
+                }
+            };
+            if let Err(e) = srv.rw.del_items(&collection, &removed).await {
+                error!("Failed to purge expired {} items: {}", collection, e);
+                continue;
+            }
+            info!("{}: removed {} expired items", collection, removed.len());
+        }
+    }
+}
+
+/// Action that is called on restoring soft-deleted items. It goes through
+/// the same auth and edit hooks as editing. Hooks see modification action,
+/// since DataObjectAction has no restoring, while history, events and
+/// webhooks report it as "restore".
+pub async fn itm_restore(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    let (mc, itm) = match (
+        serde_qs::from_str::<MergeColl>(&req.query_string()),
+        serde_qs::from_str::<Item>(&req.query_string()),
+    ) {
+        (Ok(mc), Ok(itm)) => (mc, itm),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Malformed restore query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    let srv_mut = srv.deref_mut();
+    if !srv_mut.has_collection(&mc.collection) {
+        error!("Collection {} doesn't exist", mc.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let old_itm = match srv_mut.rw.get_item(&mc.collection, itm.id).await {
+        Ok(old_itm) => old_itm,
+        Err(e) => {
+            error!("Failed to read {} item {}: {}", mc.collection, itm.id, e);
+            return store_error_response(&e);
+        }
+    };
+    if old_itm.is_none() || !old_itm.as_ref().unwrap().safe_bool("__deleted", false) {
+        return HttpResponse::BadRequest().body(
+            serde_json::to_string(&ProcessResult {
+                succeeded: false,
+                error: "Item is not deleted".to_string(),
+            })
+            .unwrap(),
+        );
+    }
+
+    let mut restored_itm = old_itm.clone().unwrap();
+    restored_itm.bools.remove("__deleted");
+    restored_itm.u64s.remove("__deleted_at");
+
+    /* call auth hooks */
+    {
+        let routes = srv_mut
+            .get_internals()
+            .await
+            .safe_strstr("itm_auth_hook", &HashMap::new());
+        for route in routes {
+            if !call_item_auth_hook(
+                srv_mut,
+                &route.1,
+                &usr,
+                &mc.collection,
+                itm.id,
+                Some(restored_itm.clone()),
+                false,
+            )
+            .await
+            {
+                return HttpResponse::Forbidden().into();
+            }
+        }
+    }
+
+    // Schema or referenced items might have changed since the removal
+    if let Some(resp) = check_schema(srv_mut, &mc.collection, &restored_itm, &old_itm, false).await
+    {
+        return resp;
+    }
+
+    /* call pre edit hooks */
+    {
+        let routes = srv_mut
+            .get_internals()
+            .await
+            .safe_strstr("item_pre_edit_hook", &HashMap::new());
+        for route in &routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == mc.collection {
+                let res = call_item_pre_edit_hook(
+                    srv_mut,
+                    parts[1],
+                    &usr,
+                    &mc.collection,
+                    old_itm.clone(),
+                    &mut restored_itm,
+                    DataObjectAction::Modify,
+                    false,
+                )
+                .await;
+                if !res.succeeded {
+                    info!("Item pre edit hook failed: {} - {}", parts[1], res.error);
+                    let s = serde_json::to_string(&res);
+                    return HttpResponse::Ok().body(s.unwrap_or("{}".to_string()));
+                }
+            }
+        }
+    }
+
+    record_history(
+        srv_mut,
+        &usr,
+        &mc.collection,
+        itm.id,
+        &old_itm,
+        RESTORE_ACTION,
+    )
+    .await;
+    if let Err(e) = srv_mut
+        .rw
+        .set_item(&mc.collection, &restored_itm, false)
+        .await
+    {
+        error!("Failed to restore {} item {}: {}", mc.collection, itm.id, e);
+        return store_error_response(&e);
+    }
+    info!("Collection {} element {} restored", mc.collection, itm.id);
+
+    /* call hooks */
+    {
+        let routes = srv_mut
+            .get_internals()
+            .await
+            .safe_strstr("item_post_edit_hook", &HashMap::new());
+        for route in routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == mc.collection {
+                call_item_post_edit_hook(
+                    srv_mut,
+                    &parts[1],
+                    &mc.collection,
+                    old_itm.clone(),
+                    itm.id,
+                    DataObjectAction::Modify,
+                )
+                .await;
+            }
+        }
+    }
+    srv_mut.events.emit_named(
+        &mc.collection,
+        itm.id,
+        RESTORE_ACTION,
+        &usr,
+        Some(restored_itm),
+    );
+
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: true,
+            error: "".to_string(),
+        })
+        .unwrap(),
+    );
+}
+
+/// Action that permanently removes items that were deleted before given
+/// UNIX timestamp (`older_than`, defaults to current time). Admins only.
+pub async fn itm_purge_deleted(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Deleted items can't be purged by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+    pub struct PurgeQuery {
+        pub older_than: Option<i64>,
+    }
+
+    let pq = match serde_qs::from_str::<PurgeQuery>(&req.query_string()) {
+        Ok(pq) => pq,
+        Err(e) => {
+            return HttpResponse::BadRequest().body(
+                serde_json::to_string(&ProcessResult {
+                    succeeded: false,
+                    error: format!("Bad query: {}", e),
+                })
+                .unwrap(),
+            );
+        }
+    };
+
+    let older_than = pq.older_than.unwrap_or(Utc::now().timestamp());
+    let cnt = match srv.rw.purge_deleted(older_than).await {
+        Ok(cnt) => cnt,
+        Err(e) => {
+            error!("Failed to purge deleted items: {}", e);
+            return store_error_response(&e);
+        }
+    };
+    info!("Purged {} deleted items older than {}", cnt, older_than);
+
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: true,
+            error: "".to_string(),
+        })
+        .unwrap(),
+    );
+}
+
+/// Action that lists secondary indexes declared in internals and existing
+/// in the database, for given collection or for all of them. Admins only.
+pub async fn itm_indexes(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Indexes can't be listed by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+    pub struct IndexesQuery {
+        pub collection: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    pub struct CollectionIndexes {
+        pub declared: Vec<IndexSpec>,
+        pub existing: Vec<IndexSpec>,
+    }
+
+    let iq = serde_qs::from_str::<IndexesQuery>(&req.query_string())
+        .unwrap_or(IndexesQuery { collection: None });
+    let collections = match iq.collection {
+        Some(collection) => {
+            if !srv.has_collection(&collection) {
+                error!("Collection {} doesn't exist", collection);
+                return HttpResponse::BadRequest().into();
+            }
+            vec![collection]
+        }
+        None => match srv.rw.get_collections().await {
+            Ok(collections) => collections,
+            Err(e) => {
+                error!("Failed to get collections: {}", e);
+                return store_error_response(&e);
+            }
+        },
+    };
+
+    let internals = srv.get_internals().await;
+    let mut res: HashMap<String, CollectionIndexes> = HashMap::new();
+    for collection in collections {
+        let declared = match declared_indexes(&internals, &collection) {
+            Ok(declared) => declared,
+            Err(e) => {
+                return HttpResponse::InternalServerError().body(
+                    serde_json::to_string(&ProcessResult {
+                        succeeded: false,
+                        error: e,
+                    })
+                    .unwrap(),
+                );
+            }
+        };
+        let existing = match srv.rw.get_indexes(&collection).await {
+            Ok(existing) => existing,
+            Err(e) => {
+                error!("Failed to get indexes of {}: {}", collection, e);
+                return store_error_response(&e);
+            }
+        };
+        res.insert(collection, CollectionIndexes { declared, existing });
+    }
+
+    return HttpResponse::Ok().body(serde_json::to_string(&res).unwrap());
+}
+
 /// Action that is called on any attempt to list database items.
 /// This function invokes all necessary hooks before giving away the list
 /// in form of json array.
 pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
     let srv_lock = data.server.lock();
     let mut srv = srv_lock.borrow_mut();
-    let usr = get_user(&mut srv, user.id().unwrap()).await;
+    let usr = get_user(&mut srv, login).await;
+
+    let lq = match serde_qs::from_str::<ListQuery>(&req.query_string()) {
+        Ok(lq) => lq,
+        Err(e) => {
+            error!("Malformed list query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
 
-    let lq = serde_qs::from_str::<ListQuery>(&req.query_string()).unwrap();
+    // Soft-deleted items are listed only on request
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+    pub struct DeletedQuery {
+        pub deleted: Option<bool>,
+    }
+    let show_deleted = serde_qs::from_str::<DeletedQuery>(&req.query_string())
+        .map(|dq| dq.deleted.unwrap_or(false))
+        .unwrap_or(false);
 
     if !srv.has_collection(&lq.collection) {
         error!("Collection {} doesn't exist", lq.collection);
@@ -297,13 +1191,23 @@ pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest)
     };
 
     if lq.id != u64::MAX {
-        let res = srv.rw.get_item(&lq.collection, lq.id).await;
-        if res == None {
+        let res = match srv.rw.get_item(&lq.collection, lq.id).await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Failed to read {} item {}: {}", lq.collection, lq.id, e);
+                return store_error_response(&e);
+            }
+        };
+        let is_deleted = res
+            .as_ref()
+            .map_or(false, |r| r.safe_bool("__deleted", false));
+        if res == None || (is_deleted && !show_deleted) {
             error!(
                 "Collection {} requested element {} doesn't exist",
                 lq.collection, lq.id
             );
-            return HttpResponse::BadRequest().into();
+            let e = StoreError::NotFound(format!("{} item {}", lq.collection, lq.id));
+            return store_error_response(&e);
         }
 
         if lq.limit == u64::MAX || lq.limit >= 1 {
@@ -314,22 +1218,36 @@ pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest)
                 lq.collection, lq.id, lq.limit
             );
         }
-    } else if lq.id_min != u64::MAX || lq.id_max != u64::MAX || lq.sort_key != "" || lq.filter != ""
+    } else if lq.id_min != u64::MAX
+        || lq.id_max != u64::MAX
+        || lq.sort_key != ""
+        || lq.filter != ""
+        || show_deleted
     {
         info!(
             "Collection {} requested range {} - {} sort {} skip {} limit {} filter {}",
             lq.collection, lq.id_min, lq.id_max, lq.sort_key, lq.skip, lq.limit, lq.filter
         );
 
-        let mut filters: Vec<String> = Vec::new();
-        let mut final_filter: String = "".to_string();
+        let mut final_filter = match Filter::from_json_str(&lq.filter) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Malformed filter {}: {}", lq.filter, e);
+                return HttpResponse::BadRequest().body(
+                    serde_json::to_string(&ProcessResult {
+                        succeeded: false,
+                        error: "Malformed filter: ".to_owned() + &e,
+                    })
+                    .unwrap(),
+                );
+            }
+        };
 
-        if lq.filter != "" {
-            filters.push(lq.filter.to_string());
+        if show_deleted {
+            final_filter = final_filter.and(Filter::eq(DELETED_FIELD, Value::Bool(true)));
         }
 
         let routes = srv
-            .rw
             .get_internals()
             .await
             .safe_strstr("itm_list_db_filter_hook", &HashMap::new());
@@ -343,18 +1261,25 @@ pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest)
                 "mongo",
             )
             .await;
-            filters.extend(new_filters);
-        }
-
-        for filt in filters {
-            if final_filter == "" {
-                final_filter = filt;
-            } else {
-                final_filter = "{ \"$and\": [".to_owned() + &final_filter + ", " + &filt + "]}";
+            match new_filters {
+                Ok(filters) => {
+                    for filt in filters {
+                        final_filter = final_filter.and(filt);
+                    }
+                }
+                Err(e) => {
+                    return HttpResponse::InternalServerError().body(
+                        serde_json::to_string(&ProcessResult {
+                            succeeded: false,
+                            error: e,
+                        })
+                        .unwrap(),
+                    );
+                }
             }
         }
 
-        lr = srv
+        lr = match srv
             .rw
             .get_items(
                 &lq.collection,
@@ -365,11 +1290,27 @@ pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest)
                 lq.skip,
                 lq.limit,
             )
-            .await;
+            .await
+        {
+            Ok(lr) => lr,
+            Err(e) => {
+                error!("Failed to list {}: {}", lq.collection, e);
+                return store_error_response(&e);
+            }
+        };
     } else if lq.id_list.len() > 0 {
         for id in lq.id_list {
-            let res = srv.rw.get_item(&lq.collection, id).await;
-            if res != None {
+            let res = match srv.rw.get_item(&lq.collection, id).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("Failed to read {} item {}: {}", lq.collection, id, e);
+                    return store_error_response(&e);
+                }
+            };
+            let is_deleted = res
+                .as_ref()
+                .map_or(false, |r| r.safe_bool("__deleted", false));
+            if res != None && !is_deleted {
                 lr.map.insert(id, res.unwrap());
                 lr.total_count += 1;
             }
@@ -382,7 +1323,6 @@ pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest)
     /* itm filter hooks */
     {
         let routes = srv
-            .rw
             .get_internals()
             .await
             .safe_strstr("itm_list_filter_hook", &HashMap::new());
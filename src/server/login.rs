@@ -23,6 +23,7 @@
  */
 use crate::handler::route_call::*;
 use crate::server::user_control::*;
+use crate::state::query::Filter;
 use crate::state::state::*;
 use crate::state::store::Store;
 use crate::util::crypto::get_otp_code;
@@ -36,6 +37,7 @@ use isabelle_dm::data_model::process_result::ProcessResult;
 use isabelle_dm::transfer_model::detailed_login_user::DetailedLoginUser;
 use isabelle_dm::transfer_model::login_user::LoginUser;
 use log::{error, info};
+use serde_json::Value;
 use std::collections::HashMap;
 
 /// Generate one-time password for the user.
@@ -72,16 +74,32 @@ pub async fn gen_otp(
             error: "Invalid login".to_string(),
         });
     } else {
-        let mut new_usr_itm = srv
-            .rw
-            .get_item("user", usr.clone().unwrap().id)
-            .await
-            .unwrap();
+        let mut new_usr_itm = match srv.rw.get_item("user", usr.clone().unwrap().id).await {
+            Ok(Some(itm)) => itm,
+            Ok(None) => {
+                return web::Json(ProcessResult {
+                    succeeded: false,
+                    error: "Invalid login".to_string(),
+                });
+            }
+            Err(e) => {
+                error!("Failed to read user {}: {}", lu.username, e);
+                return web::Json(ProcessResult {
+                    succeeded: false,
+                    error: e.to_string(),
+                });
+            }
+        };
         new_usr_itm.set_str("otp", &get_otp_code());
-        srv.rw.set_item("user", &new_usr_itm, false).await;
+        if let Err(e) = srv.rw.set_item("user", &new_usr_itm, false).await {
+            error!("Failed to set OTP for {}: {}", lu.username, e);
+            return web::Json(ProcessResult {
+                succeeded: false,
+                error: e.to_string(),
+            });
+        }
 
         let routes = srv
-            .rw
             .get_internals()
             .await
             .safe_strstr("otp_hook", &HashMap::new());
@@ -155,7 +173,13 @@ pub async fn register(
         itm.set_str("email", &email);
         itm.set_bool("role_is_active", true);
 
-        srv.rw.set_item("user", &itm, false).await;
+        if let Err(e) = srv.rw.set_item("user", &itm, false).await {
+            error!("Failed to register {}: {}", login, e);
+            return web::Json(ProcessResult {
+                succeeded: false,
+                error: e.to_string(),
+            });
+        }
     }
 
     return web::Json(ProcessResult {
@@ -229,7 +253,9 @@ pub async fn login(
             let mut logged = Item::new();
             logged.id = itm_real.id;
             logged.set_bool("logged_once", true);
-            srv.rw.set_item("user", &logged, true).await;
+            if let Err(e) = srv.rw.set_item("user", &logged, true).await {
+                error!("Failed to mark {} as logged in: {}", lu.username, e);
+            }
             info!("Logged in as {}", lu.username);
         } else {
             // Password doesn't match - error out.
@@ -274,44 +300,26 @@ pub async fn is_logged_in(_user: Option<Identity>, data: web::Data<State>) -> im
         licensed_to: "".to_string(),
     };
 
-    user.site_name = srv
-        .rw
-        .get_settings()
-        .await
-        .clone()
-        .safe_str("site_name", "");
+    user.site_name = srv.get_settings().await.clone().safe_str("site_name", "");
     if user.site_name == "" {
         user.site_name = srv
-            .rw
             .get_internals()
             .await
             .safe_str("default_site_name", "Isabelle");
     }
 
-    user.site_logo = srv
-        .rw
-        .get_settings()
-        .await
-        .clone()
-        .safe_str("site_logo", "");
+    user.site_logo = srv.get_settings().await.clone().safe_str("site_logo", "");
     if user.site_logo == "" {
         user.site_logo = srv
-            .rw
             .get_internals()
             .await
             .safe_str("default_site_logo", "/logo.png");
     }
     info!("Site logo: {}", user.site_logo);
 
-    user.licensed_to = srv
-        .rw
-        .get_settings()
-        .await
-        .clone()
-        .safe_str("licensed_to", "");
+    user.licensed_to = srv.get_settings().await.clone().safe_str("licensed_to", "");
     if user.licensed_to == "" {
         user.licensed_to = srv
-            .rw
             .get_internals()
             .await
             .safe_str("default_licensed_to", "end user");
@@ -323,25 +331,28 @@ pub async fn is_logged_in(_user: Option<Identity>, data: web::Data<State>) -> im
     }
 
     let role_is = srv
-        .rw
         .get_internals()
         .await
         .safe_str("user_role_prefix", "role_is_");
     let email = _user.as_ref().unwrap().id().unwrap();
-    if !login_has_bad_symbols(&email) {
-        let filter = "{ \"strs.email\": \"".to_owned() + &email + "\" }";
-        let all_users = srv.rw.get_all_items("user", "name", &filter).await;
-        for item in &all_users.map {
-            if item.1.strs.contains_key("email") && item.1.strs["email"] == email {
-                user.username = _user.as_ref().unwrap().id().unwrap();
-                user.id = *item.0;
-                for bp in &item.1.bools {
-                    if bp.0.starts_with(&role_is) {
-                        user.role.push(bp.0[8..].to_string());
-                    }
+    let filter = Filter::eq("strs.email", Value::String(email.clone()));
+    let all_users = match srv.rw.get_all_items("user", "name", &filter).await {
+        Ok(all_users) => all_users,
+        Err(e) => {
+            error!("Failed to look up user {}: {}", email, e);
+            return web::Json(user);
+        }
+    };
+    for item in &all_users.map {
+        if item.1.strs.contains_key("email") && item.1.strs["email"] == email {
+            user.username = _user.as_ref().unwrap().id().unwrap();
+            user.id = *item.0;
+            for bp in &item.1.bools {
+                if bp.0.starts_with(&role_is) {
+                    user.role.push(bp.0[8..].to_string());
                 }
-                break;
             }
+            break;
         }
     }
 
@@ -21,7 +21,13 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+pub mod admin;
+pub mod events;
+pub mod history;
 pub mod itm;
 pub mod login;
+pub mod search;
 pub mod setting;
 pub mod user_control;
+pub mod webhook;
+pub mod ws;
@@ -0,0 +1,200 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::handler::route_call::*;
+use crate::handler::web_response::store_error_response;
+use crate::server::user_control::*;
+use crate::state::query::Filter;
+use crate::state::search::search_fields;
+use crate::state::state::*;
+use crate::state::store::Store;
+use actix_identity::Identity;
+use actix_web::{web, HttpRequest, HttpResponse};
+use isabelle_dm::data_model::list_result::ListResult;
+use isabelle_dm::data_model::process_result::ProcessResult;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Query of search endpoint
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct SearchQuery {
+    /// Collection to search in
+    pub collection: String,
+
+    /// Words to search for
+    pub q: String,
+
+    /// Context passed to filter hooks
+    pub context: Option<String>,
+
+    /// Number of best matches to skip
+    pub skip: Option<u64>,
+
+    /// Maximum number of returned items
+    pub limit: Option<u64>,
+}
+
+/// Result of search
+#[derive(Serialize)]
+pub struct SearchResult {
+    /// Found items
+    #[serde(flatten)]
+    pub result: ListResult,
+
+    /// IDs of found items, best matches first
+    pub ranking: Vec<u64>,
+}
+
+/// Search items by words in searchable fields of the collection
+pub async fn itm_search(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    let sq = match serde_qs::from_str::<SearchQuery>(&req.query_string()) {
+        Ok(sq) => sq,
+        Err(e) => {
+            error!("Malformed search query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+    let context = sq.context.clone().unwrap_or("".to_string());
+
+    if !srv.has_collection(&sq.collection) {
+        error!("Collection {} doesn't exist", sq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let fields = search_fields(&srv.get_internals().await, &sq.collection);
+    if fields.is_empty() {
+        error!("Collection {} is not searchable", sq.collection);
+        return HttpResponse::BadRequest().body(
+            serde_json::to_string(&ProcessResult {
+                succeeded: false,
+                error: "Collection is not searchable".to_string(),
+            })
+            .unwrap(),
+        );
+    }
+
+    info!(
+        "Collection {} search \"{}\" skip {:?} limit {:?}",
+        sq.collection, sq.q, sq.skip, sq.limit
+    );
+
+    let mut final_filter = Filter::All;
+    let routes = srv
+        .get_internals()
+        .await
+        .safe_strstr("itm_list_db_filter_hook", &HashMap::new());
+    for route in routes {
+        let new_filters = call_item_list_db_filter_hook(
+            &mut srv,
+            &route.1,
+            &usr,
+            &sq.collection,
+            &context,
+            "mongo",
+        )
+        .await;
+        match new_filters {
+            Ok(filters) => {
+                for filt in filters {
+                    final_filter = final_filter.and(filt);
+                }
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().body(
+                    serde_json::to_string(&ProcessResult {
+                        succeeded: false,
+                        error: e,
+                    })
+                    .unwrap(),
+                );
+            }
+        }
+    }
+
+    let (itms, total_count) = match srv
+        .rw
+        .search_items(
+            &sq.collection,
+            &sq.q,
+            &fields,
+            &final_filter,
+            sq.skip.unwrap_or(0),
+            sq.limit.unwrap_or(u64::MAX),
+        )
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Failed to search {}: {}", sq.collection, e);
+            return store_error_response(&e);
+        }
+    };
+
+    let mut ranking: Vec<u64> = itms.iter().map(|itm| itm.id).collect();
+    let mut lr = ListResult {
+        map: itms.into_iter().map(|itm| (itm.id, itm)).collect(),
+        total_count,
+    };
+
+    /* itm filter hooks */
+    {
+        let routes = srv
+            .get_internals()
+            .await
+            .safe_strstr("itm_list_filter_hook", &HashMap::new());
+        let mut sorted_routes: Vec<_> = routes.iter().collect();
+        sorted_routes.sort_by(|a, b| a.0.cmp(b.0));
+        for route in sorted_routes {
+            call_item_list_filter_hook(
+                &mut srv,
+                &route.1,
+                &usr,
+                &sq.collection,
+                &context,
+                &mut lr.map,
+            )
+            .await;
+        }
+    }
+    ranking.retain(|id| lr.map.contains_key(id));
+
+    HttpResponse::Ok().body(
+        serde_json::to_string(&SearchResult {
+            result: lr,
+            ranking,
+        })
+        .unwrap(),
+    )
+}
@@ -21,6 +21,7 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+use crate::handler::web_response::store_error_response;
 use crate::notif::gcal::*;
 use crate::server::user_control::*;
 use crate::state::state::*;
@@ -31,7 +32,7 @@ use actix_web::{web, HttpRequest, HttpResponse};
 use futures_util::TryStreamExt;
 use isabelle_dm::data_model::item::Item;
 use isabelle_dm::data_model::process_result::ProcessResult;
-use log::info;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 use serde_qs;
 use serde_qs::Config;
@@ -66,10 +67,12 @@ pub async fn setting_edit(
         }
     }
 
-    info!("Settings edited");
-
     // Set settings
-    srv.rw.set_settings(itm.clone()).await;
+    if let Err(e) = srv.rw.set_settings(itm.clone()).await {
+        error!("Failed to edit settings: {}", e);
+        return store_error_response(&e);
+    }
+    info!("Settings edited");
 
     return HttpResponse::Ok().body(
         serde_json::to_string(&ProcessResult {
@@ -95,7 +98,13 @@ pub async fn setting_list(
     }
 
     // Return settings finally
-    let st = srv.rw.get_settings().await.clone();
+    let st = match srv.rw.get_settings().await {
+        Ok(st) => st,
+        Err(e) => {
+            error!("Failed to read settings: {}", e);
+            return store_error_response(&e);
+        }
+    };
 
     HttpResponse::Ok()
         .body(serde_json::to_string(&st).unwrap())
@@ -21,29 +21,30 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+use crate::state::query::Filter;
 use crate::state::store::Store;
 use isabelle_dm::data_model::item::Item;
-use log::trace;
+use log::{error, trace};
+use serde_json::Value;
 
-/// Check if login has bad symbols
-pub fn login_has_bad_symbols(login: &str) -> bool {
-    let bad_symbols = ['"', '\\', '{', '}', '[', ']', '$'];
-    login.chars().any(|c| bad_symbols.contains(&c))
+/// Build filter matching users by login or email
+fn login_filter(login: &str) -> Filter {
+    return Filter::Or(vec![
+        Filter::eq("strs.login", Value::String(login.to_string())),
+        Filter::eq("strs.email", Value::String(login.to_string())),
+    ]);
 }
 
 /// Get user by given login
 pub async fn get_user(srv: &mut crate::state::data::Data, login: String) -> Option<Item> {
-    if login_has_bad_symbols(&login) {
-        return None;
-    }
-
-    let filter = "{ \"$or\": [ { \"strs.login\": \"".to_owned()
-        + &login
-        + "\" }, "
-        + "{ \"strs.email\": \""
-        + &login
-        + "\" } ]}";
-    let users = srv.rw.get_all_items("user", "name", &filter).await;
+    let filter = login_filter(&login);
+    let users = match srv.rw.get_all_items("user", "name", &filter).await {
+        Ok(users) => users,
+        Err(e) => {
+            error!("Failed to look up user {}: {}", login, e);
+            return None;
+        }
+    };
     let tmp_login = login.to_lowercase();
     trace!("Users: {}", users.map.len());
     for item in &users.map {
@@ -65,7 +66,6 @@ pub async fn check_role(
     role: &str,
 ) -> bool {
     let role_is = srv
-        .rw
         .get_internals()
         .await
         .safe_str("user_role_prefix", "role_is_");
@@ -80,17 +80,14 @@ pub async fn check_role(
 
 /// Clear OTP for all users with given login/email
 pub async fn clear_otp(srv: &mut crate::state::data::Data, login: String) {
-    if login_has_bad_symbols(&login) {
-        return;
-    }
-
-    let filter = "{ \"$or\": [ { \"strs.login\": \"".to_owned()
-        + &login
-        + "\" }, "
-        + "{ \"strs.email\": \""
-        + &login
-        + "\" } ]}";
-    let users = srv.rw.get_all_items("user", "name", &filter).await;
+    let filter = login_filter(&login);
+    let users = match srv.rw.get_all_items("user", "name", &filter).await {
+        Ok(users) => users,
+        Err(e) => {
+            error!("Failed to look up user {}: {}", login, e);
+            return;
+        }
+    };
     let tmp_login = login.to_lowercase();
     for item in &users.map {
         if item.1.strs.contains_key("login")
@@ -100,7 +97,9 @@ pub async fn clear_otp(srv: &mut crate::state::data::Data, login: String) {
         {
             let mut itm = item.1.clone();
             itm.set_str("otp", "");
-            srv.rw.set_item("user", &itm, false).await;
+            if let Err(e) = srv.rw.set_item("user", &itm, false).await {
+                error!("Failed to clear OTP of {}: {}", login, e);
+            }
             return;
         }
     }
@@ -0,0 +1,433 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::handler::web_response::store_error_response;
+use crate::server::user_control::*;
+use crate::state::query::Filter;
+use crate::state::state::*;
+use crate::state::store::{Store, StoreError};
+use crate::state::webhook::*;
+use actix_identity::Identity;
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use isabelle_dm::data_model::item::Item;
+use isabelle_dm::data_model::list_result::ListResult;
+use isabelle_dm::data_model::process_result::ProcessResult;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Query of webhook endpoints addressing single webhook or delivery
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct WebhookQuery {
+    pub id: u64,
+}
+
+/// Result of the test delivery
+#[derive(Serialize)]
+pub struct WebhookTestResult {
+    #[serde(flatten)]
+    pub result: ProcessResult,
+
+    /// HTTP status of the answer, zero if there was none
+    pub http_status: u64,
+}
+
+/// Respond with bad request and the error
+fn bad_request(error: &str) -> HttpResponse {
+    return HttpResponse::BadRequest().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: false,
+            error: error.to_string(),
+        })
+        .unwrap(),
+    );
+}
+
+/// Respond with success
+fn succeeded() -> HttpResponse {
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: true,
+            error: "".to_string(),
+        })
+        .unwrap(),
+    );
+}
+
+/// List registered webhooks, with secrets hidden. Admins only.
+pub async fn webhook_list(user: Identity, data: web::Data<State>) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Webhooks can't be managed by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    if let Err(e) = srv.rw.ensure_collection(WEBHOOK_COLLECTION).await {
+        error!("Failed to read webhooks: {}", e);
+        return store_error_response(&e);
+    }
+    let mut lr = match srv
+        .rw
+        .get_all_items(WEBHOOK_COLLECTION, "id", &Filter::All)
+        .await
+    {
+        Ok(lr) => lr,
+        Err(e) => {
+            error!("Failed to read webhooks: {}", e);
+            return store_error_response(&e);
+        }
+    };
+    for itm in lr.map.values_mut() {
+        itm.strs.remove("secret");
+    }
+
+    return HttpResponse::Ok().body(serde_json::to_string(&lr).unwrap());
+}
+
+/// Register new webhook or change existing one given in "item" field (with
+/// ID). Secret is kept unless the new one is given. Admins only.
+pub async fn webhook_edit(
+    user: Identity,
+    data: web::Data<State>,
+    mut payload: Multipart,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Webhooks can't be managed by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    let mut itm = Item::new();
+    itm.id = u64::MAX;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        while let Ok(Some(chunk)) = field.try_next().await {
+            if field.name() == "item" {
+                let v = &chunk.to_vec();
+                let strv = std::str::from_utf8(v).unwrap_or("{}");
+                let new_itm: Item = serde_json::from_str(strv).unwrap_or(Item::new());
+                itm.merge(&new_itm);
+            }
+        }
+    }
+
+    if let Err(e) = srv.rw.ensure_collection(WEBHOOK_COLLECTION).await {
+        error!("Failed to read webhooks: {}", e);
+        return store_error_response(&e);
+    }
+    if itm.id != u64::MAX {
+        match srv.rw.get_item(WEBHOOK_COLLECTION, itm.id).await {
+            Ok(Some(mut old_itm)) => {
+                if itm.safe_str("secret", "") == "" {
+                    itm.strs.remove("secret");
+                }
+                old_itm.merge(&itm);
+                itm = old_itm;
+            }
+            Ok(None) => {
+                let e = StoreError::NotFound(format!("webhook {}", itm.id));
+                return store_error_response(&e);
+            }
+            Err(e) => {
+                error!("Failed to read webhook {}: {}", itm.id, e);
+                return store_error_response(&e);
+            }
+        }
+    }
+    if !itm.bools.contains_key("active") {
+        itm.bools.insert("active".to_string(), true);
+    }
+
+    let hook = Webhook::from_item(&itm);
+    if let Err(e) = hook.check() {
+        error!("Rejected webhook: {}", e);
+        return bad_request(&e);
+    }
+    if !srv.has_collection(&hook.collection) {
+        error!("Collection {} doesn't exist", hook.collection);
+        return bad_request(&format!("Collection {} doesn't exist", hook.collection));
+    }
+    if let Err(e) = srv.rw.set_item(WEBHOOK_COLLECTION, &itm, false).await {
+        error!("Failed to write webhook: {}", e);
+        return store_error_response(&e);
+    }
+    info!("Webhook for {} registered: {}", hook.collection, hook.url);
+
+    return succeeded();
+}
+
+/// Remove the webhook. Its pending deliveries become dead letters.
+/// Admins only.
+pub async fn webhook_del(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Webhooks can't be managed by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    let wq = match serde_qs::from_str::<WebhookQuery>(&req.query_string()) {
+        Ok(wq) => wq,
+        Err(e) => return bad_request(&format!("Bad query: {}", e)),
+    };
+
+    if let Err(e) = srv.rw.ensure_collection(WEBHOOK_COLLECTION).await {
+        error!("Failed to read webhooks: {}", e);
+        return store_error_response(&e);
+    }
+    if let Err(e) = srv.rw.del_item(WEBHOOK_COLLECTION, wq.id).await {
+        error!("Failed to remove webhook {}: {}", wq.id, e);
+        return store_error_response(&e);
+    }
+    info!("Webhook {} removed", wq.id);
+
+    return succeeded();
+}
+
+/// List webhook deliveries, newest first. With `status=dead` this is the
+/// dead letter list. Admins only.
+pub async fn webhook_deliveries(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Deliveries can't be listed by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+    pub struct DeliveriesQuery {
+        pub webhook_id: Option<u64>,
+        pub status: Option<String>,
+        pub skip: Option<u64>,
+        pub limit: Option<u64>,
+    }
+
+    let dq = match serde_qs::from_str::<DeliveriesQuery>(&req.query_string()) {
+        Ok(dq) => dq,
+        Err(e) => return bad_request(&format!("Bad query: {}", e)),
+    };
+
+    let mut filter = Filter::All;
+    if let Some(webhook_id) = dq.webhook_id {
+        filter = filter.and(Filter::eq("u64s.webhook_id", Value::from(webhook_id)));
+    }
+    if let Some(status) = &dq.status {
+        filter = filter.and(Filter::eq("strs.status", Value::from(status.clone())));
+    }
+
+    if let Err(e) = srv.rw.ensure_collection(DELIVERY_COLLECTION).await {
+        error!("Failed to read webhook deliveries: {}", e);
+        return store_error_response(&e);
+    }
+    let lr: ListResult = match srv
+        .rw
+        .get_items(
+            DELIVERY_COLLECTION,
+            u64::MAX,
+            u64::MAX,
+            "-id",
+            &filter,
+            dq.skip.unwrap_or(u64::MAX),
+            dq.limit.unwrap_or(u64::MAX),
+        )
+        .await
+    {
+        Ok(lr) => lr,
+        Err(e) => {
+            error!("Failed to read webhook deliveries: {}", e);
+            return store_error_response(&e);
+        }
+    };
+
+    return HttpResponse::Ok().body(serde_json::to_string(&lr).unwrap());
+}
+
+/// Queue the delivery once again, e.g. to take it out of dead letters.
+/// Admins only.
+pub async fn webhook_redeliver(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let srv_lock = data.server.lock();
+    let mut srv = srv_lock.borrow_mut();
+    let usr = get_user(&mut srv, login).await;
+
+    // Deliveries can't be managed by non-admins
+    if !check_role(&mut srv, &usr, "admin").await {
+        return HttpResponse::Forbidden().into();
+    }
+
+    let wq = match serde_qs::from_str::<WebhookQuery>(&req.query_string()) {
+        Ok(wq) => wq,
+        Err(e) => return bad_request(&format!("Bad query: {}", e)),
+    };
+
+    if let Err(e) = srv.rw.ensure_collection(DELIVERY_COLLECTION).await {
+        error!("Failed to read webhook deliveries: {}", e);
+        return store_error_response(&e);
+    }
+    let mut delivery = match srv.rw.get_item(DELIVERY_COLLECTION, wq.id).await {
+        Ok(Some(delivery)) => delivery,
+        Ok(None) => {
+            let e = StoreError::NotFound(format!("webhook delivery {}", wq.id));
+            return store_error_response(&e);
+        }
+        Err(e) => {
+            error!("Failed to read webhook delivery {}: {}", wq.id, e);
+            return store_error_response(&e);
+        }
+    };
+
+    delivery.u64s.insert("attempts".to_string(), 0);
+    delivery
+        .u64s
+        .insert("next_attempt".to_string(), Utc::now().timestamp() as u64);
+    delivery
+        .strs
+        .insert("status".to_string(), STATUS_PENDING.to_string());
+    if let Err(e) = srv.rw.set_item(DELIVERY_COLLECTION, &delivery, false).await {
+        error!("Failed to write webhook delivery {}: {}", wq.id, e);
+        return store_error_response(&e);
+    }
+    info!("Webhook delivery {} queued again", wq.id);
+
+    return succeeded();
+}
+
+/// Send test delivery with "ping" action to the webhook right away and
+/// report the answer. The delivery isn't retried. Admins only.
+pub async fn webhook_test(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let hook = {
+        let login = match user.id() {
+            Ok(login) => login,
+            Err(e) => {
+                error!("Bad identity: {}", e);
+                return HttpResponse::Unauthorized().into();
+            }
+        };
+        let srv_lock = data.server.lock();
+        let mut srv = srv_lock.borrow_mut();
+        let usr = get_user(&mut srv, login).await;
+
+        // Webhooks can't be tested by non-admins
+        if !check_role(&mut srv, &usr, "admin").await {
+            return HttpResponse::Forbidden().into();
+        }
+
+        let wq = match serde_qs::from_str::<WebhookQuery>(&req.query_string()) {
+            Ok(wq) => wq,
+            Err(e) => return bad_request(&format!("Bad query: {}", e)),
+        };
+
+        match get_webhooks(&mut srv).await {
+            Ok(mut hooks) => match hooks.remove(&wq.id) {
+                Some(hook) => hook,
+                None => {
+                    let e = StoreError::NotFound(format!("webhook {}", wq.id));
+                    return store_error_response(&e);
+                }
+            },
+            Err(e) => {
+                error!("Failed to read webhooks: {}", e);
+                return store_error_response(&e);
+            }
+        }
+    };
+
+    // The target is contacted without holding the lock
+    let payload = serde_json::json!({
+        "collection": hook.collection,
+        "action": "ping",
+        "timestamp": Utc::now().timestamp(),
+    });
+    let mut delivery = new_delivery(&hook, &hook.collection, 0, "ping", &payload.to_string());
+    delivery.id = 0;
+    let attempt = send(&new_client(), &delivery, &hook).await;
+
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&WebhookTestResult {
+            result: ProcessResult {
+                succeeded: attempt.error.is_none(),
+                error: attempt.error.unwrap_or("".to_string()),
+            },
+            http_status: attempt.http_status,
+        })
+        .unwrap(),
+    );
+}
@@ -0,0 +1,369 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::server::events::{event_visible, visibility_filter};
+use crate::server::itm::edit_item;
+use crate::server::user_control::*;
+use crate::state::events::{ItemEvent, UserMessage};
+use crate::state::query::Filter;
+use crate::state::state::*;
+use crate::state::store::strip_reserved;
+use actix_identity::Identity;
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use isabelle_dm::data_model::item::Item;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+/// Message sent by the client
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Watch changes of collection items, optionally only of given IDs
+    Subscribe {
+        collection: String,
+        ids: Option<Vec<u64>>,
+        context: Option<String>,
+    },
+
+    /// Stop watching the collection
+    Unsubscribe { collection: String },
+
+    /// Edit the item as /itm/edit does
+    Edit {
+        collection: String,
+        item: Item,
+        merge: Option<bool>,
+        expected_version: Option<u64>,
+        seq: Option<u64>,
+    },
+}
+
+/// Message sent to the client
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// Change of the watched item
+    Event(ItemEvent),
+
+    /// Message pushed by a plugin
+    Message { message: String },
+
+    /// Result of the edit, with HTTP status and body /itm/edit would give
+    EditResult {
+        seq: Option<u64>,
+        status: u16,
+        result: Value,
+    },
+
+    /// Malformed or rejected client message
+    Error { error: String },
+}
+
+/// Collection watched by the connection
+struct Subscription {
+    ids: Option<HashSet<u64>>,
+    context: String,
+    filter: Filter,
+}
+
+/// WebSocket connection of the user
+struct Connection {
+    data: web::Data<State>,
+    session: Session,
+    user: Option<Item>,
+    subscriptions: HashMap<String, Subscription>,
+}
+
+impl Connection {
+    /// Send message to the client, false if the connection is closed
+    async fn send(&mut self, msg: &ServerMessage) -> bool {
+        let text = match serde_json::to_string(msg) {
+            Ok(text) => text,
+            Err(e) => {
+                // Nothing is sent, but the connection is still usable
+                error!("Failed to serialize WebSocket message: {}", e);
+                return true;
+            }
+        };
+        return self.session.text(text).await.is_ok();
+    }
+
+    /// Send error to the client
+    async fn send_error(&mut self, error: &str) -> bool {
+        return self
+            .send(&ServerMessage::Error {
+                error: error.to_string(),
+            })
+            .await;
+    }
+
+    /// Handle message of the client, false if the connection must be closed
+    async fn on_client_message(&mut self, msg: Message) -> bool {
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Ping(bytes) => return self.session.pong(&bytes).await.is_ok(),
+            Message::Close(_reason) => return false,
+            _ => return true,
+        };
+
+        let cm = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(cm) => cm,
+            Err(e) => {
+                error!("Malformed WebSocket message: {}", e);
+                return self.send_error(&format!("Malformed message: {}", e)).await;
+            }
+        };
+
+        match cm {
+            ClientMessage::Subscribe {
+                collection,
+                ids,
+                context,
+            } => {
+                return self.subscribe(collection, ids, context).await;
+            }
+            ClientMessage::Unsubscribe { collection } => {
+                self.subscriptions.remove(&collection);
+                return true;
+            }
+            ClientMessage::Edit {
+                collection,
+                item,
+                merge,
+                expected_version,
+                seq,
+            } => {
+                return self
+                    .edit(
+                        collection,
+                        item,
+                        merge.unwrap_or(false),
+                        expected_version,
+                        seq,
+                    )
+                    .await;
+            }
+        }
+    }
+
+    /// Start watching the collection
+    async fn subscribe(
+        &mut self,
+        collection: String,
+        ids: Option<Vec<u64>>,
+        context: Option<String>,
+    ) -> bool {
+        let context = context.unwrap_or("".to_string());
+        let filter = {
+            let srv_lock = self.data.server.lock();
+            let mut srv = srv_lock.borrow_mut();
+            if !srv.has_collection(&collection) {
+                None
+            } else {
+                Some(visibility_filter(&mut srv, &self.user, &collection, &context).await)
+            }
+        };
+
+        let filter = match filter {
+            Some(Ok(filter)) => filter,
+            Some(Err(e)) => return self.send_error(&e).await,
+            None => {
+                return self
+                    .send_error(&format!("Collection {} doesn't exist", collection))
+                    .await;
+            }
+        };
+
+        info!("Collection {} subscribed over WebSocket", collection);
+        self.subscriptions.insert(
+            collection,
+            Subscription {
+                ids: ids.map(|ids| ids.into_iter().collect()),
+                context,
+                filter,
+            },
+        );
+        return true;
+    }
+
+    /// Edit the item the same way as /itm/edit does and report the result
+    async fn edit(
+        &mut self,
+        collection: String,
+        mut itm: Item,
+        merge: bool,
+        expected_version: Option<u64>,
+        seq: Option<u64>,
+    ) -> bool {
+        strip_reserved(&mut itm);
+        let resp = {
+            let srv_lock = self.data.server.lock();
+            let mut srv = srv_lock.borrow_mut();
+            edit_item(
+                &mut srv,
+                &self.user,
+                &collection,
+                itm,
+                merge,
+                expected_version,
+            )
+            .await
+        };
+
+        let status = resp.status().as_u16();
+        let result = match actix_web::body::to_bytes(resp.into_body()).await {
+            Ok(body) => serde_json::from_slice(&body).unwrap_or(Value::Null),
+            Err(_e) => Value::Null,
+        };
+        return self
+            .send(&ServerMessage::EditResult {
+                seq,
+                status,
+                result,
+            })
+            .await;
+    }
+
+    /// Notify the client about the change if it watches the item and could
+    /// list it
+    async fn on_event(&mut self, event: ItemEvent) -> bool {
+        let visible = match self.subscriptions.get(&event.collection) {
+            Some(sub) => {
+                sub.ids.as_ref().map_or(true, |ids| ids.contains(&event.id))
+                    && event_visible(&self.data, &self.user, &sub.context, &sub.filter, &event)
+                        .await
+            }
+            None => false,
+        };
+
+        if !visible {
+            return true;
+        }
+        return self.send(&ServerMessage::Event(event)).await;
+    }
+
+    /// Pass the message pushed by a plugin if it's for this user
+    async fn on_user_message(&mut self, msg: UserMessage) -> bool {
+        if let Some(user_id) = msg.user_id {
+            if self.user.as_ref().map_or(true, |usr| usr.id != user_id) {
+                return true;
+            }
+        }
+        return self
+            .send(&ServerMessage::Message {
+                message: msg.message,
+            })
+            .await;
+    }
+}
+
+/// Serve the connection until either side closes it
+async fn serve(
+    mut conn: Connection,
+    mut stream: MessageStream,
+    mut events: Receiver<ItemEvent>,
+    mut messages: Receiver<UserMessage>,
+) {
+    loop {
+        let alive = tokio::select! {
+            msg = stream.recv() => match msg {
+                Some(Ok(msg)) => conn.on_client_message(msg).await,
+                Some(Err(e)) => {
+                    error!("WebSocket protocol error: {}", e);
+                    false
+                }
+                None => false,
+            },
+            event = events.recv() => match event {
+                Ok(event) => conn.on_event(event).await,
+                Err(RecvError::Lagged(cnt)) => {
+                    info!("WebSocket connection missed {} events", cnt);
+                    true
+                }
+                Err(RecvError::Closed) => false,
+            },
+            msg = messages.recv() => match msg {
+                Ok(msg) => conn.on_user_message(msg).await,
+                Err(RecvError::Lagged(cnt)) => {
+                    info!("WebSocket connection missed {} messages", cnt);
+                    true
+                }
+                Err(RecvError::Closed) => false,
+            },
+        };
+
+        if !alive {
+            break;
+        }
+    }
+
+    info!("WebSocket connection closed");
+    let _res = conn.session.close(None).await;
+}
+
+/// Open WebSocket connection of the logged in user, delivering item
+/// changes of subscribed collections and messages pushed by plugins, and
+/// accepting item edits
+pub async fn ws_connect(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+    body: web::Payload,
+) -> HttpResponse {
+    let login = match user.id() {
+        Ok(login) => login,
+        Err(e) => {
+            error!("Bad identity: {}", e);
+            return HttpResponse::Unauthorized().into();
+        }
+    };
+    let (resp, session, stream) = match actix_ws::handle(&req, body) {
+        Ok(res) => res,
+        Err(e) => {
+            error!("WebSocket handshake failed: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    let (usr, events, messages) = {
+        let srv_lock = data.server.lock();
+        let mut srv = srv_lock.borrow_mut();
+        let usr = get_user(&mut srv, login).await;
+        (usr, srv.events.subscribe(), srv.events.subscribe_messages())
+    };
+
+    info!("WebSocket connection opened");
+    let conn = Connection {
+        data: data.clone(),
+        session,
+        user: usr,
+        subscriptions: HashMap::new(),
+    };
+    rt::spawn(serve(conn, stream, events, messages));
+    return resp;
+}
@@ -27,19 +27,25 @@ use crate::get_password_hash;
 use crate::handler::route_call::call_collection_read_hook;
 use crate::init_google;
 use crate::send_email;
-use crate::state::store::Store;
+use crate::state::events::EventBus;
+use crate::state::index::declared_indexes;
+use crate::state::query::{Cmp, Filter};
+use crate::state::schema::enforce_references;
+use crate::state::store::{unwrap_or_log, Store, StoreResult};
 use crate::state::store_local::*;
-#[cfg(not(feature = "full_file_database"))]
 use crate::state::store_mongo::*;
 use crate::sync_with_google;
 use crate::verify_password;
 use crate::G_STATE;
+use chrono::Utc;
+use isabelle_dm::data_model::data_object_action::DataObjectAction;
 use isabelle_dm::data_model::item::Item;
 use isabelle_dm::data_model::list_result::ListResult;
 use isabelle_dm::data_model::process_result::ProcessResult;
 use isabelle_plugin_api::api::*;
 use isabelle_plugin_api::plugin_pool::PluginPool;
-use log::trace;
+use log::{error, trace};
+use serde_json::json;
 use std::any::Any;
 use std::collections::HashMap;
 use std::sync::mpsc;
@@ -47,6 +53,25 @@ use std::sync::Arc;
 use threadpool::ThreadPool;
 use tokio::runtime::Runtime;
 
+/// Parse filter given by plugin, rejecting malformed ones
+fn parse_plugin_filter(filter: &str) -> Option<Filter> {
+    match Filter::from_json_str(filter) {
+        Ok(f) => Some(f),
+        Err(e) => {
+            error!("Plugin gave malformed filter {}: {}", filter, e);
+            None
+        }
+    }
+}
+
+/// Empty list result
+fn empty_list_result() -> ListResult {
+    return ListResult {
+        map: HashMap::new(),
+        total_count: 0,
+    };
+}
+
 struct IsabellePluginApi {
     thread_pool: ThreadPool,
     runtime: Arc<Runtime>,
@@ -63,6 +88,109 @@ impl IsabellePluginApi {
     }
 }
 
+/// State handle plugins set to `(String, Vec<Item>, bool)` (collection,
+/// items, merge) to write items at once. The handle gets `bool` result.
+pub const BULK_SET_STATE: &str = "db_set_items";
+
+/// State handle plugins set to `(String, Vec<u64>)` (collection, IDs) to
+/// remove items at once. The handle gets `u64` number of removed items.
+pub const BULK_DEL_STATE: &str = "db_del_items";
+
+/// State handle plugins set to `(Option<u64>, String)` (receiving user ID,
+/// none for everyone, and message text) to push the message to users
+/// connected over WebSocket. The handle is cleared.
+pub const PUSH_MESSAGE_STATE: &str = "ws_message";
+
+/*
+ * Bulk operations and message pushing are not part of PluginApi trait, so
+ * plugins request them by setting state handles.
+ */
+impl IsabellePluginApi {
+    fn db_set_items(&self, collection: String, itms: Vec<Item>, merge: bool) -> bool {
+        trace!("db_set_items++");
+        let (sender, receiver) = mpsc::channel();
+        let rt = Arc::clone(&self.runtime);
+
+        self.thread_pool.execute(move || {
+            sender
+                .send(rt.block_on(async {
+                    let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
+                    let res = srv_mut.write_items(&None, &collection, &itms, merge).await;
+                    unwrap_or_log(res.map(|_| true), false)
+                }))
+                .unwrap()
+        });
+        let res = receiver.recv().unwrap();
+        trace!("db_set_items--");
+        res
+    }
+
+    fn db_del_items(&self, collection: String, ids: Vec<u64>) -> u64 {
+        trace!("db_del_items++");
+        let (sender, receiver) = mpsc::channel();
+        let rt = Arc::clone(&self.runtime);
+
+        self.thread_pool.execute(move || {
+            sender
+                .send(rt.block_on(async {
+                    let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
+                    unwrap_or_log(srv_mut.remove_items(&None, &collection, &ids).await, 0)
+                }))
+                .unwrap()
+        });
+        let res = receiver.recv().unwrap();
+        trace!("db_del_items--");
+        res
+    }
+
+    fn push_message(&self, user_id: Option<u64>, message: &str) {
+        trace!("push_message++");
+        let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
+        srv_mut.events.push(user_id, message);
+        trace!("push_message--");
+    }
+
+    /// Push message requested through state handle
+    fn push_request(&self, value: Option<Box<(dyn Any + Send)>>) {
+        match value.map(|v| v.downcast::<(Option<u64>, String)>()) {
+            Some(Ok(req)) => self.push_message(req.0, &req.1),
+            _ => error!("Bad {} request", PUSH_MESSAGE_STATE),
+        }
+    }
+
+    /// Run bulk operation requested through state handle and get its
+    /// result, none if the request is malformed
+    fn bulk_request(
+        &self,
+        handle: &str,
+        value: Option<Box<(dyn Any + Send)>>,
+    ) -> Option<Box<(dyn Any + Send)>> {
+        let value = value?;
+        if handle == BULK_SET_STATE {
+            return match value.downcast::<(String, Vec<Item>, bool)>() {
+                Ok(req) => {
+                    let (collection, itms, merge) = *req;
+                    Some(Box::new(self.db_set_items(collection, itms, merge)))
+                }
+                Err(_e) => {
+                    error!("Bad {} request", handle);
+                    None
+                }
+            };
+        }
+        return match value.downcast::<(String, Vec<u64>)>() {
+            Ok(req) => {
+                let (collection, ids) = *req;
+                Some(Box::new(self.db_del_items(collection, ids)))
+            }
+            Err(_e) => {
+                error!("Bad {} request", handle);
+                None
+            }
+        };
+    }
+}
+
 /*
  * It is important to note that in all cases Plugin API is called through
  * locations already protected by mutex. Therefore, we may safely omit
@@ -71,20 +199,26 @@ impl IsabellePluginApi {
 impl PluginApi for IsabellePluginApi {
     fn db_get_all_items(&self, collection: &str, sort_key: &str, filter: &str) -> ListResult {
         trace!("db_get_all_items++");
+        let filter1 = match parse_plugin_filter(filter) {
+            Some(f) => f,
+            None => return empty_list_result(),
+        };
         let (sender, receiver) = mpsc::channel();
         let collection1 = collection.to_string().clone();
         let sort_key1 = sort_key.to_string().clone();
-        let filter1 = filter.to_string().clone();
         let rt = Arc::clone(&self.runtime);
 
         self.thread_pool.execute(move || {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut
-                        .rw
-                        .get_all_items(&collection1, &sort_key1, &filter1)
-                        .await
+                    unwrap_or_log(
+                        srv_mut
+                            .rw
+                            .get_all_items(&collection1, &sort_key1, &filter1)
+                            .await,
+                        empty_list_result(),
+                    )
                 }))
                 .unwrap();
         });
@@ -104,28 +238,34 @@ impl PluginApi for IsabellePluginApi {
         limit: u64,
     ) -> ListResult {
         trace!("db_get_items++");
+        let filter1 = match parse_plugin_filter(filter) {
+            Some(f) => f,
+            None => return empty_list_result(),
+        };
         let (sender, receiver) = mpsc::channel();
         let collection1 = collection.to_string().clone();
         let sort_key1 = sort_key.to_string().clone();
-        let filter1 = filter.to_string().clone();
         let rt = Arc::clone(&self.runtime);
 
         self.thread_pool.execute(move || {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut
-                        .rw
-                        .get_items(
-                            &collection1,
-                            id_min,
-                            id_max,
-                            &sort_key1,
-                            &filter1,
-                            skip,
-                            limit,
-                        )
-                        .await
+                    unwrap_or_log(
+                        srv_mut
+                            .rw
+                            .get_items(
+                                &collection1,
+                                id_min,
+                                id_max,
+                                &sort_key1,
+                                &filter1,
+                                skip,
+                                limit,
+                            )
+                            .await,
+                        empty_list_result(),
+                    )
                 }))
                 .unwrap()
         });
@@ -144,7 +284,7 @@ impl PluginApi for IsabellePluginApi {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut.rw.get_item(&collection1, id).await
+                    unwrap_or_log(srv_mut.rw.get_item(&collection1, id).await, None)
                 }))
                 .unwrap()
         });
@@ -164,7 +304,11 @@ impl PluginApi for IsabellePluginApi {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut.rw.set_item(&collection1, &itm1, merge).await
+                    let itms = vec![itm1];
+                    unwrap_or_log(
+                        srv_mut.write_items(&None, &collection1, &itms, merge).await,
+                        (),
+                    )
                 }))
                 .unwrap()
         });
@@ -183,7 +327,8 @@ impl PluginApi for IsabellePluginApi {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut.rw.del_item(&collection1, id).await
+                    let res = srv_mut.remove_items(&None, &collection1, &vec![id]).await;
+                    unwrap_or_log(res.map(|cnt| cnt > 0), false)
                 }))
                 .unwrap()
         });
@@ -208,7 +353,7 @@ impl PluginApi for IsabellePluginApi {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut.rw.get_settings().await
+                    srv_mut.get_settings().await
                 }))
                 .unwrap()
         });
@@ -346,6 +491,15 @@ impl PluginApi for IsabellePluginApi {
 
     fn fn_set_state(&self, handle: &str, value: Option<Box<(dyn Any + Send)>>) {
         trace!("fn_set_state++");
+        // Bulk requests are replaced with their results, messages are pushed
+        let value = if handle == BULK_SET_STATE || handle == BULK_DEL_STATE {
+            self.bulk_request(handle, value)
+        } else if handle == PUSH_MESSAGE_STATE {
+            self.push_request(value);
+            None
+        } else {
+            value
+        };
         let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
 
         if srv_mut.opaque_data.contains_key(handle) {
@@ -359,15 +513,12 @@ impl PluginApi for IsabellePluginApi {
 /// Server data structure
 pub struct Data {
     /// File-based read/write data, which is useful for initial propagation
-    /// of database.
-    #[cfg(not(feature = "full_file_database"))]
+    /// of database. It is connected only on first run with other stores.
     pub file_rw: StoreLocal,
 
-    /// Read database access struct.
-    #[cfg(feature = "full_file_database")]
-    pub rw: StoreLocal,
-    #[cfg(not(feature = "full_file_database"))]
-    pub rw: StoreMongo,
+    /// Read database access struct. The actual backend is chosen at
+    /// startup.
+    pub rw: Box<dyn Store>,
 
     /// Path to Google Calendar.
     pub gc_path: String,
@@ -390,6 +541,9 @@ pub struct Data {
     /// Plugin API instance
     pub plugin_api: Box<dyn PluginApi>,
 
+    /// Item events for subscribers
+    pub events: EventBus,
+
     /// Opaque data (mainly for plugins)
     pub opaque_data: HashMap<String, Option<Box<(dyn Any + Send)>>>,
 
@@ -399,15 +553,10 @@ pub struct Data {
 
 impl Data {
     pub fn new() -> Self {
-        #[cfg(feature = "full_file_database")]
-        let rw = StoreLocal::new();
-        #[cfg(not(feature = "full_file_database"))]
-        let rw = StoreMongo::new();
         Self {
-            #[cfg(not(feature = "full_file_database"))]
             file_rw: StoreLocal::new(),
 
-            rw: rw,
+            rw: Box::new(StoreMongo::new()),
 
             gc_path: "".to_string(),
             py_path: "".to_string(),
@@ -418,28 +567,182 @@ impl Data {
                 plugins: Vec::new(),
             },
             plugin_api: Box::new(IsabellePluginApi::new()),
+            events: EventBus::new(),
             opaque_data: HashMap::new(),
             none_object: None,
         }
     }
 
-    /// Check existence of collection
+    /// Check existence of collection. Internal collections (starting with
+    /// "__") are hidden from users.
     pub fn has_collection(&mut self, collection: &str) -> bool {
-        return self.rw.collections.contains_key(collection);
+        if collection.starts_with("__") {
+            return false;
+        }
+        return self.rw.has_collection(collection);
+    }
+
+    /// Read internals, falling back to empty ones if they can't be read
+    pub async fn get_internals(&mut self) -> Item {
+        return unwrap_or_log(self.rw.get_internals().await, Item::new());
+    }
+
+    /// Read settings, falling back to empty ones if they can't be read
+    pub async fn get_settings(&mut self) -> Item {
+        return unwrap_or_log(self.rw.get_settings().await, Item::new());
+    }
+
+    /// Emit event about the item written with given ID, which is the one
+    /// returned by the store. Merged items are emitted as they are stored.
+    pub fn emit_change(
+        &self,
+        collection: &str,
+        id: u64,
+        itm: &Item,
+        old_itm: &Option<Item>,
+        merge: bool,
+        user: &Option<Item>,
+    ) {
+        let mut stored_itm = itm.clone();
+        if let (true, Some(old_itm)) = (merge, old_itm) {
+            stored_itm = old_itm.clone();
+            stored_itm.merge(itm);
+        }
+        stored_itm.id = id;
+        let action = if old_itm.is_some() {
+            DataObjectAction::Modify
+        } else {
+            DataObjectAction::Create
+        };
+        self.events
+            .emit(collection, id, action, user, Some(stored_itm));
+    }
+
+    /// Emit events about referencing items changed on removal
+    pub fn emit_references(
+        &self,
+        changes: Vec<(String, Item, DataObjectAction)>,
+        user: &Option<Item>,
+    ) {
+        for (collection, itm, action) in changes {
+            self.events
+                .emit(&collection, itm.id, action, user, Some(itm));
+        }
+    }
+
+    /// Write items on behalf of the user (none for plugins and the core)
+    /// and emit events about them
+    pub async fn write_items(
+        &mut self,
+        user: &Option<Item>,
+        collection: &str,
+        itms: &Vec<Item>,
+        merge: bool,
+    ) -> StoreResult<()> {
+        let mut old_itms: Vec<Option<Item>> = Vec::new();
+        for itm in itms {
+            if itm.id == u64::MAX {
+                old_itms.push(None);
+            } else {
+                old_itms.push(self.rw.get_item(collection, itm.id).await?);
+            }
+        }
+
+        let ids = self.rw.set_items(collection, itms, merge).await?;
+        for ((itm, old_itm), id) in itms.iter().zip(old_itms.iter()).zip(ids) {
+            self.emit_change(collection, id, itm, old_itm, merge, user);
+        }
+        return Ok(());
+    }
+
+    /// Remove items on behalf of the user (none for plugins and the core),
+    /// applying reference constraints, and emit events about them. Items
+    /// are only marked as deleted, as with /itm/del, so that they can be
+    /// restored or purged later. Returns number of removed items.
+    pub async fn remove_items(
+        &mut self,
+        user: &Option<Item>,
+        collection: &str,
+        ids: &Vec<u64>,
+    ) -> StoreResult<u64> {
+        let mut old_itms: Vec<Item> = Vec::new();
+        for id in ids {
+            match self.rw.get_item(collection, *id).await? {
+                Some(itm) if !itm.safe_bool("__deleted", false) => old_itms.push(itm),
+                _ => continue,
+            }
+        }
+
+        let alive: Vec<u64> = old_itms.iter().map(|itm| itm.id).collect();
+        let changes = enforce_references(self.rw.as_mut(), collection, &alive, true).await?;
+        self.emit_references(changes, user);
+
+        let now = Utc::now().timestamp() as u64;
+        for old_itm in old_itms {
+            let mut deleted_itm = old_itm;
+            deleted_itm.bools.insert("__deleted".to_string(), true);
+            deleted_itm.u64s.insert("__deleted_at".to_string(), now);
+            self.rw.set_item(collection, &deleted_itm, false).await?;
+            self.events.emit(
+                collection,
+                deleted_itm.id,
+                DataObjectAction::Delete,
+                user,
+                Some(deleted_itm),
+            );
+        }
+        return Ok(alive.len() as u64);
+    }
+
+    /// Get IDs of items expired according to TTL indexes, per collection
+    pub async fn expired_items(&mut self) -> Vec<(String, Vec<u64>)> {
+        let mut expired: Vec<(String, Vec<u64>)> = Vec::new();
+        let internals = self.get_internals().await;
+        let collections = unwrap_or_log(self.rw.get_collections().await, Vec::new());
+        let now = Utc::now().timestamp();
+        for collection in &collections {
+            // Broken declarations are reported on connection
+            let specs = declared_indexes(&internals, collection).unwrap_or(Vec::new());
+            for spec in specs {
+                let ttl = match spec.ttl {
+                    Some(ttl) => ttl,
+                    None => continue,
+                };
+                let filter =
+                    Filter::Cmp(spec.fields[0].0.clone(), Cmp::Lt, json!(now - ttl as i64));
+                let lr = match self.rw.get_all_items(collection, "id", &filter).await {
+                    Ok(lr) => lr,
+                    Err(e) => {
+                        error!("Failed to find expired items of {}: {}", collection, e);
+                        continue;
+                    }
+                };
+                if lr.map.is_empty() {
+                    continue;
+                }
+
+                let mut ids: Vec<u64> = lr.map.into_keys().collect();
+                ids.sort();
+                expired.push((collection.clone(), ids));
+            }
+        }
+        return expired;
     }
 
     /// Early initialization
     pub async fn init_checks(&mut self) {
-        let internals = self.rw.get_internals().await;
+        let internals = self.get_internals().await;
         let routes = internals.safe_strstr("collection_read_hook", &HashMap::new());
-        let collections = self.rw.get_collections().await;
+        let collections = unwrap_or_log(self.rw.get_collections().await, Vec::new());
 
         // Load all collections
         for collection in &collections {
-            // Load all items and resave them
-            let items = self.rw.get_item_ids(collection).await;
+            // Load all items and resave changed ones at once
+            let items = unwrap_or_log(self.rw.get_item_ids(collection).await, HashMap::new());
+            let mut changed: Vec<Item> = Vec::new();
             for itm in items {
-                let loaded_item_opt = self.rw.get_item(collection, itm.0).await;
+                let loaded_item_opt =
+                    unwrap_or_log(self.rw.get_item(collection, itm.0).await, None);
                 if loaded_item_opt.is_none() {
                     continue;
                 }
@@ -452,9 +755,15 @@ impl Data {
                     }
                 }
                 if should_be_saved {
-                    self.rw.set_item(collection, &loaded_item, false).await;
+                    changed.push(loaded_item);
                 }
             }
+            if !changed.is_empty() {
+                unwrap_or_log(
+                    self.rw.set_items(collection, &changed, false).await,
+                    Vec::new(),
+                );
+            }
         }
     }
 }
@@ -0,0 +1,149 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use isabelle_dm::data_model::data_object_action::DataObjectAction;
+use isabelle_dm::data_model::item::Item;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Number of events kept for slow subscribers before they miss some
+const EVENT_CAPACITY: usize = 1024;
+
+/// Get name of the action for events and history records
+pub fn action_name(action: &DataObjectAction) -> &'static str {
+    match action {
+        DataObjectAction::Create => "create",
+        DataObjectAction::Modify => "modify",
+        DataObjectAction::Delete => "delete",
+    }
+}
+
+/// Name of restoring the soft-deleted item in events and history. There
+/// is no such DataObjectAction, so hooks see it as modification.
+pub const RESTORE_ACTION: &str = "restore";
+
+/// Change of the item made through the core
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemEvent {
+    /// Collection of the item
+    pub collection: String,
+
+    /// Item ID
+    pub id: u64,
+
+    /// Action: create, modify or delete
+    pub action: String,
+
+    /// ID of the acting user, none for changes made by the core or plugins
+    pub user_id: Option<u64>,
+
+    /// Login of the acting user
+    pub user: String,
+
+    /// Item as it is stored after the change, used for visibility checks
+    #[serde(skip)]
+    pub item: Option<Item>,
+}
+
+/// Custom message pushed by plugins to connected users
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserMessage {
+    /// Receiving user, none for all users
+    pub user_id: Option<u64>,
+
+    /// Message text
+    pub message: String,
+}
+
+/// Bus delivering item events and user messages to all subscribers
+pub struct EventBus {
+    sender: broadcast::Sender<ItemEvent>,
+    messages: broadcast::Sender<UserMessage>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _receiver) = broadcast::channel(EVENT_CAPACITY);
+        let (messages, _receiver) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender, messages }
+    }
+
+    /// Emit event about the item change
+    pub fn emit(
+        &self,
+        collection: &str,
+        id: u64,
+        action: DataObjectAction,
+        user: &Option<Item>,
+        itm: Option<Item>,
+    ) {
+        self.emit_named(collection, id, action_name(&action), user, itm);
+    }
+
+    /// Emit event about the item change with given action name
+    pub fn emit_named(
+        &self,
+        collection: &str,
+        id: u64,
+        action: &str,
+        user: &Option<Item>,
+        itm: Option<Item>,
+    ) {
+        let event = ItemEvent {
+            collection: collection.to_string(),
+            id,
+            action: action.to_string(),
+            user_id: user.as_ref().map(|usr| usr.id),
+            user: user
+                .as_ref()
+                .map_or("".to_string(), |usr| usr.safe_str("login", "")),
+            item: itm,
+        };
+        trace!("Event: {} {} {}", event.action, collection, id);
+
+        // Fails only if nobody listens
+        let _res = self.sender.send(event);
+    }
+
+    /// Subscribe to events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ItemEvent> {
+        return self.sender.subscribe();
+    }
+
+    /// Push message to the connected user, or to everyone
+    pub fn push(&self, user_id: Option<u64>, message: &str) {
+        trace!("Message to {:?}: {}", user_id, message);
+
+        // Fails only if nobody listens
+        let _res = self.messages.send(UserMessage {
+            user_id,
+            message: message.to_string(),
+        });
+    }
+
+    /// Subscribe to user messages pushed from now on
+    pub fn subscribe_messages(&self) -> broadcast::Receiver<UserMessage> {
+        return self.messages.subscribe();
+    }
+}
@@ -0,0 +1,234 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::query::lookup;
+use isabelle_dm::data_model::item::Item;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Internals entry declaring secondary indexes of collections
+pub const INDEXES: &str = "indexes";
+
+/// Prefix of declared index names. Only such indexes are dropped when they
+/// are no longer declared.
+pub const INDEX_PREFIX: &str = "__";
+
+/// Indexes of internal collections, declared by the core itself
+const BUILTIN_INDEXES: [(&str, &str); 1] = [("__history", "strs.collection,u64s.item_id")];
+
+/// Key of values that exist, but can't be indexed
+const UNKEYED: &str = "*";
+
+/// Secondary index of the collection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexSpec {
+    /// Index name
+    pub name: String,
+
+    /// Indexed fields with order: 1 is ascending, -1 is descending
+    pub fields: Vec<(String, i32)>,
+
+    /// No two items may have the same values of indexed fields
+    pub unique: bool,
+
+    /// Items expire given number of seconds after UNIX timestamp kept in
+    /// the indexed field
+    pub ttl: Option<u64>,
+}
+
+impl IndexSpec {
+    /// Parse index declaration: comma-separated fields, descending ones
+    /// prefixed with "-", followed by optional "unique" and "ttl=<seconds>",
+    /// e.g. "strs.email unique" or "u64s.created ttl=86400". Names without
+    /// dots refer to string fields.
+    pub fn parse(decl: &str) -> Result<IndexSpec, String> {
+        let mut words = decl.split_whitespace();
+        let field_list = words.next().ok_or("Empty index declaration")?;
+
+        let mut spec = IndexSpec {
+            name: INDEX_PREFIX.to_string(),
+            fields: Vec::new(),
+            unique: false,
+            ttl: None,
+        };
+        for field in field_list.split(',').filter(|f| *f != "") {
+            let (field, order) = match field.strip_prefix('-') {
+                Some(f) => (f, -1),
+                None => (field, 1),
+            };
+            let path = if field.contains('.') {
+                field.to_string()
+            } else {
+                "strs.".to_string() + field
+            };
+            spec.fields.push((path, order));
+        }
+        if spec.fields.is_empty() {
+            return Err(format!("No fields in index \"{}\"", decl));
+        }
+
+        for word in words {
+            if word == "unique" {
+                spec.unique = true;
+            } else if let Some(ttl) = word.strip_prefix("ttl=") {
+                spec.ttl = Some(
+                    ttl.parse::<u64>()
+                        .map_err(|_| format!("Bad TTL in index \"{}\"", decl))?,
+                );
+            } else {
+                return Err(format!("Unknown option {} in index \"{}\"", word, decl));
+            }
+        }
+        if spec.ttl.is_some() && spec.fields.len() != 1 {
+            return Err(format!("TTL index \"{}\" must have one field", decl));
+        }
+
+        // The name reflects the whole declaration, so changed declarations
+        // replace old indexes
+        let parts: Vec<String> = spec
+            .fields
+            .iter()
+            .map(|(field, order)| format!("{}_{}", field, order))
+            .collect();
+        spec.name += &parts.join("_");
+        if spec.unique {
+            spec.name += "_unique";
+        }
+        if let Some(ttl) = spec.ttl {
+            spec.name += &format!("_ttl{}", ttl);
+        }
+        return Ok(spec);
+    }
+}
+
+/// Get indexes of the collection declared in internals. Declarations of
+/// the collection are separated with semicolons.
+pub fn declared_indexes(internals: &Item, collection: &str) -> Result<Vec<IndexSpec>, String> {
+    let decls = internals.safe_strstr(INDEXES, &HashMap::new());
+    let mut specs: Vec<IndexSpec> = Vec::new();
+    for (coll, decl) in BUILTIN_INDEXES {
+        if coll == collection {
+            specs.push(IndexSpec::parse(decl)?);
+        }
+    }
+    if let Some(list) = decls.get(collection) {
+        for decl in list.split(';').filter(|d| d.trim() != "") {
+            specs.push(IndexSpec::parse(decl)?);
+        }
+    }
+    return Ok(specs);
+}
+
+/// Get key of the field value in field index, if it can be indexed. Equal
+/// numbers of different types get the same key.
+fn value_key(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some("s".to_string() + s),
+        Value::Number(n) => n.as_f64().map(|f| format!("n{}", f)),
+        Value::Bool(b) => Some(format!("b{}", b)),
+        _ => None,
+    }
+}
+
+/// Equality index over item fields, kept in memory by stores without
+/// secondary indexes of their own. It finds items that may match, the
+/// filter is still checked against them.
+#[derive(Debug, Clone)]
+pub struct FieldIndex {
+    /// Indexed fields
+    pub fields: Vec<String>,
+
+    /// Item IDs by field and value key
+    values: HashMap<String, HashMap<String, HashSet<u64>>>,
+
+    /// Value keys of every item by field, to remove them on change
+    keys: HashMap<u64, Vec<(String, String)>>,
+}
+
+impl FieldIndex {
+    pub fn new(fields: &Vec<String>) -> Self {
+        Self {
+            fields: fields.clone(),
+            values: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Index the item, replacing its previous state
+    pub fn insert(&mut self, itm: &Item) {
+        self.remove(itm.id);
+        let doc = serde_json::to_value(itm).unwrap_or(Value::Null);
+        let mut item_keys: Vec<(String, String)> = Vec::new();
+        for field in &self.fields {
+            let key = match lookup(&doc, field) {
+                Some(v) => value_key(v).unwrap_or(UNKEYED.to_string()),
+                None => continue,
+            };
+            self.values
+                .entry(field.clone())
+                .or_default()
+                .entry(key.clone())
+                .or_default()
+                .insert(itm.id);
+            item_keys.push((field.clone(), key));
+        }
+        self.keys.insert(itm.id, item_keys);
+    }
+
+    /// Forget the item
+    pub fn remove(&mut self, id: u64) {
+        for (field, key) in self.keys.remove(&id).unwrap_or(Vec::new()) {
+            if let Some(ids) = self.values.get_mut(&field).and_then(|v| v.get_mut(&key)) {
+                ids.remove(&id);
+            }
+        }
+    }
+
+    /// Get IDs of items that may have given field values, none if no
+    /// indexed field can be used
+    pub fn find(&self, equalities: &Vec<(&String, &Value)>) -> Option<HashSet<u64>> {
+        let mut found: Option<HashSet<u64>> = None;
+        for (field, value) in equalities {
+            if !self.fields.contains(field) {
+                continue;
+            }
+            let key = match value_key(value) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let mut ids: HashSet<u64> = HashSet::new();
+            if let Some(values) = self.values.get(*field) {
+                for k in [key.as_str(), UNKEYED] {
+                    ids.extend(values.get(k).into_iter().flatten());
+                }
+            }
+            found = Some(match found {
+                Some(prev) => prev.intersection(&ids).cloned().collect(),
+                None => ids,
+            });
+        }
+        return found;
+    }
+}
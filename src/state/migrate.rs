@@ -0,0 +1,253 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::store::*;
+use isabelle_dm::data_model::item::Item;
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Options of the migration
+#[derive(Debug, Clone, Default)]
+pub struct MigrateOptions {
+    /// Only report differences, don't write anything
+    pub dry_run: bool,
+
+    /// Remove target items missing in the source
+    pub prune: bool,
+
+    /// Collection to migrate, all collections along with settings and
+    /// internals by default
+    pub collection: Option<String>,
+}
+
+/// Differences of the collection between source and target
+#[derive(Debug, Clone, Default)]
+pub struct CollectionDiff {
+    /// Items missing in the target
+    pub missing: u64,
+
+    /// Items differing in the target
+    pub changed: u64,
+
+    /// Items that are the same in both stores
+    pub same: u64,
+
+    /// Items present only in the target
+    pub extra: u64,
+}
+
+/// Get canonical form of the item for comparison. Every store maintains
+/// item versions on its own, so they are left out.
+fn canonical(itm: &Item) -> String {
+    let mut itm = itm.clone();
+    itm.u64s.remove(ITEM_VERSION);
+    // Values keep object keys sorted, unlike item maps
+    return serde_json::to_value(&itm)
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+}
+
+/// Compare the collection page by page and copy missing and changed items
+async fn migrate_collection(
+    src: &mut dyn Store,
+    dst: &mut dyn Store,
+    collection: &str,
+    dst_exists: bool,
+    opts: &MigrateOptions,
+) -> StoreResult<CollectionDiff> {
+    if !opts.dry_run {
+        dst.ensure_collection(collection).await?;
+    }
+
+    let mut diff = CollectionDiff::default();
+    let mut total = None;
+    let mut done = 0;
+    let mut id_min = 0;
+    loop {
+        let lr = read_range(src, collection, id_min, u64::MAX, PAGE_SIZE).await?;
+        let total = *total.get_or_insert(lr.total_count);
+        let mut itms: Vec<Item> = lr.map.into_values().collect();
+        itms.sort_by_key(|itm| itm.id);
+
+        // The last page covers the rest of the target
+        let id_max = match itms.last() {
+            Some(itm) if itms.len() as u64 >= PAGE_SIZE => itm.id,
+            _ => u64::MAX,
+        };
+        let mut dst_itms: HashMap<u64, Item> = if dst_exists {
+            read_range(dst, collection, id_min, id_max, u64::MAX)
+                .await?
+                .map
+        } else {
+            HashMap::new()
+        };
+
+        done += itms.len() as u64;
+        let mut writes: Vec<Item> = Vec::new();
+        for itm in itms {
+            match dst_itms.remove(&itm.id) {
+                None => {
+                    diff.missing += 1;
+                    writes.push(itm);
+                }
+                Some(dst_itm) if canonical(&dst_itm) != canonical(&itm) => {
+                    diff.changed += 1;
+                    writes.push(itm);
+                }
+                Some(_dst_itm) => diff.same += 1,
+            }
+        }
+        let extra: Vec<u64> = dst_itms.into_keys().collect();
+        diff.extra += extra.len() as u64;
+
+        if !opts.dry_run {
+            if !writes.is_empty() {
+                dst.set_items(collection, &writes, false).await?;
+            }
+            if opts.prune && !extra.is_empty() {
+                dst.del_items(collection, &extra).await?;
+            }
+        }
+        info!("{}: {}/{} items", collection, done, total);
+
+        if id_max == u64::MAX {
+            break;
+        }
+        id_min = id_max + 1;
+    }
+
+    return Ok(diff);
+}
+
+/// Count items of the collection and compute checksum of their contents
+pub async fn summarize(store: &mut dyn Store, collection: &str) -> StoreResult<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut cnt = 0;
+    let mut id_min = 0;
+    loop {
+        let itms = read_page(store, collection, id_min).await?;
+        for itm in &itms {
+            hasher.update(canonical(itm).as_bytes());
+            hasher.update(b"\n");
+            cnt += 1;
+        }
+
+        match next_page(&itms) {
+            Some(next) => id_min = next,
+            None => break,
+        }
+    }
+
+    let checksum: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    return Ok((cnt, checksum));
+}
+
+/// Copy settings or internals unless they are the same
+async fn migrate_document(
+    name: &str,
+    itm: Item,
+    dst_itm: Item,
+    dst: &mut dyn Store,
+    opts: &MigrateOptions,
+) -> StoreResult<()> {
+    if canonical(&itm) == canonical(&dst_itm) {
+        info!("{}: same", name);
+        return Ok(());
+    }
+
+    info!("{}: changed", name);
+    if !opts.dry_run {
+        match name {
+            "settings" => dst.set_settings(itm).await?,
+            _ => dst.set_internals(itm).await?,
+        }
+    }
+    return Ok(());
+}
+
+/// Migrate collections, settings and internals from one store to another
+/// and print the differences. Items the target already has with the same
+/// contents are left alone, so interrupted migration is resumed by running
+/// it again. After migration counts and checksums of collections are
+/// verified. Returns number of collections failing verification.
+pub async fn migrate(
+    src: &mut dyn Store,
+    dst: &mut dyn Store,
+    opts: &MigrateOptions,
+) -> StoreResult<u64> {
+    let src_collections = src.get_collections().await?;
+    let collections = match &opts.collection {
+        Some(collection) => {
+            if !src_collections.contains(collection) {
+                return Err(StoreError::NotFound(format!("collection {}", collection)));
+            }
+            vec![collection.clone()]
+        }
+        None => src_collections,
+    };
+    let dst_collections = dst.get_collections().await?;
+
+    for collection in &collections {
+        let dst_exists = dst_collections.contains(collection);
+        let diff = migrate_collection(src, dst, collection, dst_exists, opts).await?;
+        info!(
+            "{}: {} missing, {} changed, {} same, {} extra",
+            collection, diff.missing, diff.changed, diff.same, diff.extra
+        );
+    }
+
+    if opts.collection.is_none() {
+        let (itm, dst_itm) = (src.get_settings().await?, dst.get_settings().await?);
+        migrate_document("settings", itm, dst_itm, dst, opts).await?;
+        let (itm, dst_itm) = (src.get_internals().await?, dst.get_internals().await?);
+        migrate_document("internals", itm, dst_itm, dst, opts).await?;
+    }
+
+    if opts.dry_run {
+        return Ok(0);
+    }
+
+    let mut failed = 0;
+    for collection in &collections {
+        let (src_cnt, src_sum) = summarize(src, collection).await?;
+        let (dst_cnt, dst_sum) = summarize(dst, collection).await?;
+        if src_cnt == dst_cnt && src_sum == dst_sum {
+            info!(
+                "{}: verified {} items, checksum {}",
+                collection, src_cnt, src_sum
+            );
+        } else {
+            error!(
+                "{}: verification failed: {} items (checksum {}) in source, {} items (checksum {}) in target",
+                collection, src_cnt, src_sum, dst_cnt, dst_sum
+            );
+            failed += 1;
+        }
+    }
+    return Ok(failed);
+}
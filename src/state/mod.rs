@@ -22,8 +22,16 @@
  * DEALINGS IN THE SOFTWARE.
  */
 pub mod data;
-pub mod merger;
+pub mod events;
+pub mod index;
+pub mod migrate;
+pub mod query;
+pub mod schema;
+pub mod search;
 pub mod state;
 pub mod store;
 pub mod store_local;
 pub mod store_mongo;
+pub mod store_sqlite;
+pub mod transfer;
+pub mod webhook;
@@ -0,0 +1,633 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::store::{StoreError, StoreResult};
+use regex::{Regex, RegexBuilder};
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Field marking soft-deleted items
+pub const DELETED_FIELD: &str = "bools.__deleted";
+
+/// Field keeping UNIX timestamp of soft deletion
+pub const DELETED_AT_FIELD: &str = "u64s.__deleted_at";
+
+/// Comparison operator
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+/// Regular expression of the filter, compiled once when the filter is built
+#[derive(Debug, Clone)]
+pub struct RegexPattern {
+    /// Pattern as given
+    pub pattern: String,
+
+    /// Mongo-style options as given
+    pub options: String,
+
+    /// Compiled expression
+    regex: Regex,
+}
+
+impl RegexPattern {
+    pub fn new(pattern: &str, options: &str) -> Result<RegexPattern, String> {
+        return Ok(RegexPattern {
+            pattern: pattern.to_string(),
+            options: options.to_string(),
+            regex: build_regex(pattern, options)?,
+        });
+    }
+}
+
+impl PartialEq for RegexPattern {
+    fn eq(&self, other: &Self) -> bool {
+        return self.pattern == other.pattern && self.options == other.options;
+    }
+}
+
+/// Query filter. It is built either directly by the core or parsed from
+/// Mongo-style JSON coming from clients and plugins.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// Matches every item
+    All,
+
+    /// All of the filters must match
+    And(Vec<Filter>),
+
+    /// Any of the filters must match
+    Or(Vec<Filter>),
+
+    /// None of the filters must match
+    Nor(Vec<Filter>),
+
+    /// Compare field with the value
+    Cmp(String, Cmp, Value),
+
+    /// Field is equal to one of the values
+    In(String, Vec<Value>),
+
+    /// Field is equal to none of the values
+    Nin(String, Vec<Value>),
+
+    /// Field exists or doesn't exist
+    Exists(String, bool),
+
+    /// Field matches regular expression
+    Regex(String, RegexPattern),
+}
+
+impl Filter {
+    /// Field is equal to the value
+    pub fn eq(field: &str, value: Value) -> Filter {
+        return Filter::Cmp(field.to_string(), Cmp::Eq, value);
+    }
+
+    /// Combine two filters so that both must match
+    pub fn and(self, other: Filter) -> Filter {
+        match (self, other) {
+            (Filter::All, f) | (f, Filter::All) => f,
+            (Filter::And(mut v), Filter::And(v2)) => {
+                v.extend(v2);
+                Filter::And(v)
+            }
+            (Filter::And(mut v), f) => {
+                v.push(f);
+                Filter::And(v)
+            }
+            (f, g) => Filter::And(vec![f, g]),
+        }
+    }
+
+    /// Filter matching every item, soft-deleted ones included
+    pub fn with_deleted() -> Filter {
+        return Filter::Or(vec![
+            Filter::Exists(DELETED_FIELD.to_string(), true),
+            Filter::Exists(DELETED_FIELD.to_string(), false),
+        ]);
+    }
+
+    /// Get fields the filter requires to be equal to values, looking only
+    /// into conjunctions
+    pub fn equalities(&self) -> Vec<(&String, &Value)> {
+        match self {
+            Filter::Cmp(field, Cmp::Eq, val) => vec![(field, val)],
+            Filter::And(v) => v.iter().flat_map(|f| f.equalities()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Check if filter matches every item
+    pub fn is_all(&self) -> bool {
+        return *self == Filter::All;
+    }
+
+    /// Check if filter only asks to show soft-deleted items along with the
+    /// rest, see `with_deleted`
+    pub fn is_with_deleted(&self) -> bool {
+        match self {
+            Filter::Or(v) => matches!(
+                v.as_slice(),
+                [Filter::Exists(a, true), Filter::Exists(b, false)]
+                    if a == DELETED_FIELD && b == DELETED_FIELD
+            ),
+            _ => false,
+        }
+    }
+
+    /// Check if filter refers to the field anywhere
+    pub fn mentions(&self, field: &str) -> bool {
+        match self {
+            Filter::All => false,
+            Filter::And(v) | Filter::Or(v) | Filter::Nor(v) => v.iter().any(|f| f.mentions(field)),
+            Filter::Cmp(f, _, _)
+            | Filter::In(f, _)
+            | Filter::Nin(f, _)
+            | Filter::Exists(f, _)
+            | Filter::Regex(f, _) => f == field,
+        }
+    }
+
+    /// Hide soft-deleted items unless the filter asks about them
+    /// explicitly. Filter asking for all items becomes `All`, so that stores
+    /// don't evaluate it.
+    pub fn hide_deleted(self) -> Filter {
+        if self.is_with_deleted() {
+            return Filter::All;
+        }
+        if self.mentions(DELETED_FIELD) {
+            return self;
+        }
+        return self.and(Filter::Cmp(
+            DELETED_FIELD.to_string(),
+            Cmp::Ne,
+            Value::Bool(true),
+        ));
+    }
+
+    /// Parse filter from JSON string. Empty string means no filtering.
+    pub fn from_json_str(filter: &str) -> Result<Filter, String> {
+        if filter.trim() == "" {
+            return Ok(Filter::All);
+        }
+
+        let v: Value = serde_json::from_str(filter)
+            .map_err(|e| "invalid JSON: ".to_owned() + &e.to_string())?;
+        return Filter::parse(&v);
+    }
+
+    /// Parse filter from Mongo-style JSON value
+    pub fn parse(v: &Value) -> Result<Filter, String> {
+        let obj = v
+            .as_object()
+            .ok_or("filter must be an object".to_string())?;
+        let mut parts: Vec<Filter> = Vec::new();
+
+        for (key, val) in obj {
+            match key.as_str() {
+                "$and" | "$or" | "$nor" => {
+                    let arr = val.as_array().ok_or(key.to_owned() + " needs an array")?;
+                    let mut sub: Vec<Filter> = Vec::new();
+                    for f in arr {
+                        sub.push(Filter::parse(f)?);
+                    }
+                    parts.push(match key.as_str() {
+                        "$and" => Filter::And(sub),
+                        "$or" => Filter::Or(sub),
+                        _ => Filter::Nor(sub),
+                    });
+                }
+                _ => {
+                    if key.starts_with('$') {
+                        return Err("unsupported operator ".to_owned() + key);
+                    }
+                    Filter::check_field(key)?;
+                    parts.push(Filter::parse_field(key, val)?);
+                }
+            }
+        }
+
+        return Ok(Filter::from_parts(parts));
+    }
+
+    /// Make a single filter out of list of filters that must all match
+    fn from_parts(mut parts: Vec<Filter>) -> Filter {
+        match parts.len() {
+            0 => Filter::All,
+            1 => parts.remove(0),
+            _ => Filter::And(parts),
+        }
+    }
+
+    /// Check that field path is sane
+    fn check_field(field: &str) -> Result<(), String> {
+        if field.split('.').any(|p| p == "" || p.starts_with('$')) {
+            return Err("invalid field ".to_owned() + field);
+        }
+        return Ok(());
+    }
+
+    /// Parse condition on a single field
+    fn parse_field(field: &str, cond: &Value) -> Result<Filter, String> {
+        let ops = match cond {
+            Value::Object(m) if !m.is_empty() && m.keys().any(|k| k.starts_with('$')) => m,
+            _ => {
+                return Ok(Filter::eq(field, cond.clone()));
+            }
+        };
+
+        if !ops.keys().all(|k| k.starts_with('$')) {
+            return Err("operators can't be mixed with values in ".to_owned() + field);
+        }
+
+        let mut parts: Vec<Filter> = Vec::new();
+        for (op, arg) in ops {
+            let f = match op.as_str() {
+                "$eq" => Filter::Cmp(field.to_string(), Cmp::Eq, arg.clone()),
+                "$ne" => Filter::Cmp(field.to_string(), Cmp::Ne, arg.clone()),
+                "$gt" => Filter::Cmp(field.to_string(), Cmp::Gt, arg.clone()),
+                "$gte" => Filter::Cmp(field.to_string(), Cmp::Gte, arg.clone()),
+                "$lt" => Filter::Cmp(field.to_string(), Cmp::Lt, arg.clone()),
+                "$lte" => Filter::Cmp(field.to_string(), Cmp::Lte, arg.clone()),
+                "$in" | "$nin" => {
+                    let arr = arg.as_array().ok_or(op.to_owned() + " needs an array")?;
+                    if op == "$in" {
+                        Filter::In(field.to_string(), arr.clone())
+                    } else {
+                        Filter::Nin(field.to_string(), arr.clone())
+                    }
+                }
+                "$exists" => {
+                    let b = arg.as_bool().ok_or("$exists needs a boolean".to_string())?;
+                    Filter::Exists(field.to_string(), b)
+                }
+                "$regex" => {
+                    let pattern = arg.as_str().ok_or("$regex needs a string".to_string())?;
+                    let options = match ops.get("$options") {
+                        Some(o) => o.as_str().ok_or("$options needs a string".to_string())?,
+                        None => "",
+                    };
+                    match RegexPattern::new(pattern, options) {
+                        Ok(re) => Filter::Regex(field.to_string(), re),
+                        Err(e) => return Err("invalid $regex: ".to_owned() + &e),
+                    }
+                }
+                "$options" => {
+                    if !ops.contains_key("$regex") {
+                        return Err("$options needs $regex".to_string());
+                    }
+                    continue;
+                }
+                "$not" => Filter::Nor(vec![Filter::parse_field(field, arg)?]),
+                _ => {
+                    return Err("unsupported operator ".to_owned() + op);
+                }
+            };
+            parts.push(f);
+        }
+
+        return Ok(Filter::from_parts(parts));
+    }
+
+    /// Convert filter to Mongo-style JSON
+    pub fn to_json(&self) -> Value {
+        match self {
+            Filter::All => json!({}),
+            Filter::And(v) if v.is_empty() => json!({}),
+            Filter::Nor(v) if v.is_empty() => json!({}),
+            // Mongo doesn't accept empty $or, so make filter that never matches
+            Filter::Or(v) if v.is_empty() => json!({ "id": { "$in": [] } }),
+            Filter::And(v) => {
+                json!({ "$and": v.iter().map(|f| f.to_json()).collect::<Vec<Value>>() })
+            }
+            Filter::Or(v) => {
+                json!({ "$or": v.iter().map(|f| f.to_json()).collect::<Vec<Value>>() })
+            }
+            Filter::Nor(v) => {
+                json!({ "$nor": v.iter().map(|f| f.to_json()).collect::<Vec<Value>>() })
+            }
+            Filter::Cmp(field, op, val) => {
+                let op = match op {
+                    Cmp::Eq => "$eq",
+                    Cmp::Ne => "$ne",
+                    Cmp::Gt => "$gt",
+                    Cmp::Gte => "$gte",
+                    Cmp::Lt => "$lt",
+                    Cmp::Lte => "$lte",
+                };
+                let mut cond = Map::new();
+                cond.insert(op.to_string(), val.clone());
+                let mut m = Map::new();
+                m.insert(field.clone(), Value::Object(cond));
+                Value::Object(m)
+            }
+            Filter::In(field, vals) | Filter::Nin(field, vals) => {
+                let op = if let Filter::In(_, _) = self {
+                    "$in"
+                } else {
+                    "$nin"
+                };
+                let mut cond = Map::new();
+                cond.insert(op.to_string(), Value::Array(vals.clone()));
+                let mut m = Map::new();
+                m.insert(field.clone(), Value::Object(cond));
+                Value::Object(m)
+            }
+            Filter::Exists(field, b) => {
+                let mut m = Map::new();
+                m.insert(field.clone(), json!({ "$exists": b }));
+                Value::Object(m)
+            }
+            Filter::Regex(field, re) => {
+                let mut m = Map::new();
+                m.insert(
+                    field.clone(),
+                    json!({ "$regex": re.pattern, "$options": re.options }),
+                );
+                Value::Object(m)
+            }
+        }
+    }
+
+    /// Convert filter to BSON document for Mongo
+    pub fn to_bson(&self) -> StoreResult<bson::Document> {
+        return bson::to_document(&self.to_json())
+            .map_err(|e| StoreError::Invalid(format!("Filter {}: {}", self, e)));
+    }
+
+    /// Evaluate filter against JSON representation of an item
+    pub fn matches(&self, doc: &Value) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::And(v) => v.iter().all(|f| f.matches(doc)),
+            Filter::Or(v) => v.iter().any(|f| f.matches(doc)),
+            Filter::Nor(v) => !v.iter().any(|f| f.matches(doc)),
+            Filter::Cmp(field, op, val) => {
+                let fv = lookup(doc, field);
+                match op {
+                    Cmp::Eq => field_equals(fv, val),
+                    Cmp::Ne => !field_equals(fv, val),
+                    Cmp::Gt => field_compare(fv, val, |o| o == Ordering::Greater),
+                    Cmp::Gte => field_compare(fv, val, |o| o != Ordering::Less),
+                    Cmp::Lt => field_compare(fv, val, |o| o == Ordering::Less),
+                    Cmp::Lte => field_compare(fv, val, |o| o != Ordering::Greater),
+                }
+            }
+            Filter::In(field, vals) => {
+                let fv = lookup(doc, field);
+                vals.iter().any(|v| field_equals(fv, v))
+            }
+            Filter::Nin(field, vals) => {
+                let fv = lookup(doc, field);
+                !vals.iter().any(|v| field_equals(fv, v))
+            }
+            Filter::Exists(field, b) => lookup(doc, field).is_some() == *b,
+            Filter::Regex(field, re) => field_regex(lookup(doc, field), &re.regex),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+/// Build regular expression with Mongo-style options
+pub fn build_regex(pattern: &str, options: &str) -> Result<regex::Regex, String> {
+    return RegexBuilder::new(pattern)
+        .case_insensitive(options.contains('i'))
+        .multi_line(options.contains('m'))
+        .dot_matches_new_line(options.contains('s'))
+        .build()
+        .map_err(|e| e.to_string());
+}
+
+/// Look up the value by dotted path (like "strs.login")
+pub fn lookup<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut cur = doc;
+    for part in path.split('.') {
+        cur = cur.as_object()?.get(part)?;
+    }
+    return Some(cur);
+}
+
+/// Rank of the value type, following Mongo ordering across types
+fn type_rank(v: Option<&Value>) -> u8 {
+    match v {
+        None => 0,
+        Some(Value::Null) => 1,
+        Some(Value::Number(_)) => 2,
+        Some(Value::String(_)) => 3,
+        Some(Value::Object(_)) => 4,
+        Some(Value::Array(_)) => 5,
+        Some(Value::Bool(_)) => 6,
+    }
+}
+
+/// Compare two values the way Mongo sorts them
+pub fn compare_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    let (ra, rb) = (type_rank(a), type_rank(b));
+    if ra != rb {
+        return ra.cmp(&rb);
+    }
+
+    match (a, b) {
+        (Some(Value::Number(x)), Some(Value::Number(y))) => {
+            if let (Some(x), Some(y)) = (x.as_u64(), y.as_u64()) {
+                return x.cmp(&y);
+            }
+            let (x, y) = (x.as_f64().unwrap_or(0.0), y.as_f64().unwrap_or(0.0));
+            return x.partial_cmp(&y).unwrap_or(Ordering::Equal);
+        }
+        (Some(Value::String(x)), Some(Value::String(y))) => x.cmp(y),
+        (Some(Value::Bool(x)), Some(Value::Bool(y))) => x.cmp(y),
+        (Some(x), Some(y)) => x.to_string().cmp(&y.to_string()),
+        _ => Ordering::Equal,
+    }
+}
+
+/// Check values for equality, treating numbers by their numeric value
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare_values(Some(a), Some(b)) == Ordering::Equal,
+        _ => a == b,
+    }
+}
+
+/// Check if the field value matches the expected one. Arrays match if any
+/// of their elements matches, as in Mongo.
+fn field_equals(field: Option<&Value>, expected: &Value) -> bool {
+    match field {
+        None => expected.is_null(),
+        Some(Value::Array(arr)) => {
+            values_equal(field.unwrap(), expected) || arr.iter().any(|v| values_equal(v, expected))
+        }
+        Some(v) => values_equal(v, expected),
+    }
+}
+
+/// Compare the field with the value, only if they have comparable types
+fn field_compare(field: Option<&Value>, arg: &Value, check: fn(Ordering) -> bool) -> bool {
+    match field {
+        Some(Value::Array(arr)) => arr.iter().any(|v| field_compare(Some(v), arg, check)),
+        Some(v) => {
+            type_rank(Some(v)) == type_rank(Some(arg)) && check(compare_values(Some(v), Some(arg)))
+        }
+        None => false,
+    }
+}
+
+/// Match the field against regular expression
+fn field_regex(field: Option<&Value>, re: &Regex) -> bool {
+    match field {
+        Some(Value::String(s)) => re.is_match(s),
+        Some(Value::Array(arr)) => arr.iter().any(|v| match v {
+            Value::String(s) => re.is_match(s),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+/// Get list of fields to sort by for given sort key. Plain keys are looked
+/// up among strings first, then among numbers. Leading "-" means
+/// descending order. Items are finally ordered by ID.
+pub fn sort_fields(sort_key: &str) -> Vec<(String, i32)> {
+    let (key, order) = match sort_key.strip_prefix('-') {
+        Some(k) => (k, -1),
+        None => (sort_key, 1),
+    };
+    let mut fields: Vec<(String, i32)> = Vec::new();
+
+    if key != "" && key != "id" {
+        if key.contains('.') {
+            fields.push((key.to_string(), order));
+        } else {
+            fields.push(("strs.".to_owned() + key, order));
+            fields.push(("u64s.".to_owned() + key, order));
+        }
+        fields.push(("id".to_string(), 1));
+    } else {
+        fields.push(("id".to_string(), order));
+    }
+
+    return fields;
+}
+
+/// Compare two items by given sort fields
+pub fn compare_by_fields(a: &Value, b: &Value, fields: &Vec<(String, i32)>) -> Ordering {
+    for (path, order) in fields {
+        let res = compare_values(lookup(a, path), lookup(b, path));
+        if res != Ordering::Equal {
+            return if *order < 0 { res.reverse() } else { res };
+        }
+    }
+    return Ordering::Equal;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> Value {
+        return json!({
+            "id": 7,
+            "strs": { "name": "Alice Smith", "role": "admin" },
+            "u64s": { "age": 30 },
+            "bools": {},
+        });
+    }
+
+    #[test]
+    fn empty_filter_is_all() {
+        assert_eq!(Filter::from_json_str("").unwrap(), Filter::All);
+        assert_eq!(Filter::from_json_str("  ").unwrap(), Filter::All);
+        assert_eq!(Filter::from_json_str("{}").unwrap(), Filter::All);
+    }
+
+    #[test]
+    fn bad_filters_are_rejected() {
+        assert!(Filter::from_json_str("{").is_err());
+        assert!(Filter::from_json_str("[]").is_err());
+        assert!(Filter::from_json_str(r#"{"$where": "1"}"#).is_err());
+        assert!(Filter::from_json_str(r#"{"strs..name": "a"}"#).is_err());
+        assert!(Filter::from_json_str(r#"{"u64s.age": {"$gt": 1, "x": 2}}"#).is_err());
+        assert!(Filter::from_json_str(r#"{"strs.name": {"$options": "i"}}"#).is_err());
+        assert!(Filter::from_json_str(r#"{"strs.name": {"$regex": "("}}"#).is_err());
+        assert!(Filter::from_json_str(r#"{"strs.name": {"$in": 1}}"#).is_err());
+    }
+
+    #[test]
+    fn filters_match() {
+        let d = doc();
+        let matches = |f: &str| Filter::from_json_str(f).unwrap().matches(&d);
+
+        assert!(matches(r#"{"strs.role": "admin"}"#));
+        assert!(!matches(r#"{"strs.role": "user"}"#));
+        assert!(matches(r#"{"u64s.age": {"$gte": 30, "$lt": 31}}"#));
+        assert!(!matches(r#"{"u64s.age": {"$gt": 30}}"#));
+        assert!(matches(r#"{"strs.role": {"$in": ["user", "admin"]}}"#));
+        assert!(matches(r#"{"strs.role": {"$nin": ["user"]}}"#));
+        assert!(matches(r#"{"strs.email": {"$exists": false}}"#));
+        assert!(matches(
+            r#"{"strs.name": {"$regex": "^alice", "$options": "i"}}"#
+        ));
+        assert!(!matches(r#"{"strs.name": {"$regex": "^alice"}}"#));
+        assert!(matches(r#"{"u64s.age": {"$not": {"$lt": 18}}}"#));
+        assert!(matches(
+            r#"{"$or": [{"strs.role": "user"}, {"u64s.age": 30}]}"#
+        ));
+        assert!(!matches(
+            r#"{"$nor": [{"strs.role": "user"}, {"u64s.age": 30}]}"#
+        ));
+        assert!(!matches(
+            r#"{"$and": [{"strs.role": "admin"}, {"u64s.age": 31}]}"#
+        ));
+    }
+
+    #[test]
+    fn deleted_items_are_hidden() {
+        let mut deleted = doc();
+        deleted["bools"]["__deleted"] = json!(true);
+
+        let f = Filter::eq("strs.role", json!("admin")).hide_deleted();
+        assert!(f.matches(&doc()));
+        assert!(!f.matches(&deleted));
+
+        assert!(!Filter::All.hide_deleted().matches(&deleted));
+        assert_eq!(Filter::with_deleted().hide_deleted(), Filter::All);
+
+        let only_deleted = Filter::eq(DELETED_FIELD, json!(true));
+        assert_eq!(only_deleted.clone().hide_deleted(), only_deleted);
+        assert!(only_deleted.hide_deleted().matches(&deleted));
+    }
+}
@@ -0,0 +1,680 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::query::Filter;
+use crate::state::store::*;
+use chrono::Utc;
+use isabelle_dm::data_model::data_object_action::DataObjectAction;
+use isabelle_dm::data_model::item::Item;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+/// Internals entry declaring schemas of collections
+pub const SCHEMAS: &str = "schemas";
+
+/// Type of the field, defining the item map keeping it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    Str,
+    U64,
+    Bool,
+    StrStr,
+}
+
+impl FieldType {
+    fn parse(name: &str) -> Option<FieldType> {
+        match name {
+            "str" => Some(FieldType::Str),
+            "u64" => Some(FieldType::U64),
+            "bool" => Some(FieldType::Bool),
+            "strstr" => Some(FieldType::StrStr),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FieldType::Str => "str",
+            FieldType::U64 => "u64",
+            FieldType::Bool => "bool",
+            FieldType::StrStr => "strstr",
+        }
+    }
+
+    /// Get types of the field present in the item
+    fn present(itm: &Item, name: &str) -> Vec<FieldType> {
+        let mut types: Vec<FieldType> = Vec::new();
+        if itm.strs.contains_key(name) {
+            types.push(FieldType::Str);
+        }
+        if itm.u64s.contains_key(name) {
+            types.push(FieldType::U64);
+        }
+        if itm.bools.contains_key(name) {
+            types.push(FieldType::Bool);
+        }
+        if itm.strstrs.contains_key(name) {
+            types.push(FieldType::StrStr);
+        }
+        return types;
+    }
+}
+
+/// What happens to referencing items when referenced item is removed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnDelete {
+    /// Removal fails
+    Restrict,
+
+    /// Referencing items are removed as well
+    Cascade,
+
+    /// Reference is removed from referencing items
+    SetNull,
+}
+
+impl OnDelete {
+    fn parse(name: &str) -> Option<OnDelete> {
+        match name {
+            "restrict" => Some(OnDelete::Restrict),
+            "cascade" => Some(OnDelete::Cascade),
+            "set_null" => Some(OnDelete::SetNull),
+            _ => None,
+        }
+    }
+}
+
+/// Declared field of the collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldSchema {
+    /// Field name inside its map
+    pub name: String,
+
+    /// Field type
+    pub kind: FieldType,
+
+    /// Field must be present
+    pub required: bool,
+
+    /// Minimal string length or number value
+    pub min: Option<u64>,
+
+    /// Maximal string length or number value
+    pub max: Option<u64>,
+
+    /// Allowed values, any if empty
+    pub values: Vec<String>,
+
+    /// Collection the field refers to with item ID
+    pub reference: Option<String>,
+
+    /// Constraint applied when referenced item is removed
+    pub on_delete: OnDelete,
+}
+
+/// Schema of the collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    /// Declared fields
+    pub fields: Vec<FieldSchema>,
+
+    /// Fields that are not declared are rejected
+    pub strict: bool,
+}
+
+/// Violation of the schema
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    /// Field name
+    pub field: String,
+
+    /// What is wrong with it
+    pub error: String,
+}
+
+impl FieldError {
+    fn new(field: &str, error: String) -> Self {
+        Self {
+            field: field.to_string(),
+            error,
+        }
+    }
+}
+
+impl FieldSchema {
+    /// Parse field declaration: "<name>:<type>" followed by optional
+    /// "required", "min=<n>", "max=<n>", "enum=<a>|<b>" and
+    /// "ref=<collection>" with "on_delete=restrict|cascade|set_null"
+    /// (restrict by default), e.g. "status:str required enum=new|closed".
+    pub fn parse(decl: &str) -> Result<FieldSchema, String> {
+        let mut words = decl.split_whitespace();
+        let name_type = words.next().ok_or("Empty field declaration")?;
+        let (name, kind) = name_type
+            .split_once(':')
+            .ok_or(format!("No type of field \"{}\"", decl))?;
+        let kind =
+            FieldType::parse(kind).ok_or(format!("Unknown type {} of field {}", kind, name))?;
+
+        let mut field = FieldSchema {
+            name: name.to_string(),
+            kind,
+            required: false,
+            min: None,
+            max: None,
+            values: Vec::new(),
+            reference: None,
+            on_delete: OnDelete::Restrict,
+        };
+        let mut on_delete: Option<OnDelete> = None;
+        for word in words {
+            let (opt, val) = word.split_once('=').unwrap_or((word, ""));
+            let num = || {
+                val.parse::<u64>()
+                    .map_err(|_| format!("Bad {} of field {}", opt, name))
+            };
+            match opt {
+                "required" => field.required = true,
+                "min" => field.min = Some(num()?),
+                "max" => field.max = Some(num()?),
+                "enum" => field.values = val.split('|').map(|v| v.to_string()).collect(),
+                "ref" if kind == FieldType::U64 && val != "" => {
+                    field.reference = Some(val.to_string())
+                }
+                "on_delete" => {
+                    on_delete = Some(
+                        OnDelete::parse(val)
+                            .ok_or(format!("Bad on_delete {} of field {}", val, name))?,
+                    )
+                }
+                _ => return Err(format!("Bad option {} of field {}", word, name)),
+            }
+        }
+        if let Some(on_delete) = on_delete {
+            if field.reference.is_none() {
+                return Err(format!("on_delete of field {} without ref", name));
+            }
+            field.on_delete = on_delete;
+        }
+        return Ok(field);
+    }
+
+    /// Check the field of the item, references aside
+    fn check(&self, itm: &Item, errors: &mut Vec<FieldError>) {
+        let types = FieldType::present(itm, &self.name);
+        if types.iter().any(|t| *t != self.kind) {
+            errors.push(FieldError::new(
+                &self.name,
+                format!("Must be {}", self.kind.name()),
+            ));
+            return;
+        }
+        if types.is_empty() {
+            if self.required {
+                errors.push(FieldError::new(&self.name, "Is required".to_string()));
+            }
+            return;
+        }
+
+        let (value, size) = match self.kind {
+            FieldType::Str => {
+                let s = &itm.strs[&self.name];
+                (s.clone(), s.chars().count() as u64)
+            }
+            FieldType::U64 => {
+                let v = itm.u64s[&self.name];
+                (v.to_string(), v)
+            }
+            _ => return,
+        };
+        if let Some(min) = self.min {
+            if size < min {
+                errors.push(FieldError::new(
+                    &self.name,
+                    format!("Must be at least {}", min),
+                ));
+            }
+        }
+        if let Some(max) = self.max {
+            if size > max {
+                errors.push(FieldError::new(
+                    &self.name,
+                    format!("Must be at most {}", max),
+                ));
+            }
+        }
+        if !self.values.is_empty() && !self.values.contains(&value) {
+            errors.push(FieldError::new(
+                &self.name,
+                format!("Must be one of {}", self.values.join(", ")),
+            ));
+        }
+    }
+}
+
+impl Schema {
+    /// Parse schema: field declarations separated with semicolons, and
+    /// optional "strict" declaration rejecting undeclared fields
+    pub fn parse(decls: &str) -> Result<Schema, String> {
+        let mut schema = Schema {
+            fields: Vec::new(),
+            strict: false,
+        };
+        for decl in decls.split(';').map(|d| d.trim()).filter(|d| *d != "") {
+            if decl == "strict" {
+                schema.strict = true;
+            } else {
+                schema.fields.push(FieldSchema::parse(decl)?);
+            }
+        }
+        return Ok(schema);
+    }
+
+    /// Check the item, references aside
+    pub fn check(&self, itm: &Item) -> Vec<FieldError> {
+        let mut errors: Vec<FieldError> = Vec::new();
+        for field in &self.fields {
+            field.check(itm, &mut errors);
+        }
+
+        if self.strict {
+            // Internal fields (starting with "__") are always allowed
+            let mut names: Vec<&String> = itm
+                .strs
+                .keys()
+                .chain(itm.u64s.keys())
+                .chain(itm.bools.keys())
+                .chain(itm.strstrs.keys())
+                .filter(|name| !name.starts_with("__"))
+                .collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                if !self.fields.iter().any(|f| f.name == *name) {
+                    errors.push(FieldError::new(name, "Unknown field".to_string()));
+                }
+            }
+        }
+        return errors;
+    }
+
+    /// Check that items referred by the item exist
+    pub async fn check_references(
+        &self,
+        store: &mut dyn Store,
+        itm: &Item,
+    ) -> StoreResult<Vec<FieldError>> {
+        let mut errors: Vec<FieldError> = Vec::new();
+        for field in &self.fields {
+            let (collection, id) = match (&field.reference, itm.u64s.get(&field.name)) {
+                (Some(collection), Some(id)) => (collection, *id),
+                _ => continue,
+            };
+
+            let target = store.get_item(collection, id).await?;
+            if target.map_or(true, |t| t.safe_bool("__deleted", false)) {
+                errors.push(FieldError::new(
+                    &field.name,
+                    format!("Item {} of {} doesn't exist", id, collection),
+                ));
+            }
+        }
+        return Ok(errors);
+    }
+}
+
+/// Get schema of the collection declared in internals, None if there is no
+/// schema
+pub fn collection_schema(internals: &Item, collection: &str) -> Result<Option<Schema>, String> {
+    let decls = internals.safe_strstr(SCHEMAS, &HashMap::new());
+    return match decls.get(collection) {
+        Some(decls) => Ok(Some(Schema::parse(decls)?)),
+        None => Ok(None),
+    };
+}
+
+/// Get fields of all collections referring to the given one: collection,
+/// field and constraint. Broken schemas are skipped.
+fn referrers(internals: &Item, collection: &str) -> Vec<(String, String, OnDelete)> {
+    let mut res: Vec<(String, String, OnDelete)> = Vec::new();
+    for (referrer, decls) in internals.safe_strstr(SCHEMAS, &HashMap::new()) {
+        let schema = match Schema::parse(&decls) {
+            Ok(schema) => schema,
+            Err(_) => continue,
+        };
+        for field in schema.fields {
+            if field.reference.as_deref() == Some(collection) {
+                res.push((referrer.clone(), field.name, field.on_delete));
+            }
+        }
+    }
+    return res;
+}
+
+/// Apply reference constraints before the items are removed: referencing
+/// items are removed as well (cascade) or lose the reference (set null).
+/// Soft removal marks cascaded items as deleted, otherwise they are removed
+/// for good. If removal is restricted, nothing is changed and the error
+/// names the referencing items. Returns changed referencing items with
+/// collections and actions.
+pub async fn enforce_references(
+    store: &mut dyn Store,
+    collection: &str,
+    ids: &Vec<u64>,
+    soft: bool,
+) -> StoreResult<Vec<(String, Item, DataObjectAction)>> {
+    let internals = store.get_internals().await?;
+    let mut removed: HashSet<(String, u64)> =
+        ids.iter().map(|id| (collection.to_string(), *id)).collect();
+    let mut queue: Vec<(String, u64)> = removed.iter().cloned().collect();
+    let mut cascaded: HashMap<String, Vec<Item>> = HashMap::new();
+    let mut nulled: HashMap<(String, u64), Item> = HashMap::new();
+    let mut blockers: Vec<String> = Vec::new();
+
+    // Find all affected items first, so that nothing is changed when
+    // removal is restricted
+    while let Some((target, id)) = queue.pop() {
+        for (referrer, field, on_delete) in referrers(&internals, &target) {
+            let filter = Filter::eq(&("u64s.".to_string() + &field), json!(id));
+            let lr = store.get_all_items(&referrer, "id", &filter).await?;
+            let mut found: Vec<Item> = lr.map.into_values().collect();
+            found.sort_by_key(|itm| itm.id);
+            for itm in found {
+                let key = (referrer.clone(), itm.id);
+                if removed.contains(&key) {
+                    continue;
+                }
+                match on_delete {
+                    OnDelete::Restrict => {
+                        blockers.push(format!("{} {} ({})", referrer, itm.id, field))
+                    }
+                    OnDelete::Cascade => {
+                        removed.insert(key.clone());
+                        queue.push(key);
+                        cascaded.entry(referrer.clone()).or_default().push(itm);
+                    }
+                    OnDelete::SetNull => {
+                        nulled.entry(key).or_insert(itm).u64s.remove(&field);
+                    }
+                }
+            }
+        }
+    }
+    if !blockers.is_empty() {
+        return Err(StoreError::Referenced(blockers.join(", ")));
+    }
+
+    let mut updated: HashMap<String, Vec<Item>> = HashMap::new();
+    for ((referrer, id), itm) in nulled {
+        if !removed.contains(&(referrer.clone(), id)) {
+            updated.entry(referrer).or_default().push(itm);
+        }
+    }
+    let mut changes: Vec<(String, Item, DataObjectAction)> = Vec::new();
+    for (referrer, itms) in updated {
+        store.set_items(&referrer, &itms, false).await?;
+        for itm in itms {
+            changes.push((referrer.clone(), itm, DataObjectAction::Modify));
+        }
+    }
+
+    for (referrer, mut itms) in cascaded {
+        if soft {
+            let now = Utc::now().timestamp() as u64;
+            for itm in &mut itms {
+                itm.bools.insert("__deleted".to_string(), true);
+                itm.u64s.insert("__deleted_at".to_string(), now);
+            }
+            store.set_items(&referrer, &itms, false).await?;
+        } else {
+            let cascaded_ids: Vec<u64> = itms.iter().map(|itm| itm.id).collect();
+            store.del_items(&referrer, &cascaded_ids).await?;
+        }
+        for itm in itms {
+            changes.push((referrer.clone(), itm, DataObjectAction::Delete));
+        }
+    }
+    return Ok(changes);
+}
+
+/// Check the item against schema of the collection, references included.
+/// Items of collections without schema are always valid.
+pub async fn validate_item(
+    store: &mut dyn Store,
+    collection: &str,
+    itm: &Item,
+) -> StoreResult<Vec<FieldError>> {
+    let internals = store.get_internals().await?;
+    let schema = match collection_schema(&internals, collection) {
+        Ok(Some(schema)) => schema,
+        Ok(None) => return Ok(Vec::new()),
+        Err(e) => return Err(StoreError::Serialization(format!("Broken schema: {}", e))),
+    };
+
+    let mut errors = schema.check(itm);
+    errors.extend(schema.check_references(store, itm).await?);
+    return Ok(errors);
+}
+
+/// Check existing items against schemas and print the report. Returns
+/// number of invalid items.
+pub async fn validate_database(
+    store: &mut dyn Store,
+    collection: &Option<String>,
+) -> StoreResult<u64> {
+    let internals = store.get_internals().await?;
+    let mut collections: Vec<String> = internals
+        .safe_strstr(SCHEMAS, &HashMap::new())
+        .into_keys()
+        .filter(|c| collection.as_ref().map_or(true, |only| c == only))
+        .collect();
+    collections.sort();
+
+    let mut invalid = 0;
+    for collection in &collections {
+        let schema = match collection_schema(&internals, collection) {
+            Ok(Some(schema)) => schema,
+            Ok(None) => {
+                info!("{}: no schema to check", collection);
+                continue;
+            }
+            Err(e) => {
+                error!("{}: broken schema: {}", collection, e);
+                invalid += 1;
+                continue;
+            }
+        };
+
+        let lr = store.get_all_items(collection, "id", &Filter::All).await?;
+        let mut ids: Vec<&u64> = lr.map.keys().collect();
+        ids.sort();
+        let mut coll_invalid = 0;
+        for id in ids {
+            let itm = &lr.map[id];
+            let mut errors = schema.check(itm);
+            errors.extend(schema.check_references(store, itm).await?);
+            for e in &errors {
+                error!("{} {}: {}: {}", collection, id, e.field, e.error);
+            }
+            if !errors.is_empty() {
+                coll_invalid += 1;
+            }
+        }
+        info!(
+            "{}: {} items, {} invalid",
+            collection,
+            lr.map.len(),
+            coll_invalid
+        );
+        invalid += coll_invalid;
+    }
+
+    return Ok(invalid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::store_local::StoreLocal;
+
+    fn item(strs: &[(&str, &str)], u64s: &[(&str, u64)]) -> Item {
+        let mut itm = Item::new();
+        itm.id = 1;
+        for (k, v) in strs {
+            itm.strs.insert(k.to_string(), v.to_string());
+        }
+        for (k, v) in u64s {
+            itm.u64s.insert(k.to_string(), *v);
+        }
+        return itm;
+    }
+
+    fn errors(schema: &Schema, itm: &Item) -> Vec<(String, String)> {
+        return schema
+            .check(itm)
+            .into_iter()
+            .map(|e| (e.field, e.error))
+            .collect();
+    }
+
+    #[test]
+    fn field_is_parsed() {
+        let field = FieldSchema::parse("status:str required min=1 max=8 enum=new|closed").unwrap();
+        assert_eq!(field.name, "status");
+        assert_eq!(field.kind, FieldType::Str);
+        assert!(field.required);
+        assert_eq!(field.min, Some(1));
+        assert_eq!(field.max, Some(8));
+        assert_eq!(field.values, vec!["new", "closed"]);
+        assert_eq!(field.reference, None);
+
+        let field = FieldSchema::parse("owner:u64 ref=users on_delete=cascade").unwrap();
+        assert_eq!(field.reference, Some("users".to_string()));
+        assert_eq!(field.on_delete, OnDelete::Cascade);
+    }
+
+    #[test]
+    fn bad_fields_are_rejected() {
+        assert!(FieldSchema::parse("").is_err());
+        assert!(FieldSchema::parse("name").is_err());
+        assert!(FieldSchema::parse("name:text").is_err());
+        assert!(FieldSchema::parse("name:str min=x").is_err());
+        assert!(FieldSchema::parse("name:str unique").is_err());
+        assert!(FieldSchema::parse("name:str ref=users").is_err());
+        assert!(FieldSchema::parse("owner:u64 on_delete=cascade").is_err());
+        assert!(FieldSchema::parse("owner:u64 ref=users on_delete=drop").is_err());
+        assert!(Schema::parse("name:str; age").is_err());
+    }
+
+    #[test]
+    fn items_are_checked() {
+        let schema = Schema::parse("name:str required max=5; age:u64 min=18; strict").unwrap();
+        assert!(schema.strict);
+        assert_eq!(schema.fields.len(), 2);
+
+        let ok = item(&[("name", "Bob"), ("__owner", "x")], &[("age", 20)]);
+        assert!(errors(&schema, &ok).is_empty());
+
+        let bad = item(&[("name", "Robert"), ("city", "Oslo")], &[("age", 10)]);
+        assert_eq!(
+            errors(&schema, &bad),
+            vec![
+                ("name".to_string(), "Must be at most 5".to_string()),
+                ("age".to_string(), "Must be at least 18".to_string()),
+                ("city".to_string(), "Unknown field".to_string()),
+            ]
+        );
+
+        let missing = item(&[], &[]);
+        assert_eq!(
+            errors(&schema, &missing),
+            vec![("name".to_string(), "Is required".to_string())]
+        );
+
+        let mistyped = item(&[("age", "20")], &[("name", 1)]);
+        assert_eq!(
+            errors(&schema, &mistyped),
+            vec![
+                ("name".to_string(), "Must be str".to_string()),
+                ("age".to_string(), "Must be u64".to_string()),
+            ]
+        );
+
+        let schema = Schema::parse("status:str enum=new|closed").unwrap();
+        assert_eq!(
+            errors(&schema, &item(&[("status", "open")], &[])),
+            vec![(
+                "status".to_string(),
+                "Must be one of new, closed".to_string()
+            )]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn items_are_validated_against_store() {
+        let path = std::env::temp_dir().join(format!("isabelle-schema-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(path.join("collection/users/1")).unwrap();
+        let user = item(&[("name", "Bob")], &[]);
+        std::fs::write(
+            path.join("collection/users/1/data.js"),
+            serde_json::to_string(&user).unwrap(),
+        )
+        .unwrap();
+
+        let mut store = StoreLocal::new();
+        store.connect(path.to_str().unwrap(), "").await.unwrap();
+
+        let mut internals = Item::new();
+        internals.strstrs.insert(
+            SCHEMAS.to_string(),
+            HashMap::from([(
+                "tasks".to_string(),
+                "title:str required; owner:u64 ref=users".to_string(),
+            )]),
+        );
+        store.set_internals(internals).await.unwrap();
+
+        let ok = item(&[("title", "Test")], &[("owner", 1)]);
+        assert!(validate_item(&mut store, "tasks", &ok)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let bad = item(&[], &[("owner", 2)]);
+        let errs = validate_item(&mut store, "tasks", &bad).await.unwrap();
+        assert_eq!(
+            errs.iter().map(|e| e.field.as_str()).collect::<Vec<&str>>(),
+            vec!["title", "owner"]
+        );
+
+        // Collections without schema accept anything
+        assert!(validate_item(&mut store, "notes", &bad)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
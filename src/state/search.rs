@@ -0,0 +1,222 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::query::lookup;
+use isabelle_dm::data_model::item::Item;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Internals entry declaring searchable fields of collections
+pub const SEARCH_FIELDS: &str = "search_fields";
+
+/// Get searchable fields of the collection declared in internals. Fields
+/// are listed with commas, names without dots refer to string fields
+/// (like "name" for "strs.name").
+pub fn search_fields(internals: &Item, collection: &str) -> Vec<String> {
+    let decls = internals.safe_strstr(SEARCH_FIELDS, &HashMap::new());
+    let list = match decls.get(collection) {
+        Some(list) => list,
+        None => return Vec::new(),
+    };
+
+    return list
+        .split(',')
+        .map(|field| field.trim())
+        .filter(|field| *field != "")
+        .map(|field| {
+            if field.contains('.') {
+                field.to_string()
+            } else {
+                "strs.".to_string() + field
+            }
+        })
+        .collect();
+}
+
+/// Split text into lowercase words
+pub fn tokenize(text: &str) -> Vec<String> {
+    return text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| *word != "")
+        .map(|word| word.to_lowercase())
+        .collect();
+}
+
+/// Get words of the item in searchable fields
+pub fn item_tokens(itm: &Item, fields: &Vec<String>) -> Vec<String> {
+    let doc = serde_json::to_value(itm).unwrap_or(Value::Null);
+    let mut tokens: Vec<String> = Vec::new();
+    for field in fields {
+        if let Some(Value::String(text)) = lookup(&doc, field) {
+            tokens.extend(tokenize(text));
+        }
+    }
+    return tokens;
+}
+
+/// Relevance of the item: number of query words occurring in its fields
+pub fn score(tokens: &Vec<String>, query: &Vec<String>) -> u64 {
+    return tokens.iter().filter(|token| query.contains(token)).count() as u64;
+}
+
+/// Sort scored items by relevance, best first and then by ID
+pub fn rank(scored: &mut Vec<(u64, u64)>) {
+    scored.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+}
+
+/// In-process inverted index over searchable fields of one collection
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+    /// Fields the index is built for
+    pub fields: Vec<String>,
+
+    /// Word to item IDs with number of occurrences
+    postings: HashMap<String, HashMap<u64, u64>>,
+
+    /// Words of every indexed item, needed to drop them on change
+    words: HashMap<u64, Vec<String>>,
+}
+
+impl SearchIndex {
+    pub fn new(fields: &Vec<String>) -> Self {
+        Self {
+            fields: fields.clone(),
+            postings: HashMap::new(),
+            words: HashMap::new(),
+        }
+    }
+
+    /// Add or replace the item
+    pub fn insert(&mut self, itm: &Item) {
+        self.remove(itm.id);
+
+        let tokens = item_tokens(itm, &self.fields);
+        for token in &tokens {
+            *self
+                .postings
+                .entry(token.clone())
+                .or_insert(HashMap::new())
+                .entry(itm.id)
+                .or_insert(0) += 1;
+        }
+        self.words.insert(itm.id, tokens);
+    }
+
+    /// Drop the item
+    pub fn remove(&mut self, id: u64) {
+        let tokens = match self.words.remove(&id) {
+            Some(tokens) => tokens,
+            None => return,
+        };
+
+        for token in tokens {
+            if let Some(ids) = self.postings.get_mut(&token) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// Find items having any of the query words. Returns pairs of item ID
+    /// and its relevance, best matches first.
+    pub fn find(&self, query: &Vec<String>) -> Vec<(u64, u64)> {
+        let mut scores: HashMap<u64, u64> = HashMap::new();
+        let mut seen: Vec<&String> = Vec::new();
+        for token in query {
+            if seen.contains(&token) {
+                continue;
+            }
+            seen.push(token);
+
+            if let Some(ids) = self.postings.get(token) {
+                for (id, cnt) in ids {
+                    *scores.entry(*id).or_insert(0) += cnt;
+                }
+            }
+        }
+
+        let mut scored: Vec<(u64, u64)> = scores.into_iter().collect();
+        rank(&mut scored);
+        return scored;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: u64, name: &str, note: &str) -> Item {
+        let mut itm = Item::new();
+        itm.id = id;
+        itm.strs.insert("name".to_string(), name.to_string());
+        itm.strs.insert("note".to_string(), note.to_string());
+        return itm;
+    }
+
+    #[test]
+    fn text_is_tokenized() {
+        assert_eq!(
+            tokenize("Hello, World! x-42  Ünïcode"),
+            vec!["hello", "world", "x", "42", "ünïcode"]
+        );
+        assert!(tokenize(" ,.; ").is_empty());
+    }
+
+    #[test]
+    fn fields_are_declared() {
+        let mut internals = Item::new();
+        internals.strstrs.insert(
+            SEARCH_FIELDS.to_string(),
+            HashMap::from([("users".to_string(), "name, u64s.age,".to_string())]),
+        );
+        assert_eq!(
+            search_fields(&internals, "users"),
+            vec!["strs.name", "u64s.age"]
+        );
+        assert!(search_fields(&internals, "tasks").is_empty());
+    }
+
+    #[test]
+    fn items_are_found() {
+        let fields = vec!["strs.name".to_string()];
+        let mut index = SearchIndex::new(&fields);
+        index.insert(&item(1, "Red apple", "green"));
+        index.insert(&item(2, "Green apple apple", ""));
+        index.insert(&item(3, "Pear", "apple"));
+
+        // Ranked by occurrences, unindexed fields are ignored
+        assert_eq!(index.find(&tokenize("apple")), vec![(2, 2), (1, 1)]);
+        assert_eq!(index.find(&tokenize("green red")), vec![(1, 1), (2, 1)]);
+        assert_eq!(index.find(&tokenize("apple apple")), vec![(2, 2), (1, 1)]);
+        assert!(index.find(&tokenize("plum")).is_empty());
+
+        // Replaced and removed items drop their old words
+        index.insert(&item(2, "Plum", ""));
+        assert_eq!(index.find(&tokenize("apple")), vec![(1, 1)]);
+        assert_eq!(index.find(&tokenize("plum")), vec![(2, 1)]);
+        index.remove(1);
+        assert!(index.find(&tokenize("apple red")).is_empty());
+    }
+}
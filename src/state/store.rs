@@ -21,33 +21,230 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+use crate::state::index::IndexSpec;
+use crate::state::query::Filter;
+use crate::state::search;
+use crate::state::store_local::StoreLocal;
+use crate::state::store_mongo::StoreMongo;
+use crate::state::store_sqlite::StoreSqlite;
 use async_trait::async_trait;
 use isabelle_dm::data_model::item::Item;
 use isabelle_dm::data_model::list_result::ListResult;
+use log::{error, info};
 use std::collections::HashMap;
+use std::fmt;
+
+/// Name of u64 field keeping item version, maintained by stores
+pub const ITEM_VERSION: &str = "__version";
+
+/// Get version of the item, zero if it doesn't exist or has no version
+pub fn item_version(itm: &Option<Item>) -> u64 {
+    match itm {
+        Some(itm) => *itm.u64s.get(ITEM_VERSION).unwrap_or(&0),
+        None => 0,
+    }
+}
+
+/// Set version of the item that replaces the old one
+pub fn bump_version(new_itm: &mut Item, old_itm: &Option<Item>) {
+    new_itm
+        .u64s
+        .insert(ITEM_VERSION.to_string(), item_version(old_itm) + 1);
+}
+
+/// Prefix of item fields maintained by the core, like version and deletion
+/// marks. Users can't set them through edits.
+pub const RESERVED_PREFIX: &str = "__";
+
+/// Remove reserved fields from the item given by user
+pub fn strip_reserved(itm: &mut Item) {
+    itm.strs.retain(|k, _v| !k.starts_with(RESERVED_PREFIX));
+    itm.bools.retain(|k, _v| !k.starts_with(RESERVED_PREFIX));
+    itm.u64s.retain(|k, _v| !k.starts_with(RESERVED_PREFIX));
+    itm.strstrs.retain(|k, _v| !k.starts_with(RESERVED_PREFIX));
+}
+
+/// Keep reserved fields of the old item that the replacing one lacks
+pub fn keep_reserved(new_itm: &mut Item, old_itm: &Option<Item>) {
+    let old = match old_itm {
+        Some(old) => old,
+        None => return,
+    };
+    for (k, v) in &old.strs {
+        if k.starts_with(RESERVED_PREFIX) && !new_itm.strs.contains_key(k) {
+            new_itm.strs.insert(k.clone(), v.clone());
+        }
+    }
+    for (k, v) in &old.bools {
+        if k.starts_with(RESERVED_PREFIX) && !new_itm.bools.contains_key(k) {
+            new_itm.bools.insert(k.clone(), *v);
+        }
+    }
+    for (k, v) in &old.u64s {
+        if k.starts_with(RESERVED_PREFIX) && !new_itm.u64s.contains_key(k) {
+            new_itm.u64s.insert(k.clone(), *v);
+        }
+    }
+    for (k, v) in &old.strstrs {
+        if k.starts_with(RESERVED_PREFIX) && !new_itm.strstrs.contains_key(k) {
+            new_itm.strstrs.insert(k.clone(), v.clone());
+        }
+    }
+}
+
+/// Number of items read from stores at once when going over whole
+/// collections
+pub const PAGE_SIZE: u64 = 500;
+
+/// Read items with IDs in the given range, deleted ones included
+pub async fn read_range(
+    store: &mut dyn Store,
+    collection: &str,
+    id_min: u64,
+    id_max: u64,
+    limit: u64,
+) -> StoreResult<ListResult> {
+    return store
+        .get_items(
+            collection,
+            id_min,
+            id_max,
+            "id",
+            &Filter::with_deleted(),
+            u64::MAX,
+            limit,
+        )
+        .await;
+}
+
+/// Read the page of items with IDs starting from given one, deleted ones
+/// included, sorted by ID. Empty page means there are no more items.
+pub async fn read_page(
+    store: &mut dyn Store,
+    collection: &str,
+    id_min: u64,
+) -> StoreResult<Vec<Item>> {
+    let lr = read_range(store, collection, id_min, u64::MAX, PAGE_SIZE).await?;
+    let mut itms: Vec<Item> = lr.map.into_values().collect();
+    itms.sort_by_key(|itm| itm.id);
+    return Ok(itms);
+}
+
+/// Get ID the page following given one starts from, none after the last
+/// page
+pub fn next_page(itms: &Vec<Item>) -> Option<u64> {
+    if (itms.len() as u64) < PAGE_SIZE {
+        return None;
+    }
+    return itms.last().and_then(|itm| itm.id.checked_add(1));
+}
+
+/// Error of the store operation
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreError {
+    /// Collection or item doesn't exist
+    NotFound(String),
+
+    /// Item was changed by someone else, holds its current version
+    Conflict(u64),
+
+    /// Database can't be reached
+    Unavailable(String),
+
+    /// Data can't be converted to or from its stored form
+    Serialization(String),
+
+    /// Item can't be removed, holds the referencing items
+    Referenced(String),
+
+    /// Request can't be executed as given, e.g. malformed filter
+    Invalid(String),
+
+    /// Item with the same unique key already exists
+    Duplicate(String),
+
+    /// Database is reachable, but failed the operation
+    Failed(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::NotFound(what) => write!(f, "Not found: {}", what),
+            StoreError::Conflict(version) => {
+                write!(f, "Item was changed by someone else (version {})", version)
+            }
+            StoreError::Unavailable(e) => write!(f, "Database unavailable: {}", e),
+            StoreError::Serialization(e) => write!(f, "Serialization error: {}", e),
+            StoreError::Referenced(by) => write!(f, "Referenced by {}", by),
+            StoreError::Invalid(e) => write!(f, "Invalid request: {}", e),
+            StoreError::Duplicate(e) => write!(f, "Duplicate key: {}", e),
+            StoreError::Failed(e) => write!(f, "Database operation failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Unavailable(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Serialization(e.to_string())
+    }
+}
+
+/// Result of the store operation
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Get value of the store operation, logging the error and falling back to
+/// the given value. For callers that can't report errors any further.
+pub fn unwrap_or_log<T>(res: StoreResult<T>, fallback: T) -> T {
+    match res {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Store error: {}", e);
+            fallback
+        }
+    }
+}
 
 /// Store implementation
 #[async_trait]
 pub trait Store {
     /// Connect the store to database
-    async fn connect(&mut self, addr: &str, altaddr: &str);
+    async fn connect(&mut self, addr: &str, altaddr: &str) -> StoreResult<()>;
 
     /// Disconnect the store
     #[allow(dead_code)]
     async fn disconnect(&mut self);
 
     /// Get all collections
-    async fn get_collections(&mut self) -> Vec<String>;
+    async fn get_collections(&mut self) -> StoreResult<Vec<String>>;
+
+    /// Check if the collection is known to the store
+    fn has_collection(&self, collection: &str) -> bool;
+
+    /// Create the collection unless it exists
+    async fn ensure_collection(&mut self, collection: &str) -> StoreResult<()>;
 
     /// Get all item IDs (can be exhausting)
-    async fn get_item_ids(&mut self, collection: &str) -> HashMap<u64, bool>;
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>>;
 
     /// Get all items (can be exhausting unless you provide filter)
-    async fn get_all_items(&mut self, collection: &str, sort_key: &str, filter: &str)
-        -> ListResult;
+    async fn get_all_items(
+        &mut self,
+        collection: &str,
+        sort_key: &str,
+        filter: &Filter,
+    ) -> StoreResult<ListResult>;
 
-    /// Get item by specific ID
-    async fn get_item(&mut self, collection: &str, id: u64) -> Option<Item>;
+    /// Get item by specific ID, None if there is no such item
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>>;
 
     /// Get items by given parameters. Use u64::MAX for IDs you don't know.
     async fn get_items(
@@ -56,16 +253,105 @@ pub trait Store {
         id_min: u64,
         id_max: u64,
         sort_key: &str,
-        filter: &str,
+        filter: &Filter,
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<ListResult>;
+
+    /// Find items having words of the query in given fields, best matches
+    /// first. Returns the requested page and total number of found items.
+    async fn search_items(
+        &mut self,
+        collection: &str,
+        query: &str,
+        fields: &Vec<String>,
+        filter: &Filter,
         skip: u64,
         limit: u64,
-    ) -> ListResult;
+    ) -> StoreResult<(Vec<Item>, u64)> {
+        let query = search::tokenize(query);
+        let mut lr = self.get_all_items(collection, "id", filter).await?;
+        let mut scored: Vec<(u64, u64)> = Vec::new();
+        for (id, itm) in &lr.map {
+            let score = search::score(&search::item_tokens(itm, fields), &query);
+            if score > 0 {
+                scored.push((*id, score));
+            }
+        }
+        search::rank(&mut scored);
 
-    /// Write the item to the database
-    async fn set_item(&mut self, collection: &str, itm: &Item, merge: bool);
+        let total_count = scored.len() as u64;
+        let itms = scored
+            .iter()
+            .skip(if skip == u64::MAX { 0 } else { skip as usize })
+            .take(limit.try_into().unwrap_or(usize::MAX))
+            .filter_map(|(id, _score)| lr.map.remove(id))
+            .collect();
+        return Ok((itms, total_count));
+    }
 
-    /// Read the item from the database
-    async fn del_item(&mut self, collection: &str, id: u64) -> bool;
+    /// Write the item to the database. Returns ID of the stored item, which
+    /// is allocated by the store for items with u64::MAX.
+    async fn set_item(&mut self, collection: &str, itm: &Item, merge: bool) -> StoreResult<u64>;
+
+    /// Write the item only if its stored version is the expected one (zero
+    /// for new items). Returns ID of the stored item and its new version, or
+    /// conflict with current version.
+    async fn set_item_versioned(
+        &mut self,
+        collection: &str,
+        itm: &Item,
+        merge: bool,
+        expected_version: u64,
+    ) -> StoreResult<(u64, u64)> {
+        let old_itm = if itm.id != u64::MAX {
+            self.get_item(collection, itm.id).await?
+        } else {
+            None
+        };
+        let current_version = item_version(&old_itm);
+        if current_version != expected_version {
+            return Err(StoreError::Conflict(current_version));
+        }
+
+        let id = self.set_item(collection, itm, merge).await?;
+        return Ok((id, current_version + 1));
+    }
+
+    /// Remove the item from the database
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<()>;
+
+    /// Write many items at once. Returns IDs of stored items in the same
+    /// order.
+    async fn set_items(
+        &mut self,
+        collection: &str,
+        itms: &Vec<Item>,
+        merge: bool,
+    ) -> StoreResult<Vec<u64>>;
+
+    /// Remove many items at once. Returns number of removed items.
+    async fn del_items(&mut self, collection: &str, ids: &Vec<u64>) -> StoreResult<u64>;
+
+    /// Start transaction: changes made until commit are applied all at once
+    /// or not at all.
+    async fn begin_transaction(&mut self) -> StoreResult<()>;
+
+    /// Apply changes made in transaction
+    async fn commit_transaction(&mut self) -> StoreResult<()>;
+
+    /// Drop changes made in transaction
+    async fn abort_transaction(&mut self);
+
+    /// Get secondary indexes existing in the database. Stores without
+    /// secondary indexes have none.
+    async fn get_indexes(&mut self, _collection: &str) -> StoreResult<Vec<IndexSpec>> {
+        return Ok(Vec::new());
+    }
+
+    /// Permanently remove items deleted before given UNIX timestamp.
+    /// Returns number of purged items.
+    async fn purge_deleted(&mut self, older_than: i64) -> StoreResult<u64>;
 
     /// Get credentials
     async fn get_credentials(&mut self) -> String;
@@ -74,11 +360,49 @@ pub trait Store {
     async fn get_pickle(&mut self) -> String;
 
     /// Read internal data (like internal settings not exposed to user)
-    async fn get_internals(&mut self) -> Item;
+    async fn get_internals(&mut self) -> StoreResult<Item>;
 
     /// Read settings item
-    async fn get_settings(&mut self) -> Item;
+    async fn get_settings(&mut self) -> StoreResult<Item>;
 
     /// Write settings item
-    async fn set_settings(&mut self, itm: Item);
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()>;
+
+    /// Write internal data
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()>;
+}
+
+/// Create store of given kind (local, sqlite or mongo) and connect it.
+/// Local and SQLite stores keep everything inside the data path, Mongo
+/// keeps settings and internals there.
+pub async fn open_store(
+    kind: &str,
+    db_url: &str,
+    db_name: &str,
+    data_path: &str,
+) -> StoreResult<Box<dyn Store>> {
+    let store: Box<dyn Store> = match kind {
+        "local" => {
+            info!("Database url {} name {} unused", db_url, db_name);
+            let mut store = Box::new(StoreLocal::new());
+            store.connect(data_path, "").await?;
+            store
+        }
+        "sqlite" => {
+            // SQLite database lives next to the rest of the data
+            let db_path = data_path.to_string() + "/" + db_name + ".sqlite";
+            let mut store = Box::new(StoreSqlite::new());
+            store.connect(&db_path, data_path).await?;
+            store
+        }
+        "mongo" => {
+            let mut mongo = StoreMongo::new();
+            mongo.database_name = db_name.to_string();
+            let mut store = Box::new(mongo);
+            store.connect(db_url, data_path).await?;
+            store
+        }
+        _ => return Err(StoreError::NotFound(format!("store {}", kind))),
+    };
+    return Ok(store);
 }
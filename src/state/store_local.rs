@@ -22,14 +22,46 @@
  * DEALINGS IN THE SOFTWARE.
  */
 use isabelle_dm::data_model::list_result::ListResult;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::state::store::Store;
+use crate::state::index::{declared_indexes, FieldIndex};
+use crate::state::query::*;
+use crate::state::search::{self, SearchIndex};
+use crate::state::store::*;
 use async_trait::async_trait;
+use chrono::Utc;
 use isabelle_dm::data_model::item::*;
-use log::{debug, error, trace};
-use std::collections::HashMap;
+use log::{debug, error, info, trace};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs;
+use std::io::Write;
+
+/// Number of journal entries after which the journal is compacted
+const JOURNAL_COMPACT_LIMIT: u64 = 256;
+
+/// Journal entry, recorded before the change is applied to item folders
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum JournalEntry {
+    /// Item is written with given contents
+    Set { item: Item },
+    /// Item is removed
+    Del { id: u64 },
+}
+
+/// Change made within transaction
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TransactionEntry {
+    /// Collection of the item
+    collection: String,
+
+    /// The change itself
+    #[serde(flatten)]
+    entry: JournalEntry,
+}
 
 /// Local storage implementation
 #[derive(Debug, Clone)]
@@ -40,11 +72,23 @@ pub struct StoreLocal {
     /// Collection hash map
     pub collections: HashMap<String, u64>,
 
-    /// All items
+    /// All items, the value is false for soft-deleted ones
     pub items: HashMap<u64, HashMap<u64, bool>>,
 
     /// Item counters
     pub items_count: HashMap<u64, u64>,
+
+    /// Number of entries in collection journals
+    pub journal_entries: HashMap<u64, u64>,
+
+    /// Changes of the running transaction
+    transaction: Option<Vec<TransactionEntry>>,
+
+    /// Search indexes of collections, built on first search
+    search_indexes: HashMap<String, SearchIndex>,
+
+    /// Equality indexes over declared index fields, built on first listing
+    field_indexes: HashMap<String, FieldIndex>,
 }
 
 unsafe impl Send for StoreLocal {}
@@ -56,86 +100,577 @@ impl StoreLocal {
             collections: HashMap::new(),
             items: HashMap::new(),
             items_count: HashMap::new(),
+            journal_entries: HashMap::new(),
+            transaction: None,
+            search_indexes: HashMap::new(),
+            field_indexes: HashMap::new(),
+        }
+    }
+
+    /// Path to collection folder
+    fn collection_path(&self, collection: &str) -> String {
+        return self.path.to_string() + "/collection/" + collection;
+    }
+
+    /// Write file atomically: the data is written to temporary file, flushed
+    /// to disk and then renamed over the target, so the readers see either
+    /// old or new contents.
+    fn write_atomic(path: &str, data: &[u8]) -> std::io::Result<()> {
+        let tmp_path = path.to_owned() + ".tmp";
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+
+        // Make the rename itself durable
+        if let Some(parent) = Path::new(path).parent() {
+            if let Ok(dir) = fs::File::open(parent) {
+                let _res = dir.sync_all();
+            }
+        }
+        return Ok(());
+    }
+
+    /// Read and parse item file
+    fn read_item_file(path: &str) -> StoreResult<Item> {
+        let text = std::fs::read_to_string(path)?;
+        return Ok(serde_json::from_str(&text)?);
+    }
+
+    /// Get index of the known collection
+    fn coll_id(&self, collection: &str) -> StoreResult<u64> {
+        return self
+            .collections
+            .get(collection)
+            .cloned()
+            .ok_or(StoreError::NotFound(format!("collection {}", collection)));
+    }
+
+    /// Move broken item folder away, so that it can be inspected later
+    fn quarantine(&self, collection: &str, name: &OsStr) {
+        let coll_path = PathBuf::from(self.collection_path(collection));
+        let quarantine_path = coll_path.join(".quarantine");
+        if let Err(e) = fs::create_dir_all(&quarantine_path) {
+            error!("Failed to create {}: {}", quarantine_path.display(), e);
+            return;
+        }
+
+        let mut target_name = name.to_os_string();
+        target_name.push("-".to_string() + &Utc::now().timestamp().to_string());
+        let target = quarantine_path.join(target_name);
+        let src = coll_path.join(name);
+        let name = name.to_string_lossy();
+        match fs::rename(&src, &target) {
+            Ok(_) => error!(
+                "{}: item {} is corrupt, moved to {}",
+                collection,
+                name,
+                target.display()
+            ),
+            Err(e) => error!("{}: failed to quarantine item {}: {}", collection, name, e),
+        }
+    }
+
+    /// Check all item folders of the collection and get IDs of sane items.
+    /// Leftovers of interrupted writes are removed, corrupt items and
+    /// folders with unreadable names are quarantined.
+    fn recover_collection(&self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
+        let mut ids: HashMap<u64, bool> = HashMap::new();
+        let coll_path = self.collection_path(collection);
+        let _res = fs::remove_file(coll_path.clone() + "/cnt.tmp");
+
+        let data_files = fs::read_dir(&coll_path)?;
+        for data_file in data_files {
+            let data_file = match data_file {
+                Ok(data_file) => data_file,
+                Err(e) => {
+                    error!("{}: failed to read folder entry: {}", collection, e);
+                    continue;
+                }
+            };
+            let data_file_idx = match data_file.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    error!(
+                        "{}: folder name {} isn't valid UTF-8",
+                        collection,
+                        name.to_string_lossy()
+                    );
+                    self.quarantine(collection, &name);
+                    continue;
+                }
+            };
+            let tmp_path = coll_path.clone() + "/" + &data_file_idx;
+            if data_file_idx.starts_with('.') || !Path::new(&tmp_path).is_dir() {
+                continue;
+            }
+
+            let id = match data_file_idx.parse::<u64>() {
+                Ok(id) => id,
+                Err(_e) => {
+                    error!("{}: unexpected folder {}", collection, &data_file_idx);
+                    continue;
+                }
+            };
+
+            let _res = fs::remove_file(tmp_path.clone() + "/data.js.tmp");
+            match Self::read_item_file(&(tmp_path + "/data.js")) {
+                Ok(itm) => {
+                    ids.insert(id, !itm.safe_bool("__deleted", false));
+                    trace!("{}: idx {}", collection, &data_file_idx);
+                }
+                Err(e) => {
+                    error!("{}: failed to load item {}: {}", collection, id, e);
+                    self.quarantine(collection, &data_file.file_name());
+                }
+            }
+        }
+
+        return Ok(ids);
+    }
+
+    /// Path to collection journal
+    fn journal_path(&self, collection: &str) -> String {
+        return self.collection_path(collection) + "/journal";
+    }
+
+    /// Append entries to collection journal and flush them to disk at once
+    fn journal_append(
+        &mut self,
+        collection: &str,
+        entries: &[JournalEntry],
+    ) -> std::io::Result<()> {
+        let mut line = String::new();
+        for entry in entries {
+            line += &serde_json::to_string(entry)?;
+            line.push('\n');
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.journal_path(collection))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+
+        let coll_id = self.collections[collection];
+        *self.journal_entries.entry(coll_id).or_insert(0) += entries.len() as u64;
+        return Ok(());
+    }
+
+    /// Read all complete entries of collection journal
+    fn journal_read(&self, collection: &str) -> Vec<JournalEntry> {
+        let mut entries: Vec<JournalEntry> = Vec::new();
+        let text = match fs::read_to_string(self.journal_path(collection)) {
+            Ok(text) => text,
+            Err(_e) => return entries,
+        };
+
+        for line in text.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    // Only the tail can be broken by interrupted append
+                    error!("{}: journal entry is broken, stopping: {}", collection, e);
+                    break;
+                }
+            }
+        }
+
+        return entries;
+    }
+
+    /// Apply all journal entries to item folders and truncate the journal
+    fn journal_compact(&mut self, collection: &str) {
+        let entries = self.journal_read(collection);
+        if !entries.is_empty() {
+            debug!(
+                "{}: replaying {} journal entries",
+                collection,
+                entries.len()
+            );
+        }
+
+        for entry in &entries {
+            let res = match entry {
+                JournalEntry::Set { item } => self.apply_set(collection, item),
+                JournalEntry::Del { id } => self.apply_del(collection, *id).map(|_| ()),
+            };
+            if let Err(e) = res {
+                error!(
+                    "{}: failed to replay journal, keeping it: {}",
+                    collection, e
+                );
+                return;
+            }
+        }
+
+        let journal_path = self.journal_path(collection);
+        if Path::new(&journal_path).exists() {
+            if let Err(e) = Self::write_atomic(&journal_path, b"") {
+                error!("{}: failed to truncate journal: {}", collection, e);
+                return;
+            }
+        }
+
+        let coll_id = self.collections[collection];
+        self.journal_entries.insert(coll_id, 0);
+    }
+
+    /// Compact journal if it grew too big
+    fn journal_maybe_compact(&mut self, collection: &str) {
+        let coll_id = self.collections[collection];
+        if *self.journal_entries.get(&coll_id).unwrap_or(&0) >= JOURNAL_COMPACT_LIMIT {
+            self.journal_compact(collection);
         }
     }
+
+    /// Write item to its folder and update the counters
+    fn apply_set(&mut self, collection: &str, new_itm: &Item) -> std::io::Result<()> {
+        let tmp_path =
+            self.path.to_string() + "/collection/" + collection + "/" + &new_itm.id.to_string();
+
+        let _dir_create_err = std::fs::create_dir(&tmp_path);
+
+        let tmp_data_path = tmp_path.clone() + "/data.js";
+        let s = serde_json::to_string(&new_itm)?;
+        Self::write_atomic(&tmp_data_path, s.as_bytes())?;
+
+        let coll_id = self.collections[collection];
+        if self.items.contains_key(&coll_id) {
+            let coll = self.items.get_mut(&coll_id).unwrap();
+            coll.insert(new_itm.id, !new_itm.safe_bool("__deleted", false));
+            if self.items_count.contains_key(&coll_id) {
+                let cnt = self.items_count.get_mut(&coll_id).unwrap();
+                if new_itm.id > *cnt {
+                    *cnt = new_itm.id;
+                    let _res = Self::write_atomic(
+                        &(self.collection_path(collection) + "/cnt"),
+                        (new_itm.id + 1).to_string().as_bytes(),
+                    );
+                }
+            } else {
+                self.items_count.insert(coll_id, new_itm.id + 1);
+                let _res = Self::write_atomic(
+                    &(self.collection_path(collection) + "/cnt"),
+                    (new_itm.id + 1).to_string().as_bytes(),
+                );
+            }
+        }
+
+        if let Some(index) = self.search_indexes.get_mut(collection) {
+            index.insert(new_itm);
+        }
+        if let Some(index) = self.field_indexes.get_mut(collection) {
+            index.insert(new_itm);
+        }
+
+        return Ok(());
+    }
+
+    /// Path to file keeping committed transaction until it is applied
+    fn transaction_path(&self) -> String {
+        return self.path.clone() + "/transaction.js";
+    }
+
+    /// Get the latest change of the item made in running transaction
+    fn pending_entry(&self, collection: &str, id: u64) -> Option<&JournalEntry> {
+        let entries = self.transaction.as_ref()?;
+        for tx_entry in entries.iter().rev() {
+            if tx_entry.collection != collection {
+                continue;
+            }
+            match &tx_entry.entry {
+                JournalEntry::Set { item } if item.id == id => return Some(&tx_entry.entry),
+                JournalEntry::Del { id: del_id } if *del_id == id => return Some(&tx_entry.entry),
+                _ => {}
+            }
+        }
+        return None;
+    }
+
+    /// Get items changed in running transaction with their latest state:
+    /// alive flag for written ones and none for removed ones
+    fn pending_items(&self, collection: &str) -> HashMap<u64, Option<bool>> {
+        let mut pending: HashMap<u64, Option<bool>> = HashMap::new();
+        for tx_entry in self.transaction.iter().flatten() {
+            if tx_entry.collection != collection {
+                continue;
+            }
+            match &tx_entry.entry {
+                JournalEntry::Set { item } => {
+                    pending.insert(item.id, Some(!item.safe_bool("__deleted", false)))
+                }
+                JournalEntry::Del { id } => pending.insert(*id, None),
+            };
+        }
+        return pending;
+    }
+
+    /// Get the biggest item ID created in running transaction
+    fn pending_max_id(&self, collection: &str) -> u64 {
+        let mut max_id = 0;
+        for tx_entry in self.transaction.iter().flatten() {
+            if let JournalEntry::Set { item } = &tx_entry.entry {
+                if tx_entry.collection == collection {
+                    max_id = std::cmp::max(max_id, item.id);
+                }
+            }
+        }
+        return max_id;
+    }
+
+    /// Apply changes of committed transaction and forget it. On failure
+    /// the transaction file is kept, so it is applied again on start.
+    fn transaction_apply(&mut self, entries: &Vec<TransactionEntry>) -> StoreResult<()> {
+        for tx_entry in entries {
+            if !self.collections.contains_key(&tx_entry.collection) {
+                return Err(StoreError::NotFound(format!(
+                    "collection {}",
+                    tx_entry.collection
+                )));
+            }
+            self.journal_append(&tx_entry.collection, &[tx_entry.entry.clone()])?;
+            match &tx_entry.entry {
+                JournalEntry::Set { item } => self.apply_set(&tx_entry.collection, item)?,
+                JournalEntry::Del { id } => {
+                    self.apply_del(&tx_entry.collection, *id)?;
+                }
+            };
+        }
+
+        if let Err(e) = fs::remove_file(self.transaction_path()) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        return Ok(());
+    }
+
+    /// Apply committed transaction that wasn't applied completely, if any
+    fn transaction_replay(&mut self) -> StoreResult<()> {
+        let text = match fs::read_to_string(self.transaction_path()) {
+            Ok(text) => text,
+            Err(_e) => return Ok(()),
+        };
+        let entries = serde_json::from_str::<Vec<TransactionEntry>>(&text)?;
+        info!(
+            "Applying {} changes of committed transaction",
+            entries.len()
+        );
+        return self.transaction_apply(&entries);
+    }
+
+    /// Build the item as it will be stored: merged with the old one and with
+    /// new version
+    async fn prepare_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+    ) -> StoreResult<Item> {
+        let mut itm = exp_itm.clone();
+        if itm.bools.contains_key("__security_preserve") {
+            itm.bools.remove("__security_preserve");
+        }
+
+        let old_itm = self.get_item(collection, itm.id).await?;
+        let mut new_itm = itm.clone();
+        if !old_itm.is_none() && merge {
+            new_itm = old_itm.as_ref().unwrap().clone();
+            new_itm.merge(&itm);
+        }
+        bump_version(&mut new_itm, &old_itm);
+        return Ok(new_itm);
+    }
+
+    /// Move item folder to collection trash. Returns false if there was no
+    /// such item.
+    fn apply_del(&mut self, collection: &str, id: u64) -> std::io::Result<bool> {
+        let tmp_path = self.collection_path(collection) + "/" + &id.to_string();
+        let path = Path::new(&tmp_path);
+        if path.exists() {
+            let trash_path = self.collection_path(collection) + "/.trash";
+            let target = trash_path.clone()
+                + "/"
+                + &id.to_string()
+                + "-"
+                + &Utc::now().timestamp().to_string();
+            fs::create_dir_all(&trash_path)?;
+            fs::rename(&tmp_path, &target)?;
+        }
+        if let Some(index) = self.search_indexes.get_mut(collection) {
+            index.remove(id);
+        }
+        if let Some(index) = self.field_indexes.get_mut(collection) {
+            index.remove(id);
+        }
+        let coll_id = self.collections[collection];
+        if self.items.contains_key(&coll_id) {
+            let coll = self.items.get_mut(&coll_id).unwrap();
+            if coll.contains_key(&id) {
+                coll.remove(&id);
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    /// Build search index of the collection unless it exists for the fields
+    fn ensure_search_index(&mut self, collection: &str, fields: &Vec<String>) -> StoreResult<()> {
+        if let Some(index) = self.search_indexes.get(collection) {
+            if index.fields == *fields {
+                return Ok(());
+            }
+        }
+
+        let coll_id = self.coll_id(collection)?;
+        let mut index = SearchIndex::new(fields);
+        for id in self.items[&coll_id].keys() {
+            let path = self.collection_path(collection) + "/" + &id.to_string() + "/data.js";
+            index.insert(&Self::read_item_file(&path)?);
+        }
+        debug!("{}: built search index over {:?}", collection, fields);
+        self.search_indexes.insert(collection.to_string(), index);
+        return Ok(());
+    }
+
+    /// Build equality index of the collection over fields of its declared
+    /// indexes unless it is built already
+    async fn ensure_field_index(&mut self, collection: &str) -> StoreResult<()> {
+        if self.field_indexes.contains_key(collection) {
+            return Ok(());
+        }
+
+        let internals = self.get_internals().await?;
+        let mut fields: Vec<String> = Vec::new();
+        for spec in declared_indexes(&internals, collection).unwrap_or(Vec::new()) {
+            for (field, _order) in spec.fields {
+                if !fields.contains(&field) {
+                    fields.push(field);
+                }
+            }
+        }
+
+        let coll_id = self.coll_id(collection)?;
+        let mut index = FieldIndex::new(&fields);
+        if !fields.is_empty() {
+            for id in self.items[&coll_id].keys() {
+                let path = self.collection_path(collection) + "/" + &id.to_string() + "/data.js";
+                index.insert(&Self::read_item_file(&path)?);
+            }
+            debug!("{}: built field index over {:?}", collection, fields);
+        }
+        self.field_indexes.insert(collection.to_string(), index);
+        return Ok(());
+    }
 }
 
 #[async_trait]
 impl Store for StoreLocal {
-    async fn connect(&mut self, url: &str, _alturl: &str) {
+    async fn connect(&mut self, url: &str, _alturl: &str) -> StoreResult<()> {
         self.path = url.to_string();
-        let collections = fs::read_dir(self.path.to_string() + "/collection").unwrap();
+        let collections = fs::read_dir(self.path.to_string() + "/collection")?;
         for coll in collections {
-            let idx = coll.as_ref().unwrap().file_name().into_string().unwrap();
-            let new_col: HashMap<u64, bool> = HashMap::new();
+            let idx = match coll?.file_name().into_string() {
+                Ok(idx) => idx,
+                Err(_name) => continue,
+            };
+            if !Path::new(&self.collection_path(&idx)).is_dir() {
+                continue;
+            }
             let coll_index = self.items.len().try_into().unwrap();
-            self.items.insert(coll_index, new_col);
             self.collections.insert(idx.clone(), coll_index);
             trace!("New collection {}", idx.clone());
 
-            let cnt_str =
-                std::fs::read_to_string(self.path.clone() + "/collection/" + &idx + "/cnt");
-            if let Err(_e) = cnt_str {
-                error!("Failed to read counter");
-                continue;
-            }
+            // Find sane items, moving broken ones away
+            let ids = self.recover_collection(&idx)?;
+            let max_id = ids.keys().max().cloned().unwrap_or(0);
+            self.items.insert(coll_index, ids);
 
-            let parsed = cnt_str.as_ref().unwrap().trim().parse::<u64>();
-            if let Err(_e) = parsed {
-                error!("Failed to parse counter {}", cnt_str.as_ref().unwrap());
-                continue;
-            }
+            let cnt_str = std::fs::read_to_string(self.collection_path(&idx) + "/cnt");
+            let parsed = match cnt_str {
+                Ok(s) => match s.trim().parse::<u64>() {
+                    Ok(cnt) => cnt,
+                    Err(_e) => {
+                        error!("Failed to parse counter {}, restoring it", s);
+                        max_id + 1
+                    }
+                },
+                Err(_e) => {
+                    error!("Failed to read counter, restoring it");
+                    max_id + 1
+                }
+            };
 
             self.items_count
-                .insert(self.collections[&idx], *parsed.as_ref().unwrap());
-            trace!(" - index: {}", self.collections[&idx]);
-            trace!(" - counter: {}", parsed.as_ref().unwrap());
-
-            let data_files = fs::read_dir(self.path.to_string() + "/collection/" + &idx).unwrap();
-            for data_file in data_files {
-                let data_file_idx = data_file
-                    .as_ref()
-                    .unwrap()
-                    .file_name()
-                    .into_string()
-                    .unwrap();
-                let tmp_path = self.path.to_string() + "/collection/" + &idx + "/" + &data_file_idx;
-                if Path::new(&tmp_path).is_dir() {
-                    let m = self.items.get_mut(&coll_index).unwrap();
-                    (*m).insert(data_file_idx.parse::<u64>().unwrap(), true);
-                    trace!("{}: idx {}", &idx, &data_file_idx);
-                }
-            }
+                .insert(coll_index, std::cmp::max(parsed, max_id));
+            trace!(" - index: {}", coll_index);
+            trace!(" - counter: {}", self.items_count[&coll_index]);
+
+            // Bring item folders up to date with the journal
+            self.journal_compact(&idx);
         }
+
+        // Finish transaction committed before crash
+        if let Err(e) = self.transaction_replay() {
+            error!("Couldn't apply committed transaction: {}", e);
+        }
+
+        return Ok(());
     }
 
     async fn disconnect(&mut self) {}
 
-    async fn get_collections(&mut self) -> Vec<String> {
+    async fn get_collections(&mut self) -> StoreResult<Vec<String>> {
         let mut lst: Vec<String> = Vec::new();
 
         for coll in &self.collections {
             lst.push(coll.0.clone());
         }
 
-        return lst;
+        return Ok(lst);
+    }
+
+    fn has_collection(&self, collection: &str) -> bool {
+        return self.collections.contains_key(collection);
+    }
+
+    async fn ensure_collection(&mut self, collection: &str) -> StoreResult<()> {
+        if self.collections.contains_key(collection) {
+            return Ok(());
+        }
+
+        fs::create_dir_all(self.collection_path(collection))?;
+
+        let coll_index = self.items.len().try_into().unwrap();
+        self.collections.insert(collection.to_string(), coll_index);
+        self.items.insert(coll_index, HashMap::new());
+        self.items_count.insert(coll_index, 0);
+        trace!("New collection {}", collection);
+        return Ok(());
     }
 
-    async fn get_item_ids(&mut self, collection: &str) -> HashMap<u64, bool> {
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
         if !self.collections.contains_key(collection) {
-            return HashMap::new();
+            return Ok(HashMap::new());
         }
 
         let coll_id = self.collections[collection];
-        return self.items[&coll_id].clone();
+        return Ok(self.items[&coll_id].clone());
     }
 
     async fn get_all_items(
         &mut self,
         collection: &str,
         sort_key: &str,
-        filter: &str,
-    ) -> ListResult {
+        filter: &Filter,
+    ) -> StoreResult<ListResult> {
         return self
             .get_items(
                 collection,
@@ -149,7 +684,14 @@ impl Store for StoreLocal {
             .await;
     }
 
-    async fn get_item(&mut self, collection: &str, id: u64) -> Option<Item> {
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        // Running transaction sees its own changes
+        match self.pending_entry(collection, id) {
+            Some(JournalEntry::Set { item }) => return Ok(Some(item.clone())),
+            Some(JournalEntry::Del { .. }) => return Ok(None),
+            None => {}
+        }
+
         let tmp_path = self.path.to_string()
             + "/collection/"
             + collection
@@ -157,11 +699,9 @@ impl Store for StoreLocal {
             + &id.to_string()
             + "/data.js";
         if Path::new(&tmp_path).is_file() {
-            let text = std::fs::read_to_string(tmp_path).unwrap();
-            let itm: Item = serde_json::from_str(&text).unwrap();
-            return Some(itm);
+            return Ok(Some(Self::read_item_file(&tmp_path)?));
         }
-        return None;
+        return Ok(None);
     }
 
     async fn get_items(
@@ -169,23 +709,31 @@ impl Store for StoreLocal {
         collection: &str,
         id_min: u64,
         id_max: u64,
-        _sort_key: &str,
-        _filter: &str,
+        sort_key: &str,
+        filter: &Filter,
         skip: u64,
         limit: u64,
-    ) -> ListResult {
+    ) -> StoreResult<ListResult> {
         let mut lr = ListResult {
             map: HashMap::new(),
             total_count: 0,
         };
-        let itms = self
-            .items
-            .get_mut(&self.collections[collection])
-            .unwrap()
-            .clone();
+        if !self.collections.contains_key(collection) {
+            return Ok(lr);
+        }
+
+        let mut itms = self.items[&self.collections[collection]].clone();
+
+        // Running transaction sees its own changes
+        let pending = self.pending_items(collection);
+        for (id, alive) in &pending {
+            match alive {
+                Some(alive) => itms.insert(*id, *alive),
+                None => itms.remove(id),
+            };
+        }
         let mut eff_id_min = id_min;
         let eff_id_max = id_max;
-        let mut count = 0;
         let mut eff_skip = skip;
 
         if eff_skip == u64::MAX {
@@ -197,100 +745,346 @@ impl Store for StoreLocal {
         }
 
         debug!(
-            "Getting {} in range {} - {} limit {}",
-            &collection, eff_id_min, eff_id_max, limit
+            "Getting {} in range {} - {} skip {} limit {} sort key {} filter {}",
+            &collection, eff_id_min, eff_id_max, eff_skip, limit, sort_key, filter
         );
-        for itm in &itms {
-            if itm.0 >= &eff_id_min && itm.0 <= &eff_id_max {
-                let new_item = self.get_item(collection, *itm.0).await;
-                if !new_item.is_none() {
-                    if count >= eff_skip {
-                        lr.map.insert(*itm.0, new_item.unwrap());
-                    }
-                    count = count + 1;
-                    if count >= eff_skip && (count - eff_skip) >= limit {
-                        break;
-                    }
+
+        // Soft-deleted items are shown only if the filter asks for them
+        let hide_deleted = !filter.mentions(DELETED_FIELD);
+        let mut ids: Vec<u64> = itms
+            .iter()
+            .filter(|(id, alive)| {
+                **id >= eff_id_min && **id <= eff_id_max && (**alive || !hide_deleted)
+            })
+            .map(|(id, _alive)| *id)
+            .collect();
+        ids.sort();
+
+        // Without filter and sorting only the requested page is read.
+        if (filter.is_all() || filter.is_with_deleted()) && (sort_key == "" || sort_key == "id") {
+            lr.total_count = ids.len() as u64;
+            for id in ids.iter().skip(eff_skip as usize) {
+                if lr.map.len() as u64 >= limit {
+                    break;
+                }
+                if let Some(new_item) = self.get_item(collection, *id).await? {
+                    lr.map.insert(*id, new_item);
                 }
             }
+            debug!(
+                " - result: {} items, total {}",
+                lr.map.len(),
+                lr.total_count
+            );
+            return Ok(lr);
+        }
+
+        // Equalities on indexed fields narrow the items to read
+        self.ensure_field_index(collection).await?;
+        if let Some(found) = self.field_indexes[collection].find(&filter.equalities()) {
+            ids.retain(|id| found.contains(id) || pending.contains_key(id));
         }
-        debug!(" - result: {} items", count - eff_skip);
-        lr.total_count = itms.len() as u64;
 
-        return lr;
+        let mut matched: Vec<(Value, Item)> = Vec::new();
+        for id in &ids {
+            let new_item = self.get_item(collection, *id).await?;
+            if new_item.is_none() {
+                continue;
+            }
+            let new_item = new_item.unwrap();
+            let doc = serde_json::to_value(&new_item).unwrap_or(Value::Null);
+            if !filter.matches(&doc) {
+                continue;
+            }
+            matched.push((doc, new_item));
+        }
+
+        let fields = sort_fields(sort_key);
+        matched.sort_by(|a, b| compare_by_fields(&a.0, &b.0, &fields));
+        lr.total_count = matched.len() as u64;
+        for (_doc, new_item) in matched.into_iter().skip(eff_skip as usize) {
+            if lr.map.len() as u64 >= limit {
+                break;
+            }
+            lr.map.insert(new_item.id, new_item);
+        }
+
+        debug!(
+            " - result: {} items, total {}",
+            lr.map.len(),
+            lr.total_count
+        );
+        return Ok(lr);
     }
 
-    async fn set_item(&mut self, collection: &str, exp_itm: &Item, merge: bool) {
+    async fn search_items(
+        &mut self,
+        collection: &str,
+        query: &str,
+        fields: &Vec<String>,
+        filter: &Filter,
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<(Vec<Item>, u64)> {
+        if !self.collections.contains_key(collection) {
+            return Ok((Vec::new(), 0));
+        }
+        self.ensure_search_index(collection, fields)?;
+
+        let found = self.search_indexes[collection].find(&search::tokenize(query));
+        let hide_deleted = !filter.mentions(DELETED_FIELD);
+        let coll_id = self.collections[collection];
+        let mut matched: Vec<Item> = Vec::new();
+        for (id, _score) in found {
+            if hide_deleted && !*self.items[&coll_id].get(&id).unwrap_or(&false) {
+                continue;
+            }
+            let itm = match self.get_item(collection, id).await? {
+                Some(itm) => itm,
+                None => continue,
+            };
+            let doc = serde_json::to_value(&itm).unwrap_or(Value::Null);
+            if filter.matches(&doc) {
+                matched.push(itm);
+            }
+        }
+
+        let total_count = matched.len() as u64;
+        let itms = matched
+            .into_iter()
+            .skip(if skip == u64::MAX { 0 } else { skip as usize })
+            .take(limit.try_into().unwrap_or(usize::MAX))
+            .collect();
+        return Ok((itms, total_count));
+    }
+
+    async fn set_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+    ) -> StoreResult<u64> {
         let mut itm = exp_itm.clone();
+        let coll_id = self.coll_id(collection)?;
 
-        if itm.bools.contains_key("__security_preserve") {
-            itm.bools.remove("__security_preserve");
+        if itm.id == u64::MAX && self.items.contains_key(&coll_id) {
+            itm.id = std::cmp::max(self.items_count[&coll_id], self.pending_max_id(collection)) + 1;
         }
 
-        if itm.id == u64::MAX {
-            let coll_id = self.collections[collection];
-            if self.items.contains_key(&coll_id) {
-                itm.id = self.items_count[&coll_id] + 1;
-            }
+        let new_itm = self.prepare_item(collection, &itm, merge).await?;
+        let entry = JournalEntry::Set {
+            item: new_itm.clone(),
+        };
+
+        // Within transaction the change is only remembered
+        if let Some(entries) = self.transaction.as_mut() {
+            entries.push(TransactionEntry {
+                collection: collection.to_string(),
+                entry: entry,
+            });
+            return Ok(new_itm.id);
         }
 
-        let old_itm = self.get_item(collection, itm.id).await;
-        let mut new_itm = itm.clone();
-        if !old_itm.is_none() && merge {
-            new_itm = old_itm.unwrap().clone();
-            new_itm.merge(&itm);
+        self.journal_append(collection, &[entry])?;
+        self.apply_set(collection, &new_itm)?;
+        self.journal_maybe_compact(collection);
+        return Ok(new_itm.id);
+    }
+
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<()> {
+        self.coll_id(collection)?;
+        if self.get_item(collection, id).await?.is_none() {
+            return Err(StoreError::NotFound(format!("{} item {}", collection, id)));
         }
-        let tmp_path =
-            self.path.to_string() + "/collection/" + collection + "/" + &new_itm.id.to_string();
 
-        let _dir_create_err = std::fs::create_dir(&tmp_path);
+        if let Some(entries) = self.transaction.as_mut() {
+            entries.push(TransactionEntry {
+                collection: collection.to_string(),
+                entry: JournalEntry::Del { id },
+            });
+            return Ok(());
+        }
 
-        let tmp_data_path = tmp_path.clone() + "/data.js";
-        let s = serde_json::to_string(&new_itm);
-        std::fs::write(tmp_data_path, s.unwrap()).expect("Couldn't write item");
+        self.journal_append(collection, &[JournalEntry::Del { id }])?;
+        self.apply_del(collection, id)?;
+        self.journal_maybe_compact(collection);
+        return Ok(());
+    }
 
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&new_itm.id) {
-                *(coll.get_mut(&new_itm.id).unwrap()) = true;
-            } else {
-                coll.insert(new_itm.id, true);
+    async fn set_items(
+        &mut self,
+        collection: &str,
+        itms: &Vec<Item>,
+        merge: bool,
+    ) -> StoreResult<Vec<u64>> {
+        if itms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if self.transaction.is_some() {
+            let mut ids: Vec<u64> = Vec::new();
+            for itm in itms {
+                ids.push(self.set_item(collection, itm, merge).await?);
             }
-            if self.items_count.contains_key(&coll_id) {
-                let cnt = self.items_count.get_mut(&coll_id).unwrap();
-                if new_itm.id > *cnt {
-                    *cnt = new_itm.id;
-                    let _res = std::fs::write(
-                        self.path.to_string() + "/collection/" + collection + "/cnt",
-                        (new_itm.id + 1).to_string(),
-                    );
+            return Ok(ids);
+        }
+
+        // New IDs go after both the counter and IDs given explicitly
+        let coll_id = self.coll_id(collection)?;
+        let mut next_id = itms
+            .iter()
+            .filter(|itm| itm.id != u64::MAX)
+            .map(|itm| itm.id)
+            .fold(self.items_count[&coll_id], std::cmp::max)
+            + 1;
+
+        let mut new_itms: Vec<Item> = Vec::new();
+        for exp_itm in itms {
+            let mut itm = exp_itm.clone();
+            if itm.id == u64::MAX {
+                itm.id = next_id;
+                next_id += 1;
+            }
+            new_itms.push(self.prepare_item(collection, &itm, merge).await?);
+        }
+
+        // Whole batch is journaled with a single flush. Items that fail to
+        // be written are restored from the journal on start.
+        let entries: Vec<JournalEntry> = new_itms
+            .iter()
+            .map(|itm| JournalEntry::Set { item: itm.clone() })
+            .collect();
+        self.journal_append(collection, &entries)?;
+
+        for new_itm in &new_itms {
+            self.apply_set(collection, new_itm)?;
+        }
+
+        self.journal_maybe_compact(collection);
+        return Ok(new_itms.iter().map(|itm| itm.id).collect());
+    }
+
+    async fn del_items(&mut self, collection: &str, ids: &Vec<u64>) -> StoreResult<u64> {
+        self.coll_id(collection)?;
+        if self.transaction.is_some() {
+            let mut cnt = 0;
+            for id in ids {
+                match self.del_item(collection, *id).await {
+                    Ok(()) => cnt += 1,
+                    Err(StoreError::NotFound(_)) => {}
+                    Err(e) => return Err(e),
                 }
-            } else {
-                self.items_count.insert(coll_id, new_itm.id + 1);
-                let _res = std::fs::write(
-                    self.path.to_string() + "/collection/" + collection + "/cnt",
-                    (new_itm.id + 1).to_string(),
-                );
             }
+            return Ok(cnt);
         }
+
+        let entries: Vec<JournalEntry> =
+            ids.iter().map(|id| JournalEntry::Del { id: *id }).collect();
+        self.journal_append(collection, &entries)?;
+
+        let mut cnt = 0;
+        for id in ids {
+            if self.apply_del(collection, *id)? {
+                cnt += 1;
+            }
+        }
+
+        self.journal_maybe_compact(collection);
+        return Ok(cnt);
     }
 
-    async fn del_item(&mut self, collection: &str, id: u64) -> bool {
-        let tmp_path = self.path.to_string() + "/" + collection + "/" + &id.to_string();
-        let path = Path::new(&tmp_path);
-        if path.exists() {
-            let _res = std::fs::remove_dir_all(tmp_path);
+    async fn begin_transaction(&mut self) -> StoreResult<()> {
+        if self.transaction.is_some() {
+            return Err(StoreError::Unavailable(
+                "Transaction is already running".to_string(),
+            ));
         }
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&id) {
-                coll.remove(&id);
-                return true;
+        // Changes of committed transaction that failed to apply would be
+        // lost once the next one is committed
+        self.transaction_replay()?;
+        self.transaction = Some(Vec::new());
+        return Ok(());
+    }
+
+    async fn commit_transaction(&mut self) -> StoreResult<()> {
+        let entries = match self.transaction.take() {
+            Some(entries) => entries,
+            None => {
+                return Err(StoreError::Unavailable(
+                    "No transaction is running".to_string(),
+                ))
+            }
+        };
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // Once the transaction file is written, the changes will be applied
+        // even if the process crashes halfway
+        let s = serde_json::to_string(&entries)?;
+        Self::write_atomic(&self.transaction_path(), s.as_bytes())?;
+
+        return self.transaction_apply(&entries);
+    }
+
+    async fn abort_transaction(&mut self) {
+        self.transaction = None;
+    }
+
+    async fn purge_deleted(&mut self, older_than: i64) -> StoreResult<u64> {
+        let mut cnt = 0;
+        let names: Vec<String> = self.collections.keys().cloned().collect();
+
+        for collection in names {
+            // Move soft-deleted items to trash, their trash is purged below
+            let mut purged: HashSet<u64> = HashSet::new();
+            let coll_id = self.collections[&collection];
+            let deleted: Vec<u64> = self.items[&coll_id]
+                .iter()
+                .filter(|(_id, alive)| !**alive)
+                .map(|(id, _alive)| *id)
+                .collect();
+            for id in deleted {
+                let itm = match self.get_item(&collection, id).await? {
+                    Some(itm) => itm,
+                    None => continue,
+                };
+                let deleted_at = *itm.u64s.get("__deleted_at").unwrap_or(&0);
+                if (deleted_at as i64) < older_than {
+                    self.del_item(&collection, id).await?;
+                    purged.insert(id);
+                }
+            }
+            cnt += purged.len() as u64;
+
+            let trash_path = self.collection_path(&collection) + "/.trash";
+            let entries = match fs::read_dir(&trash_path) {
+                Ok(entries) => entries,
+                Err(_e) => continue,
+            };
+
+            // Trash of items removed for good is kept until it's old enough
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let (id, ts) = match name.rsplit_once('-') {
+                    Some((id, ts)) => (
+                        id.parse::<u64>().unwrap_or(u64::MAX),
+                        ts.parse::<i64>().unwrap_or(i64::MAX),
+                    ),
+                    None => (u64::MAX, i64::MAX),
+                };
+                if ts >= older_than && !purged.contains(&id) {
+                    continue;
+                }
+
+                match fs::remove_dir_all(entry.path()) {
+                    Ok(_) => debug!("{}: purged {}", collection, name),
+                    Err(e) => error!("{}: failed to purge {}: {}", collection, name, e),
+                }
             }
         }
-        return false;
+
+        return Ok(cnt);
     }
 
     async fn get_credentials(&mut self) -> String {
@@ -301,33 +1095,39 @@ impl Store for StoreLocal {
         return self.path.clone() + "/token.pickle";
     }
 
-    async fn get_internals(&mut self) -> Item {
+    async fn get_internals(&mut self) -> StoreResult<Item> {
         let tmp_data_path = self.path.clone() + "/internals.js";
 
-        let read_data = std::fs::read_to_string(tmp_data_path);
+        let read_data = std::fs::read_to_string(&tmp_data_path);
         if let Err(_e) = read_data {
-            return Item::new();
+            return Ok(Item::new());
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        return Ok(serde_json::from_str(&text)?);
     }
 
-    async fn get_settings(&mut self) -> Item {
+    async fn get_settings(&mut self) -> StoreResult<Item> {
         let tmp_data_path = self.path.clone() + "/settings.js";
 
-        let read_data = std::fs::read_to_string(tmp_data_path);
+        let read_data = std::fs::read_to_string(&tmp_data_path);
         if let Err(_e) = read_data {
-            return Item::new();
+            return Ok(Item::new());
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        return Ok(serde_json::from_str(&text)?);
     }
 
-    async fn set_settings(&mut self, itm: Item) {
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()> {
         let tmp_data_path = self.path.clone() + "/settings.js";
-        let s = serde_json::to_string(&itm);
-        std::fs::write(tmp_data_path, s.unwrap()).expect("Couldn't write item");
+        let s = serde_json::to_string(&itm)?;
+        Self::write_atomic(&tmp_data_path, s.as_bytes())?;
+        return Ok(());
+    }
+
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()> {
+        let tmp_data_path = self.path.clone() + "/internals.js";
+        let s = serde_json::to_string(&itm)?;
+        Self::write_atomic(&tmp_data_path, s.as_bytes())?;
+        return Ok(());
     }
 }
@@ -21,22 +21,123 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-use bson::Document;
+use bson::{Bson, Document};
 use futures_util::TryStreamExt;
 use isabelle_dm::data_model::list_result::ListResult;
 extern crate serde_json;
 
-use crate::state::store::Store;
+use crate::state::index::{self, IndexSpec, INDEX_PREFIX};
+use crate::state::query::*;
+use crate::state::search;
+use crate::state::store::*;
 use async_trait::async_trait;
 use isabelle_dm::data_model::item::*;
-use log::{debug, info, trace};
-use serde_json::Value;
+use log::{debug, error, info, trace};
 
-use mongodb::{bson::doc, Client, Collection, IndexModel};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{ClientOptions, IndexOptions, ReplaceOneModel, ReturnDocument, WriteModel};
+use mongodb::{bson::doc, Client, ClientSession, Collection, IndexModel};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Run the action within current transaction session, if there is one
+macro_rules! in_session {
+    ($session:expr, $action:expr) => {
+        match $session.as_mut() {
+            Some(session) => $action.session(session).await,
+            None => $action.await,
+        }
+    };
+}
+
+/// Check that the database is reachable before the operation and note
+/// if it becomes unreachable during it
+macro_rules! tracked {
+    ($self:ident, $action:expr) => {{
+        $self.ensure_connected().await?;
+        let res = $action.await;
+        $self.track(res)
+    }};
+}
+
+/// How long to wait for the server before giving up on the request
+const SERVER_SELECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First delay between reconnection attempts, doubled after each failure
+const RETRY_DELAY_MIN: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Collection keeping ID counters of all other collections
+const COUNTERS_COLLECTION: &str = "__counters";
+
+/// Name of text index used for search
+const SEARCH_INDEX: &str = "search";
+
+/// Server error codes reported for unique index violations
+const DUPLICATE_KEY_CODES: [i32; 2] = [11000, 11001];
+
+/// Get server error code of rejected command or write, if any. Write
+/// concern failures are not counted, since the request itself was valid.
+fn rejection_code(kind: &ErrorKind) -> Option<i32> {
+    match kind {
+        ErrorKind::Write(WriteFailure::WriteError(we)) => Some(we.code),
+        ErrorKind::InsertMany(ime) => ime
+            .write_errors
+            .as_ref()
+            .and_then(|errs| errs.first())
+            .map(|we| we.code),
+        ErrorKind::Command(ce) => Some(ce.code),
+        _ => None,
+    }
+}
+
+impl From<mongodb::error::Error> for StoreError {
+    fn from(e: mongodb::error::Error) -> Self {
+        // Transient server states (e.g. primary step down) are retried
+        // after reconnection like network failures
+        if e.contains_label("TransientTransactionError") || e.contains_label("RetryableWriteError")
+        {
+            return StoreError::Unavailable(e.to_string());
+        }
+
+        match e.kind.as_ref() {
+            ErrorKind::BsonDeserialization(_) | ErrorKind::BsonSerialization(_) => {
+                StoreError::Serialization(e.to_string())
+            }
+            ErrorKind::Io(_)
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::DnsResolve { .. }
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::Shutdown => StoreError::Unavailable(e.to_string()),
+            ErrorKind::InvalidArgument { .. } => StoreError::Invalid(e.to_string()),
+            kind => match rejection_code(kind) {
+                Some(code) if DUPLICATE_KEY_CODES.contains(&code) => {
+                    StoreError::Duplicate(e.to_string())
+                }
+                Some(_) => StoreError::Invalid(e.to_string()),
+                None => StoreError::Failed(e.to_string()),
+            },
+        }
+    }
+}
+
+impl From<bson::ser::Error> for StoreError {
+    fn from(e: bson::ser::Error) -> Self {
+        StoreError::Serialization(e.to_string())
+    }
+}
+
+impl From<bson::de::Error> for StoreError {
+    fn from(e: bson::de::Error) -> Self {
+        StoreError::Serialization(e.to_string())
+    }
+}
 
 /// Mongo storage implementation
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct StoreMongo {
     /// URL to Mongo database
     pub path: String,
@@ -47,39 +148,66 @@ pub struct StoreMongo {
     /// Collection hash map
     pub collections: HashMap<String, u64>,
 
-    /// Items map
-    pub items: HashMap<u64, HashMap<u64, bool>>,
-
-    /// Item counters
-    pub items_count: HashMap<u64, u64>,
+    /// Collections that are known, but not set up in the database yet
+    pub pending_collections: Vec<String>,
 
     /// Actual Mongo client
     pub client: Option<mongodb::Client>,
 
     /// Database name
     pub database_name: String,
+
+    /// Session of the running transaction
+    pub session: Option<ClientSession>,
+
+    /// Database was reachable during the last request
+    pub healthy: bool,
+
+    /// Earliest time of the next reconnection attempt
+    pub next_retry: Option<Instant>,
+
+    /// Delay before the next reconnection attempt
+    pub retry_delay: Duration,
+
+    /// Fields of text indexes created for collections
+    pub search_indexes: HashMap<String, Vec<String>>,
 }
 
 unsafe impl Send for StoreMongo {}
 
 impl StoreMongo {
-    #[cfg(not(feature = "full_file_database"))]
     pub fn new() -> Self {
         Self {
             path: "".to_string(),
             local_path: "".to_string(),
             collections: HashMap::new(),
-            items: HashMap::new(),
-            items_count: HashMap::new(),
+            pending_collections: Vec::new(),
             client: None,
             database_name: "isabelle".to_string(),
+            session: None,
+            healthy: false,
+            next_retry: None,
+            retry_delay: RETRY_DELAY_MIN,
+            search_indexes: HashMap::new(),
         }
     }
 
     pub async fn do_conn(&mut self) -> bool {
         if self.client.is_none() {
-            let client = Client::with_uri_str(&self.path).await;
-            match client {
+            let mut options = match ClientOptions::parse(&self.path).await {
+                Ok(options) => options,
+                Err(e) => {
+                    error!("Bad database URL {}: {}", &self.path, e);
+                    return false;
+                }
+            };
+            // Fail fast while the server is down instead of blocking
+            // requests for the default 30 seconds
+            if options.server_selection_timeout.is_none() {
+                options.server_selection_timeout = Some(SERVER_SELECTION_TIMEOUT);
+            }
+
+            match Client::with_options(options) {
                 Ok(cl) => {
                     self.client = Some(cl);
                 }
@@ -93,273 +221,384 @@ impl StoreMongo {
         return true;
     }
 
-    pub async fn json_to_bson(&mut self, json_string: &str) -> Result<Document, bool> {
-        // Parse JSON string into serde_json::Value
-        let js_res = serde_json::from_str(json_string);
-        let js: Value;
-        match js_res {
-            Ok(tmp) => {
-                js = tmp;
+    /// Check that the server answers
+    async fn ping(&self) -> StoreResult<()> {
+        self.client()?
+            .database(&self.database_name)
+            .run_command(doc! { "ping": 1 })
+            .await?;
+        return Ok(());
+    }
+
+    /// Make sure the database is reachable. While it isn't, the connection
+    /// is retried with growing delays and requests fail right away.
+    async fn ensure_connected(&mut self) -> StoreResult<()> {
+        if self.healthy {
+            return Ok(());
+        }
+        if let Some(next_retry) = self.next_retry {
+            if Instant::now() < next_retry {
+                return Err(StoreError::Unavailable(
+                    "Database is unreachable".to_string(),
+                ));
             }
-            Err(_error) => {
-                return Err(false);
+        }
+
+        // Collections couldn't be set up while the database was down
+        let res = match self.ping().await {
+            Ok(()) => self.load_collections().await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => {
+                info!("Database {} is reachable", self.database_name);
+                self.healthy = true;
+                self.next_retry = None;
+                self.retry_delay = RETRY_DELAY_MIN;
+                return Ok(());
+            }
+            Err(e) => {
+                error!(
+                    "Database {} is unreachable, retrying in {} s: {}",
+                    self.database_name,
+                    self.retry_delay.as_secs(),
+                    e
+                );
+                self.next_retry = Some(Instant::now() + self.retry_delay);
+                self.retry_delay = std::cmp::min(self.retry_delay * 2, RETRY_DELAY_MAX);
+                return Err(e);
             }
         }
+    }
 
-        // Convert serde_json::Value into BSON Document
-        let bs_res = bson::ser::to_document(&js);
+    /// Note failed request, so that the connection is checked before the
+    /// next one
+    fn track<T>(&mut self, res: StoreResult<T>) -> StoreResult<T> {
+        if let Err(StoreError::Unavailable(e)) = &res {
+            if self.healthy {
+                error!("Database request failed: {}", e);
+            }
+            self.healthy = false;
+        }
+        return res;
+    }
 
-        match bs_res {
-            Ok(tmp) => {
-                return Ok(tmp);
+    /// Get Mongo client, if connected
+    fn client(&self) -> StoreResult<&Client> {
+        return self
+            .client
+            .as_ref()
+            .ok_or(StoreError::Unavailable("Not connected".to_string()));
+    }
+
+    /// Get Mongo collection by name
+    fn collection<T: Send + Sync>(&self, name: &str) -> StoreResult<Collection<T>> {
+        return Ok(self
+            .client()?
+            .database(&self.database_name)
+            .collection(name));
+    }
+
+    /// Get the biggest item ID in the collection
+    async fn max_id(&self, collection: &str) -> StoreResult<u64> {
+        let coll: Collection<Item> = self.collection(collection)?;
+        let res = coll.find_one(doc! {}).sort(doc! { "id": -1 }).await?;
+        return Ok(res.map(|itm| itm.id).unwrap_or(0));
+    }
+
+    /// Make sure the counter is not below given ID
+    async fn raise_counter(&self, collection: &str, id: u64) -> StoreResult<()> {
+        let counters: Collection<Document> = self.collection(COUNTERS_COLLECTION)?;
+        counters
+            .update_one(
+                doc! { "_id": collection },
+                doc! { "$max": { "seq": std::cmp::min(id, i64::MAX as u64) as i64 } },
+            )
+            .upsert(true)
+            .await?;
+        return Ok(());
+    }
+
+    /// Remember the collection, so that it is known even before it is set
+    /// up in the database
+    fn register_collection(&mut self, name: &str) {
+        if !self.collections.contains_key(name) {
+            let coll_idx = self.collections.len().try_into().unwrap();
+            self.collections.insert(name.to_string(), coll_idx);
+        }
+    }
+
+    /// Set up collections that are not in the database yet
+    async fn load_collections(&mut self) -> StoreResult<()> {
+        while let Some(name) = self.pending_collections.last().cloned() {
+            debug!("Create collection {}", &name);
+            self.load_collection(&name).await?;
+            self.pending_collections.pop();
+        }
+        return Ok(());
+    }
+
+    /// Create collection with index
+    async fn load_collection(&mut self, name: &str) -> StoreResult<()> {
+        let db = self.client()?.database(&self.database_name);
+        // Fails if the collection exists already, which is fine
+        let _res = db.create_collection(name).await;
+        let coll: Collection<Item> = db.collection(name);
+
+        // IDs must be unique even with several core instances. Older
+        // databases have non-unique index with the same name, replace it.
+        let options = IndexOptions::builder()
+            .unique(true)
+            .name("id_1".to_string())
+            .build();
+        let index: IndexModel = IndexModel::builder()
+            .keys(doc! { "id": 1 })
+            .options(options)
+            .build();
+        if coll.create_index(index.clone()).await.is_err() {
+            let _res = coll.drop_index("id_1").await;
+            if let Err(e) = coll.create_index(index).await {
+                // Most likely there are duplicates already, keep plain index
+                error!("Failed to create unique index for {}: {}", name, e);
+                let plain: IndexModel = IndexModel::builder().keys(doc! { "id": 1 }).build();
+                coll.create_index(plain).await?;
             }
-            Err(_error) => {
-                return Err(false);
+        }
+
+        self.register_collection(name);
+        self.sync_indexes(name).await?;
+
+        let fields = search::search_fields(&self.get_internals().await?, name);
+        if !fields.is_empty() {
+            if let Err(e) = self.ensure_search_index(name, &fields).await {
+                error!("Failed to create search index for {}: {}", name, e);
             }
         }
+
+        // Counter must never go below existing IDs
+        let max_id = self.max_id(name).await?;
+        return self.raise_counter(name, max_id).await;
     }
-}
 
-#[async_trait]
-impl Store for StoreMongo {
-    async fn connect(&mut self, url: &str, alturl: &str) {
-        // Preserve parameters
-        self.path = url.to_string();
-        self.local_path = alturl.to_string();
+    /// Create indexes declared in internals and drop declared indexes that
+    /// are not declared anymore. Broken declarations are skipped.
+    async fn sync_indexes(&mut self, name: &str) -> StoreResult<()> {
+        let specs = match index::declared_indexes(&self.get_internals().await?, name) {
+            Ok(specs) => specs,
+            Err(e) => {
+                error!("Broken index declaration for {}: {}", name, e);
+                return Ok(());
+            }
+        };
 
-        // Connect
-        let res = self.do_conn().await;
-        if res {
-            // If successful, create all collections
-            info!("Connected {} / {}!", url, self.database_name);
-            let internals = self.get_internals().await;
-            let collections = internals.safe_strstr("collections", &HashMap::new());
-            debug!("Collections: {}", collections.len());
-            let db = self.client.as_ref().unwrap().database(&self.database_name);
-            for coll_name in collections {
-                debug!("Create collection {}", &coll_name.1);
-                db.create_collection(&coll_name.1).await.unwrap();
-                let coll: Collection<Item> = db.collection(&coll_name.1);
-                let index: IndexModel = IndexModel::builder().keys(doc! { "id": 1 }).build();
-                let _result = coll.create_index(index).await;
-
-                let coll_idx = self.collections.len().try_into().unwrap();
-                self.collections.insert(coll_name.1.to_string(), coll_idx);
-
-                let mut map: HashMap<u64, bool> = HashMap::new();
-                let filter = doc! {}; // An empty filter matches all documents
-
-                // Find documents in the collection and fill hash map/counter
-                let mut cursor = coll.find(filter).await.unwrap();
-                let mut count = 0;
-                while let Some(doc) = cursor.try_next().await.unwrap() {
-                    map.insert(doc.id, true);
-                    count = std::cmp::max(count, doc.id);
-                }
+        let coll: Collection<Document> = self.collection(name)?;
+        let existing = coll.list_index_names().await?;
+        for spec in &specs {
+            if existing.contains(&spec.name) {
+                continue;
+            }
 
-                self.items.insert(coll_idx, map);
-                self.items_count.insert(coll_idx, count);
+            let mut keys = Document::new();
+            for (field, order) in &spec.fields {
+                keys.insert(field.clone(), *order);
+            }
+            let options = IndexOptions::builder()
+                .name(spec.name.clone())
+                .unique(spec.unique)
+                .build();
+            let index: IndexModel = IndexModel::builder().keys(keys).options(options).build();
+            match coll.create_index(index).await {
+                Ok(_) => info!("Created index {} for {}", spec.name, name),
+                // Most likely there are duplicates for unique index
+                Err(e) => error!("Failed to create index {} for {}: {}", spec.name, name, e),
             }
-        } else {
-            info!("Not connected");
         }
+
+        for idx_name in existing {
+            if !idx_name.starts_with(INDEX_PREFIX) || specs.iter().any(|s| s.name == idx_name) {
+                continue;
+            }
+            match coll.drop_index(idx_name.clone()).await {
+                Ok(_) => info!("Dropped index {} for {}", idx_name, name),
+                Err(e) => error!("Failed to drop index {} for {}: {}", idx_name, name, e),
+            }
+        }
+        return Ok(());
     }
 
-    async fn disconnect(&mut self) {}
+    /// Create text index over given fields unless it exists. There can be
+    /// only one text index per collection, so the old one is replaced.
+    async fn ensure_search_index(&mut self, name: &str, fields: &Vec<String>) -> StoreResult<()> {
+        if self.search_indexes.get(name) == Some(fields) {
+            return Ok(());
+        }
 
-    async fn get_collections(&mut self) -> Vec<String> {
+        let coll: Collection<Document> = self.collection(name)?;
+        let mut keys = Document::new();
+        for field in fields {
+            keys.insert(field.clone(), "text");
+        }
+        let options = IndexOptions::builder()
+            .name(SEARCH_INDEX.to_string())
+            .build();
+        let index: IndexModel = IndexModel::builder().keys(keys).options(options).build();
+        if coll.create_index(index.clone()).await.is_err() {
+            let _res = coll.drop_index(SEARCH_INDEX).await;
+            coll.create_index(index).await?;
+        }
+
+        self.search_indexes.insert(name.to_string(), fields.clone());
+        return Ok(());
+    }
+
+    /// Allocate new item ID atomically, so that concurrent writers never
+    /// get the same one
+    async fn next_id(&mut self, collection: &str) -> StoreResult<u64> {
+        return self.allocate_ids(collection, 1).await;
+    }
+
+    /// Allocate a range of item IDs atomically, returning the first one
+    async fn allocate_ids(&mut self, collection: &str, count: u64) -> StoreResult<u64> {
+        let counters: Collection<Document> = self.collection(COUNTERS_COLLECTION)?;
+        let res = counters
+            .find_one_and_update(
+                doc! { "_id": collection },
+                doc! { "$inc": { "seq": count as i64 } },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?;
+
+        match res {
+            Some(counter) => {
+                if let Ok(seq) = counter.get_i64("seq") {
+                    return Ok(seq as u64 - count + 1);
+                }
+                error!("Counter for {} is broken", collection);
+            }
+            None => error!("Counter for {} is missing", collection),
+        }
+
+        // Fall back to the biggest ID
+        return Ok(self.max_id(collection).await? + 1);
+    }
+
+    /// Get all collections from the database
+    async fn do_get_collections(&mut self) -> StoreResult<Vec<String>> {
         let colls = self
-            .client
-            .as_ref()
-            .unwrap()
+            .client()?
             .database(&self.database_name)
             .list_collection_names()
-            .await
-            .unwrap();
+            .await?;
         let mut lst: Vec<String> = Vec::new();
 
+        // Internal collections (counters, history, webhooks) aren't listed
         for coll in &colls {
+            if coll.starts_with("__") {
+                continue;
+            }
             lst.push(coll.clone());
         }
 
-        return lst;
+        return Ok(lst);
     }
 
-    async fn get_item_ids(&mut self, collection: &str) -> HashMap<u64, bool> {
+    /// Create the collection unless it is set up already
+    async fn do_ensure_collection(&mut self, collection: &str) -> StoreResult<()> {
         if !self.collections.contains_key(collection) {
-            return HashMap::new();
+            self.load_collection(collection).await?;
         }
-
-        let coll_id = self.collections[collection];
-        return self.items[&coll_id].clone();
+        return Ok(());
     }
 
-    async fn get_all_items(
-        &mut self,
-        collection: &str,
-        sort_key: &str,
-        filter: &str,
-    ) -> ListResult {
-        return self
-            .get_items(
-                collection,
-                u64::MAX,
-                u64::MAX,
-                sort_key,
-                filter,
-                u64::MAX,
-                u64::MAX,
-            )
-            .await;
+    /// Get IDs of all items of the collection
+    async fn do_get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
+        if !self.collections.contains_key(collection) {
+            return Ok(HashMap::new());
+        }
+
+        let mut map: HashMap<u64, bool> = HashMap::new();
+        let coll: Collection<Document> = self.collection(collection)?;
+        let mut cursor = coll.find(doc! {}).projection(doc! { "id": 1 }).await?;
+        while let Some(doc) = cursor.try_next().await? {
+            match doc.get("id") {
+                Some(Bson::Int64(id)) => map.insert(*id as u64, true),
+                Some(Bson::Int32(id)) => map.insert(*id as u64, true),
+                _ => continue,
+            };
+        }
+
+        return Ok(map);
     }
 
-    async fn get_item(&mut self, collection: &str, id: u64) -> Option<Item> {
-        let coll = self
-            .client
-            .as_ref()
-            .unwrap()
-            .database(&self.database_name)
-            .collection(collection);
+    /// Get item by ID
+    async fn do_get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        let coll: Collection<Item> = self.collection(collection)?;
         let filter = doc! {
             "id": id as i64,
         };
 
-        let result = coll.find_one(filter).await;
-
-        match result {
-            Ok(r) => {
-                if r.is_none() {
-                    return None;
-                }
-                return Some(r.unwrap());
-            }
-            Err(_e) => {}
-        };
-        return None;
+        return Ok(in_session!(self.session, coll.find_one(filter))?);
     }
 
-    async fn get_items(
+    /// Get items matching the filter
+    async fn do_get_items(
         &mut self,
         collection: &str,
         id_min: u64,
         id_max: u64,
         sort_key: &str,
-        filter: &str,
+        filter: &Filter,
         skip: u64,
         limit: u64,
-    ) -> ListResult {
+    ) -> StoreResult<ListResult> {
+        let filter = &filter.clone().hide_deleted();
         let mut lr = ListResult {
             map: HashMap::new(),
             total_count: 0,
         };
-        let itms = self
-            .items
-            .get_mut(&self.collections[collection])
-            .unwrap()
-            .clone();
-        let mut eff_id_min = id_min;
-        let eff_id_max = id_max;
-        let mut count = 0;
-        let mut eff_skip = skip;
-        let mut care_about_sort = false;
-        let eff_limit: i64;
-
-        if eff_skip == u64::MAX {
-            eff_skip = 0;
-        }
-
-        if eff_id_min == u64::MAX {
-            eff_id_min = 0;
-            if sort_key != "" {
-                care_about_sort = true;
-            }
-        }
-
-        if limit > (i64::MAX as u64) {
-            eff_limit = i64::MAX;
-        } else {
-            eff_limit = limit as i64;
-        }
+        let eff_id_min = if id_min == u64::MAX { 0 } else { id_min };
+        let eff_skip = if skip == u64::MAX { 0 } else { skip };
+        let eff_limit = std::cmp::min(limit, i64::MAX as u64) as i64;
 
         debug!(
-            "Getting {} in range {} - {} ({}-{}) skip {} limit {} sort key {} (care {}) filter {}",
-            &collection,
-            eff_id_min,
-            eff_id_max,
-            id_min,
-            id_max,
-            eff_skip,
-            eff_limit,
-            sort_key,
-            care_about_sort,
-            filter
+            "Getting {} in range {} - {} skip {} limit {} sort key {} filter {}",
+            &collection, eff_id_min, id_max, eff_skip, eff_limit, sort_key, filter
         );
-        if care_about_sort {
-            let coll: Collection<Item> = self
-                .client
-                .as_ref()
-                .unwrap()
-                .database(&self.database_name)
-                .collection(collection);
-
-            let json_bson: Document = if filter != "" {
-                debug!("Using real filter: {}", filter);
-                let bson_document = self.json_to_bson(filter).await;
-                match bson_document {
-                    Ok(d) => d,
-                    Err(_err) => {
-                        trace!("Using empty filter due to error");
-                        Document::new()
-                    }
+
+        let coll: Collection<Item> = self.collection(collection)?;
+        let json_bson: Document = filter.to_bson()?;
+        trace!("Using filter: {}", json_bson);
+
+        // Respect requested ID range as well
+        let json_bson = if id_min != u64::MAX || id_max != u64::MAX {
+            let range = doc! {
+                "id": {
+                    "$gte": std::cmp::min(eff_id_min, i64::MAX as u64) as i64,
+                    "$lte": std::cmp::min(id_max, i64::MAX as u64) as i64,
                 }
-            } else {
-                trace!("Using empty filter");
-                Document::new()
             };
-
-            let count = coll
-                .count_documents(json_bson.clone())
-                .skip(eff_skip)
-                .limit(eff_limit.try_into().unwrap())
-                .await;
-            lr.total_count = count.unwrap_or(0);
-
-            let mut cursor = coll.find(json_bson).skip(eff_skip).limit(eff_limit).await;
-            loop {
-                let result = cursor.as_mut().unwrap().try_next().await;
-                match result {
-                    Ok(r) => {
-                        let c = r.clone();
-                        if c.is_none() {
-                            break;
-                        }
-
-                        lr.map
-                            .insert(c.as_ref().unwrap().id, c.as_ref().unwrap().clone());
-                    }
-                    Err(_e) => {
-                        debug!("Error: {}", _e);
-                        break;
-                    }
-                };
-            }
+            doc! { "$and": [json_bson, range] }
         } else {
-            for itm in &itms {
-                if itm.0 >= &eff_id_min && itm.0 <= &eff_id_max {
-                    let new_item = self.get_item(collection, *itm.0).await;
-                    if !new_item.is_none() {
-                        if count >= eff_skip {
-                            lr.map.insert(*itm.0, new_item.unwrap());
-                        }
-                        count = count + 1;
-                        if count >= eff_skip && (count - eff_skip) >= eff_limit as u64 {
-                            break;
-                        }
-                    }
-                }
-            }
+            json_bson
+        };
+
+        lr.total_count = coll.count_documents(json_bson.clone()).await?;
 
-            lr.total_count = itms.len() as u64;
+        // Sort the same way as the other stores do
+        let mut sort = Document::new();
+        for (field, order) in sort_fields(sort_key) {
+            sort.insert(field, order);
+        }
+
+        let mut cursor = coll
+            .find(json_bson)
+            .sort(sort)
+            .skip(eff_skip)
+            .limit(eff_limit)
+            .await?;
+        while let Some(itm) = cursor.try_next().await? {
+            lr.map.insert(itm.id, itm);
         }
 
         debug!(
@@ -367,90 +606,535 @@ impl Store for StoreMongo {
             lr.map.len(),
             lr.total_count
         );
-        return lr;
+        return Ok(lr);
     }
 
-    async fn set_item(&mut self, collection: &str, exp_itm: &Item, merge: bool) {
+    /// Get all indexes of the collection
+    async fn do_get_indexes(&mut self, collection: &str) -> StoreResult<Vec<IndexSpec>> {
+        let coll: Collection<Document> = self.collection(collection)?;
+        let mut specs: Vec<IndexSpec> = Vec::new();
+        let mut cursor = coll.list_indexes().await?;
+        while let Some(model) = cursor.try_next().await? {
+            let options = model.options.unwrap_or_default();
+            let fields = model
+                .keys
+                .iter()
+                .map(|(field, order)| {
+                    let order = match order {
+                        Bson::Int32(v) if *v < 0 => -1,
+                        Bson::Int64(v) if *v < 0 => -1,
+                        Bson::Double(v) if *v < 0.0 => -1,
+                        _ => 1,
+                    };
+                    (field.clone(), order)
+                })
+                .collect();
+            specs.push(IndexSpec {
+                name: options.name.unwrap_or("".to_string()),
+                fields,
+                unique: options.unique.unwrap_or(false),
+                ttl: options.expire_after.map(|d| d.as_secs()),
+            });
+        }
+        return Ok(specs);
+    }
+
+    /// Find items with text index, best matches first
+    async fn do_search_items(
+        &mut self,
+        collection: &str,
+        query: &str,
+        fields: &Vec<String>,
+        filter: &Filter,
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<(Vec<Item>, u64)> {
+        let mut itms: Vec<Item> = Vec::new();
+        if fields.is_empty() {
+            return Ok((itms, 0));
+        }
+        self.ensure_search_index(collection, fields).await?;
+
+        let eff_skip = if skip == u64::MAX { 0 } else { skip };
+        let eff_limit = std::cmp::min(limit, i64::MAX as u64) as i64;
+        debug!(
+            "Searching {} for \"{}\" skip {} limit {} filter {}",
+            &collection, query, eff_skip, eff_limit, filter
+        );
+
+        let coll: Collection<Document> = self.collection(collection)?;
+        let filter_bson = filter.clone().hide_deleted().to_bson()?;
+        let json_bson = doc! {
+            "$and": [
+                filter_bson,
+                { "$text": { "$search": query } },
+            ]
+        };
+        let total_count = coll.count_documents(json_bson.clone()).await?;
+
+        let mut cursor = coll
+            .find(json_bson)
+            .projection(doc! { "__score": { "$meta": "textScore" } })
+            .sort(doc! { "__score": { "$meta": "textScore" }, "id": 1 })
+            .skip(eff_skip)
+            .limit(eff_limit)
+            .await?;
+        while let Some(mut found) = cursor.try_next().await? {
+            found.remove("__score");
+            itms.push(bson::from_document::<Item>(found)?);
+        }
+
+        debug!(" - result: {} items, total {}", itms.len(), total_count);
+        return Ok((itms, total_count));
+    }
+
+    /// Write the item
+    async fn do_set_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+    ) -> StoreResult<u64> {
         let mut itm = exp_itm.clone();
         if itm.bools.contains_key("__security_preserve") {
             itm.bools.remove("__security_preserve");
         }
 
         if itm.id == u64::MAX {
-            let coll_id = self.collections[collection];
-            if self.items.contains_key(&coll_id) {
-                itm.id = self.items_count[&coll_id] + 1;
-            }
+            itm.id = self.next_id(collection).await?;
         }
 
-        let old_itm = if itm.id != u64::MAX {
-            self.get_item(collection, itm.id).await
-        } else {
-            None
-        };
+        let old_itm = self.get_item(collection, itm.id).await?;
         let mut new_itm = itm.clone();
         if !old_itm.as_ref().is_none() && merge {
             new_itm = old_itm.as_ref().unwrap().clone();
             new_itm.merge(&itm);
         }
+        bump_version(&mut new_itm, &old_itm);
 
-        let coll: Collection<Item> = self
-            .client
-            .as_ref()
-            .unwrap()
-            .database(&self.database_name)
-            .collection(collection);
+        let coll: Collection<Item> = self.collection(collection)?;
         let filter = doc! {
             "id": itm.id as i64,
         };
 
         if old_itm.as_ref().is_none() {
-            let _res = coll.insert_one(new_itm.clone()).await;
+            in_session!(self.session, coll.insert_one(new_itm.clone()))?;
+            // IDs given by the caller must not be handed out again
+            self.raise_counter(collection, new_itm.id).await?;
+        } else {
+            in_session!(self.session, coll.replace_one(filter, new_itm.clone()))?;
+        }
+
+        return Ok(new_itm.id);
+    }
+
+    /// Write the item if its stored version is the expected one
+    async fn do_set_item_versioned(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        expected_version: u64,
+    ) -> StoreResult<(u64, u64)> {
+        let old_itm = if exp_itm.id != u64::MAX {
+            self.get_item(collection, exp_itm.id).await?
+        } else {
+            None
+        };
+        let current_version = item_version(&old_itm);
+        if current_version != expected_version {
+            return Err(StoreError::Conflict(current_version));
+        }
+        if old_itm.is_none() {
+            // New items have nothing to race with
+            let id = self.do_set_item(collection, exp_itm, merge).await?;
+            return Ok((id, 1));
+        }
+
+        let mut itm = exp_itm.clone();
+        if itm.bools.contains_key("__security_preserve") {
+            itm.bools.remove("__security_preserve");
+        }
+
+        let mut new_itm = itm.clone();
+        if merge {
+            new_itm = old_itm.as_ref().unwrap().clone();
+            new_itm.merge(&itm);
+        }
+        bump_version(&mut new_itm, &old_itm);
+
+        // Check and write in one step, so that parallel writers can't
+        // sneak in between
+        let mut filter = doc! {
+            "id": itm.id as i64,
+        };
+        let version_field = "u64s.".to_owned() + ITEM_VERSION;
+        if expected_version == 0 {
+            filter.insert(version_field, doc! { "$exists": false });
         } else {
-            let _res = coll.replace_one(filter, new_itm.clone()).await;
+            filter.insert(version_field, expected_version as i64);
         }
 
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&new_itm.id) {
-                *(coll.get_mut(&new_itm.id).unwrap()) = true;
+        let coll: Collection<Item> = self.collection(collection)?;
+        let res = in_session!(self.session, coll.replace_one(filter, new_itm))?;
+        if res.matched_count == 1 {
+            return Ok((itm.id, expected_version + 1));
+        }
+
+        let stored = self.get_item(collection, itm.id).await?;
+        return Err(StoreError::Conflict(item_version(&stored)));
+    }
+
+    /// Remove the item
+    async fn do_del_item(&mut self, collection: &str, id: u64) -> StoreResult<()> {
+        let coll: Collection<Item> = self.collection(collection)?;
+        let filter = doc! {
+            "id": id as i64,
+        };
+
+        let res = in_session!(self.session, coll.delete_one(filter))?;
+        if res.deleted_count == 0 {
+            return Err(StoreError::NotFound(format!("{} item {}", collection, id)));
+        }
+        return Ok(());
+    }
+
+    /// Write many items at once
+    async fn do_set_items(
+        &mut self,
+        collection: &str,
+        itms: &Vec<Item>,
+        merge: bool,
+    ) -> StoreResult<Vec<u64>> {
+        if itms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let coll: Collection<Item> = self.collection(collection)?;
+
+        // Load items being replaced with a single query
+        let ids: Vec<i64> = itms
+            .iter()
+            .filter(|itm| itm.id != u64::MAX)
+            .map(|itm| itm.id as i64)
+            .collect();
+        let mut old_itms: HashMap<u64, Item> = HashMap::new();
+        if !ids.is_empty() {
+            // Old items are read in the same session to see changes made
+            // earlier in the transaction
+            let filter = doc! { "id": { "$in": ids } };
+            match self.session.as_mut() {
+                Some(session) => {
+                    let mut cursor = coll.find(filter).session(&mut *session).await?;
+                    let mut stream = cursor.stream(session);
+                    while let Some(itm) = stream.try_next().await? {
+                        old_itms.insert(itm.id, itm);
+                    }
+                }
+                None => {
+                    let mut cursor = coll.find(filter).await?;
+                    while let Some(itm) = cursor.try_next().await? {
+                        old_itms.insert(itm.id, itm);
+                    }
+                }
+            }
+        }
+
+        // Allocate all new IDs with a single counter update
+        let new_count = itms.iter().filter(|itm| itm.id == u64::MAX).count() as u64;
+        let mut next_id = 0;
+        if new_count > 0 {
+            next_id = self.allocate_ids(collection, new_count).await?;
+        }
+
+        let mut stored_ids: Vec<u64> = Vec::new();
+        let mut inserts: Vec<Item> = Vec::new();
+        let mut replaces: Vec<Item> = Vec::new();
+        for exp_itm in itms {
+            let mut itm = exp_itm.clone();
+            if itm.bools.contains_key("__security_preserve") {
+                itm.bools.remove("__security_preserve");
+            }
+            if itm.id == u64::MAX {
+                itm.id = next_id;
+                next_id += 1;
+            }
+
+            let old_itm = old_itms.get(&itm.id).cloned();
+            let mut new_itm = itm.clone();
+            if !old_itm.is_none() && merge {
+                new_itm = old_itm.as_ref().unwrap().clone();
+                new_itm.merge(&itm);
+            }
+            bump_version(&mut new_itm, &old_itm);
+            stored_ids.push(new_itm.id);
+
+            if old_itm.is_none() {
+                inserts.push(new_itm);
             } else {
-                coll.insert(new_itm.id, true);
+                replaces.push(new_itm);
+            }
+        }
+
+        if !inserts.is_empty() {
+            let max_id = inserts.iter().map(|itm| itm.id).max().unwrap_or(0);
+            in_session!(self.session, coll.insert_many(&inserts))?;
+            self.raise_counter(collection, max_id).await?;
+        }
+
+        if !replaces.is_empty() {
+            let mut models: Vec<WriteModel> = Vec::new();
+            for itm in &replaces {
+                models.push(WriteModel::ReplaceOne(
+                    ReplaceOneModel::builder()
+                        .namespace(coll.namespace())
+                        .filter(doc! { "id": itm.id as i64 })
+                        .replacement(bson::to_document(itm)?)
+                        .build(),
+                ));
             }
-            if self.items_count.contains_key(&coll_id) {
-                let cnt = self.items_count.get_mut(&coll_id).unwrap();
-                if new_itm.id > *cnt {
-                    *cnt = new_itm.id;
+
+            // Bulk write across the client needs MongoDB 8.0 and can't be
+            // a part of transaction
+            let mut bulk_done = false;
+            if self.session.is_none() {
+                match self.client()?.bulk_write(models).await {
+                    Ok(_) => bulk_done = true,
+                    Err(e) => debug!("Bulk write failed, replacing one by one: {}", e),
+                }
+            }
+            if !bulk_done {
+                for itm in &replaces {
+                    in_session!(
+                        self.session,
+                        coll.replace_one(doc! { "id": itm.id as i64 }, itm)
+                    )?;
                 }
-            } else {
-                self.items_count.insert(coll_id, new_itm.id + 1);
             }
         }
+
+        return Ok(stored_ids);
     }
 
-    async fn del_item(&mut self, collection: &str, id: u64) -> bool {
-        let coll: Collection<Item> = self
-            .client
-            .as_ref()
-            .unwrap()
-            .database(&self.database_name)
-            .collection(collection);
-        let filter = doc! {
-            "id": id as i64,
+    /// Remove many items at once
+    async fn do_del_items(&mut self, collection: &str, ids: &Vec<u64>) -> StoreResult<u64> {
+        let coll: Collection<Item> = self.collection(collection)?;
+        let ids: Vec<i64> = ids.iter().map(|id| *id as i64).collect();
+        let res = in_session!(
+            self.session,
+            coll.delete_many(doc! { "id": { "$in": ids } })
+        )?;
+        return Ok(res.deleted_count);
+    }
+
+    /// Start transaction in new session
+    async fn do_begin_transaction(&mut self) -> StoreResult<()> {
+        if self.session.is_some() {
+            return Err(StoreError::Unavailable(
+                "Transaction is already running".to_string(),
+            ));
+        }
+
+        let mut session = self.client()?.start_session().await?;
+        // Transactions need replica set, standalone servers refuse them
+        session.start_transaction().await?;
+
+        self.session = Some(session);
+        return Ok(());
+    }
+
+    /// Commit transaction of current session
+    async fn do_commit_transaction(&mut self) -> StoreResult<()> {
+        let mut session = match self.session.take() {
+            Some(session) => session,
+            None => {
+                return Err(StoreError::Unavailable(
+                    "No transaction is running".to_string(),
+                ))
+            }
         };
+        if let Err(e) = session.commit_transaction().await {
+            let _res = session.abort_transaction().await;
+            return Err(e.into());
+        }
+        return Ok(());
+    }
+}
 
-        let _res = coll.delete_one(filter).await;
+#[async_trait]
+impl Store for StoreMongo {
+    async fn connect(&mut self, url: &str, alturl: &str) -> StoreResult<()> {
+        // Preserve parameters
+        self.path = url.to_string();
+        self.local_path = alturl.to_string();
+
+        if !self.do_conn().await {
+            info!("Not connected");
+            return Err(StoreError::Unavailable(format!("Can't connect to {}", url)));
+        }
+
+        // Collections are known from internals even while database is down
+        let internals = self.get_internals().await?;
+        let collections = internals.safe_strstr("collections", &HashMap::new());
+        debug!("Collections: {}", collections.len());
+        for coll_name in collections {
+            if !self.collections.contains_key(&coll_name.1) {
+                self.register_collection(&coll_name.1);
+                self.pending_collections.push(coll_name.1.clone());
+            }
+        }
+
+        // Unreachable database is not fatal, requests fail until it is back
+        self.healthy = false;
+        self.next_retry = None;
+        match self.ensure_connected().await {
+            Ok(()) => info!("Connected {} / {}!", url, self.database_name),
+            Err(_e) => info!("Not connected, starting without database"),
+        }
+
+        return Ok(());
+    }
+
+    async fn disconnect(&mut self) {}
+
+    async fn get_collections(&mut self) -> StoreResult<Vec<String>> {
+        return tracked!(self, self.do_get_collections());
+    }
+
+    fn has_collection(&self, collection: &str) -> bool {
+        return self.collections.contains_key(collection);
+    }
+
+    async fn ensure_collection(&mut self, collection: &str) -> StoreResult<()> {
+        return tracked!(self, self.do_ensure_collection(collection));
+    }
+
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
+        return tracked!(self, self.do_get_item_ids(collection));
+    }
+
+    async fn get_all_items(
+        &mut self,
+        collection: &str,
+        sort_key: &str,
+        filter: &Filter,
+    ) -> StoreResult<ListResult> {
+        return self
+            .get_items(
+                collection,
+                u64::MAX,
+                u64::MAX,
+                sort_key,
+                filter,
+                u64::MAX,
+                u64::MAX,
+            )
+            .await;
+    }
+
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        return tracked!(self, self.do_get_item(collection, id));
+    }
+
+    async fn get_items(
+        &mut self,
+        collection: &str,
+        id_min: u64,
+        id_max: u64,
+        sort_key: &str,
+        filter: &Filter,
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<ListResult> {
+        return tracked!(
+            self,
+            self.do_get_items(collection, id_min, id_max, sort_key, filter, skip, limit)
+        );
+    }
+
+    async fn search_items(
+        &mut self,
+        collection: &str,
+        query: &str,
+        fields: &Vec<String>,
+        filter: &Filter,
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<(Vec<Item>, u64)> {
+        return tracked!(
+            self,
+            self.do_search_items(collection, query, fields, filter, skip, limit)
+        );
+    }
+
+    async fn set_item(&mut self, collection: &str, itm: &Item, merge: bool) -> StoreResult<u64> {
+        return tracked!(self, self.do_set_item(collection, itm, merge));
+    }
+
+    async fn set_item_versioned(
+        &mut self,
+        collection: &str,
+        itm: &Item,
+        merge: bool,
+        expected_version: u64,
+    ) -> StoreResult<(u64, u64)> {
+        return tracked!(
+            self,
+            self.do_set_item_versioned(collection, itm, merge, expected_version)
+        );
+    }
 
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&id) {
-                coll.remove(&id);
-                return true;
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<()> {
+        return tracked!(self, self.do_del_item(collection, id));
+    }
+
+    async fn set_items(
+        &mut self,
+        collection: &str,
+        itms: &Vec<Item>,
+        merge: bool,
+    ) -> StoreResult<Vec<u64>> {
+        return tracked!(self, self.do_set_items(collection, itms, merge));
+    }
+
+    async fn del_items(&mut self, collection: &str, ids: &Vec<u64>) -> StoreResult<u64> {
+        return tracked!(self, self.do_del_items(collection, ids));
+    }
+
+    async fn begin_transaction(&mut self) -> StoreResult<()> {
+        return tracked!(self, self.do_begin_transaction());
+    }
+
+    async fn commit_transaction(&mut self) -> StoreResult<()> {
+        return tracked!(self, self.do_commit_transaction());
+    }
+
+    async fn abort_transaction(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            if let Err(e) = session.abort_transaction().await {
+                error!("Failed to abort transaction: {}", e);
             }
         }
-        return false;
+    }
+
+    async fn get_indexes(&mut self, collection: &str) -> StoreResult<Vec<IndexSpec>> {
+        return tracked!(self, self.do_get_indexes(collection));
+    }
+
+    async fn purge_deleted(&mut self, older_than: i64) -> StoreResult<u64> {
+        let mut cnt = 0;
+        let filter = Filter::eq(DELETED_FIELD, Value::Bool(true)).and(Filter::Cmp(
+            DELETED_AT_FIELD.to_string(),
+            Cmp::Lt,
+            Value::from(older_than),
+        ));
+
+        for collection in self.get_collections().await? {
+            let lr = self.get_all_items(&collection, "id", &filter).await?;
+            let ids: Vec<u64> = lr.map.keys().cloned().collect();
+            cnt += self.del_items(&collection, &ids).await?;
+        }
+
+        return Ok(cnt);
     }
 
     async fn get_credentials(&mut self) -> String {
@@ -461,33 +1145,41 @@ impl Store for StoreMongo {
         return self.local_path.clone() + "/token.pickle";
     }
 
-    async fn get_internals(&mut self) -> Item {
+    async fn get_internals(&mut self) -> StoreResult<Item> {
         let tmp_data_path = self.local_path.clone() + "/internals.js";
 
         let read_data = std::fs::read_to_string(tmp_data_path);
         if let Err(_e) = read_data {
-            return Item::new();
+            return Ok(Item::new());
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        let itm: Item = serde_json::from_str(&text)?;
+        return Ok(itm);
     }
 
-    async fn get_settings(&mut self) -> Item {
+    async fn get_settings(&mut self) -> StoreResult<Item> {
         let tmp_data_path = self.local_path.clone() + "/settings.js";
 
         let read_data = std::fs::read_to_string(tmp_data_path);
         if let Err(_e) = read_data {
-            return Item::new();
+            return Ok(Item::new());
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        let itm: Item = serde_json::from_str(&text)?;
+        return Ok(itm);
     }
 
-    async fn set_settings(&mut self, itm: Item) {
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()> {
         let tmp_data_path = self.local_path.clone() + "/settings.js";
-        let s = serde_json::to_string(&itm);
-        std::fs::write(tmp_data_path, s.unwrap()).expect("Couldn't write item");
+        let s = serde_json::to_string(&itm)?;
+        std::fs::write(tmp_data_path, s)?;
+        return Ok(());
+    }
+
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()> {
+        let tmp_data_path = self.local_path.clone() + "/internals.js";
+        let s = serde_json::to_string(&itm)?;
+        std::fs::write(tmp_data_path, s)?;
+        return Ok(());
     }
 }
@@ -0,0 +1,760 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use isabelle_dm::data_model::list_result::ListResult;
+
+use crate::state::query::*;
+use crate::state::store::*;
+use async_trait::async_trait;
+use isabelle_dm::data_model::item::*;
+use log::{debug, error, info, trace};
+use parking_lot::{Mutex, MutexGuard};
+use regex::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::FromSqlConversionFailure(..)
+            | rusqlite::Error::ToSqlConversionFailure(_) => {
+                StoreError::Serialization(e.to_string())
+            }
+            _ => StoreError::Unavailable(e.to_string()),
+        }
+    }
+}
+
+/// SQLite storage implementation
+#[derive(Debug)]
+pub struct StoreSqlite {
+    /// Path to database file
+    pub path: String,
+
+    /// Local settings path (like for Local storage)
+    pub local_path: String,
+
+    /// Collection hash map
+    pub collections: HashMap<String, u64>,
+
+    /// Actual SQLite connection. It can't be shared between threads, so it
+    /// is only used under the lock.
+    pub connection: Option<Mutex<Connection>>,
+
+    /// Modification times of document files imported last
+    document_mtimes: HashMap<String, SystemTime>,
+}
+
+impl StoreSqlite {
+    pub fn new() -> Self {
+        Self {
+            path: "".to_string(),
+            local_path: "".to_string(),
+            collections: HashMap::new(),
+            connection: None,
+            document_mtimes: HashMap::new(),
+        }
+    }
+
+    /// Open database file and create schema
+    pub fn do_conn(&mut self) -> bool {
+        if self.connection.is_some() {
+            return true;
+        }
+
+        let conn = match Connection::open(&self.path) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to open {}: {}", &self.path, e);
+                return false;
+            }
+        };
+
+        let res = conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;
+             CREATE TABLE IF NOT EXISTS collections (
+                 name TEXT PRIMARY KEY,
+                 cnt INTEGER NOT NULL DEFAULT 0
+             );
+             CREATE TABLE IF NOT EXISTS items (
+                 collection TEXT NOT NULL,
+                 id INTEGER NOT NULL,
+                 data TEXT NOT NULL,
+                 PRIMARY KEY (collection, id)
+             );
+             CREATE TABLE IF NOT EXISTS documents (
+                 name TEXT PRIMARY KEY,
+                 data TEXT NOT NULL
+             );",
+        );
+        if let Err(e) = res {
+            error!("Failed to create schema: {}", e);
+            return false;
+        }
+
+        // SQLite has no built-in regular expressions, so provide our own
+        // function for $regex filters.
+        let res = conn.create_scalar_function(
+            "isabelle_regexp",
+            3,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| {
+                let text = match ctx.get_raw(0).as_str() {
+                    Ok(t) => t.to_string(),
+                    Err(_) => return Ok(false),
+                };
+                // Regex is compiled once per statement rather than per row
+                let options: String = ctx.get(2)?;
+                let re = ctx.get_or_create_aux(1, |pattern| -> Result<Regex, String> {
+                    let pattern = pattern.as_str().map_err(|e| e.to_string())?;
+                    return build_regex(pattern, &options);
+                })?;
+                Ok(re.is_match(&text))
+            },
+        );
+        if let Err(e) = res {
+            error!("Failed to register regexp function: {}", e);
+            return false;
+        }
+
+        self.connection = Some(Mutex::new(conn));
+        return true;
+    }
+
+    /// Get SQLite connection, if open
+    fn conn(&self) -> StoreResult<MutexGuard<Connection>> {
+        return match &self.connection {
+            Some(conn) => Ok(conn.lock()),
+            None => Err(StoreError::Unavailable("Not connected".to_string())),
+        };
+    }
+
+    /// Read named JSON document (settings, internals)
+    fn get_document(&mut self, name: &str) -> StoreResult<Option<Item>> {
+        let text: Option<String> = self
+            .conn()?
+            .query_row(
+                "SELECT data FROM documents WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match text {
+            Some(t) => Ok(Some(serde_json::from_str(&t)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Write named JSON document (settings, internals)
+    fn set_document(&mut self, name: &str, itm: &Item) -> StoreResult<()> {
+        let s = serde_json::to_string(itm)?;
+        self.conn()?.execute(
+            "INSERT INTO documents (name, data) VALUES (?1, ?2)
+             ON CONFLICT(name) DO UPDATE SET data = excluded.data",
+            params![name, s],
+        )?;
+        return Ok(());
+    }
+
+    /// Import document from the local data folder if the file changed since
+    /// it was imported last. Like with other stores, the file takes
+    /// precedence, so that it can be edited on disk.
+    fn import_document(&mut self, name: &str, file_name: &str) -> StoreResult<()> {
+        let tmp_data_path = self.local_path.clone() + "/" + file_name;
+        let mtime = match std::fs::metadata(&tmp_data_path).and_then(|m| m.modified()) {
+            Ok(mtime) => mtime,
+            Err(_e) => return Ok(()),
+        };
+        if self.document_mtimes.get(name) == Some(&mtime) {
+            return Ok(());
+        }
+
+        let read_data = std::fs::read_to_string(&tmp_data_path);
+        if let Ok(text) = read_data {
+            match serde_json::from_str::<Item>(&text) {
+                Ok(itm) => {
+                    info!("Importing {} from {}", name, &tmp_data_path);
+                    self.set_document(name, &itm)?;
+                }
+                Err(e) => {
+                    error!("Failed to parse {}: {}", &tmp_data_path, e);
+                }
+            }
+        }
+        self.document_mtimes.insert(name.to_string(), mtime);
+        return Ok(());
+    }
+
+    /// Write document to database and to the local data folder, so that the
+    /// file doesn't override it later
+    fn store_document(&mut self, name: &str, file_name: &str, itm: &Item) -> StoreResult<()> {
+        self.set_document(name, itm)?;
+        if self.local_path != "" {
+            let tmp_data_path = self.local_path.clone() + "/" + file_name;
+            std::fs::write(&tmp_data_path, serde_json::to_string(itm)?)?;
+            if let Ok(mtime) = std::fs::metadata(&tmp_data_path).and_then(|m| m.modified()) {
+                self.document_mtimes.insert(name.to_string(), mtime);
+            }
+        }
+        return Ok(());
+    }
+
+    /// Register collection in the database and in the local map
+    fn create_collection(&mut self, name: &str) -> StoreResult<()> {
+        self.conn()?.execute(
+            "INSERT OR IGNORE INTO collections (name, cnt) VALUES (?1, 0)",
+            params![name],
+        )?;
+        if !self.collections.contains_key(name) {
+            let coll_idx = self.collections.len().try_into().unwrap();
+            self.collections.insert(name.to_string(), coll_idx);
+            trace!("New collection {}", name);
+        }
+        return Ok(());
+    }
+
+    /// Convert JSON filter value to SQL parameter
+    fn json_to_sql_value(v: &Value) -> SqlValue {
+        match v {
+            Value::Null => SqlValue::Null,
+            Value::Bool(b) => SqlValue::Integer(*b as i64),
+            Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    SqlValue::Integer(i)
+                } else if let Some(u) = n.as_u64() {
+                    SqlValue::Integer(u as i64)
+                } else {
+                    SqlValue::Real(n.as_f64().unwrap_or(0.0))
+                }
+            }
+            Value::String(s) => SqlValue::Text(s.clone()),
+            _ => SqlValue::Text(v.to_string()),
+        }
+    }
+
+    /// Convert dotted field path (like "strs.login") to SQL expression.
+    /// The path becomes part of the query text, so quotes aren't allowed.
+    fn field_to_sql_expr(field: &str) -> Option<String> {
+        let mut path = "$".to_string();
+        for part in field.split('.') {
+            if part == "" || part.contains(|c: char| c == '"' || c == '\'' || c == '\\') {
+                return None;
+            }
+            path = path + ".\"" + part + "\"";
+        }
+        return Some(format!("json_extract(data, '{}')", path));
+    }
+
+    /// Get SQL expression for the field
+    fn field_expr(field: &str) -> Option<String> {
+        if field == "id" {
+            return Some("id".to_string());
+        }
+        return Self::field_to_sql_expr(field);
+    }
+
+    /// Translate filter to SQL condition
+    pub fn filter_to_sql(filter: &Filter, params: &mut Vec<SqlValue>) -> Option<String> {
+        match filter {
+            Filter::All => Some("1".to_string()),
+            Filter::And(v) | Filter::Or(v) | Filter::Nor(v) => {
+                let mut sub: Vec<String> = Vec::new();
+                for f in v {
+                    sub.push(Self::filter_to_sql(f, params)?);
+                }
+                Some(match filter {
+                    Filter::And(_) if sub.is_empty() => "1".to_string(),
+                    Filter::Or(_) if sub.is_empty() => "0".to_string(),
+                    Filter::Nor(_) if sub.is_empty() => "1".to_string(),
+                    Filter::And(_) => "(".to_owned() + &sub.join(" AND ") + ")",
+                    Filter::Or(_) => "(".to_owned() + &sub.join(" OR ") + ")",
+                    _ => "NOT (".to_owned() + &sub.join(" OR ") + ")",
+                })
+            }
+            Filter::Cmp(field, op, val) => {
+                let expr = Self::field_expr(field)?;
+                if val.is_null() {
+                    return match op {
+                        Cmp::Eq => Some(expr + " IS NULL"),
+                        Cmp::Ne => Some(expr + " IS NOT NULL"),
+                        _ => Some("0".to_string()),
+                    };
+                }
+                params.push(Self::json_to_sql_value(val));
+                Some(match op {
+                    Cmp::Eq => format!("{} = ?", expr),
+                    Cmp::Ne => format!("({} IS NULL OR {} <> ?)", expr, expr),
+                    Cmp::Gt => format!("{} > ?", expr),
+                    Cmp::Gte => format!("{} >= ?", expr),
+                    Cmp::Lt => format!("{} < ?", expr),
+                    Cmp::Lte => format!("{} <= ?", expr),
+                })
+            }
+            Filter::In(field, vals) | Filter::Nin(field, vals) => {
+                let expr = Self::field_expr(field)?;
+                let is_in = matches!(filter, Filter::In(_, _));
+                if vals.is_empty() {
+                    return Some(if is_in { "0" } else { "1" }.to_string());
+                }
+                for v in vals {
+                    params.push(Self::json_to_sql_value(v));
+                }
+                let marks = vec!["?"; vals.len()].join(", ");
+                if is_in {
+                    Some(format!("{} IN ({})", expr, marks))
+                } else {
+                    Some(format!("({} IS NULL OR {} NOT IN ({}))", expr, expr, marks))
+                }
+            }
+            Filter::Exists(field, b) => {
+                let expr = Self::field_expr(field)?;
+                if *b {
+                    Some(expr + " IS NOT NULL")
+                } else {
+                    Some(expr + " IS NULL")
+                }
+            }
+            Filter::Regex(field, re) => {
+                let expr = Self::field_expr(field)?;
+                params.push(SqlValue::Text(re.pattern.clone()));
+                params.push(SqlValue::Text(re.options.clone()));
+                Some(format!("isabelle_regexp({}, ?, ?)", expr))
+            }
+        }
+    }
+
+    /// Translate sort key to SQL ordering expression
+    fn sort_to_sql(sort_key: &str) -> String {
+        let (key, order) = match sort_key.strip_prefix('-') {
+            Some(k) => (k, "DESC"),
+            None => (sort_key, "ASC"),
+        };
+
+        if key == "" || key == "id" {
+            return "id ".to_owned() + order;
+        }
+
+        let expr = if key.contains('.') {
+            Self::field_to_sql_expr(key)
+        } else {
+            // Plain keys are looked up among strings first, then numbers
+            match (
+                Self::field_to_sql_expr(&("strs.".to_owned() + key)),
+                Self::field_to_sql_expr(&("u64s.".to_owned() + key)),
+            ) {
+                (Some(s), Some(n)) => Some(format!("COALESCE({}, {})", s, n)),
+                _ => None,
+            }
+        };
+
+        match expr {
+            Some(e) => e + " " + order + ", id ASC",
+            None => "id ".to_owned() + order,
+        }
+    }
+
+    /// Get the largest ID ever given in the collection
+    fn get_counter(&mut self, collection: &str) -> StoreResult<u64> {
+        let cnt: Option<i64> = self
+            .conn()?
+            .query_row(
+                "SELECT cnt FROM collections WHERE name = ?1",
+                params![collection],
+                |row| row.get(0),
+            )
+            .optional()?;
+        return Ok(cnt.unwrap_or(0) as u64);
+    }
+}
+
+#[async_trait]
+impl Store for StoreSqlite {
+    async fn connect(&mut self, url: &str, alturl: &str) -> StoreResult<()> {
+        // Preserve parameters
+        self.path = url.to_string();
+        self.local_path = alturl.to_string();
+
+        if !self.do_conn() {
+            info!("Not connected");
+            return Err(StoreError::Unavailable(format!("Can't open {}", url)));
+        }
+        info!("Connected {}!", url);
+
+        // Take internals and settings from data folder
+        self.import_document("internals", "internals.js")?;
+        self.import_document("settings", "settings.js")?;
+
+        // Create collections that are already known and declared ones
+        let known: Vec<String> = {
+            let conn = self.conn()?;
+            let mut stmt = conn.prepare("SELECT name FROM collections ORDER BY name")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            let names: Vec<String> = rows.filter_map(|r| r.ok()).collect();
+            names
+        };
+        for coll_name in known {
+            self.create_collection(&coll_name)?;
+        }
+
+        let internals = self.get_internals().await?;
+        let collections = internals.safe_strstr("collections", &HashMap::new());
+        debug!("Collections: {}", collections.len());
+        for coll_name in collections {
+            debug!("Create collection {}", &coll_name.1);
+            self.create_collection(&coll_name.1)?;
+        }
+
+        return Ok(());
+    }
+
+    async fn disconnect(&mut self) {
+        self.connection = None;
+    }
+
+    async fn get_collections(&mut self) -> StoreResult<Vec<String>> {
+        let mut lst: Vec<String> = Vec::new();
+
+        for coll in &self.collections {
+            lst.push(coll.0.clone());
+        }
+
+        return Ok(lst);
+    }
+
+    fn has_collection(&self, collection: &str) -> bool {
+        return self.collections.contains_key(collection);
+    }
+
+    async fn ensure_collection(&mut self, collection: &str) -> StoreResult<()> {
+        return self.create_collection(collection);
+    }
+
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
+        let mut map: HashMap<u64, bool> = HashMap::new();
+        if !self.collections.contains_key(collection) {
+            return Ok(map);
+        }
+
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT id FROM items WHERE collection = ?1")?;
+        let rows = stmt.query_map(params![collection], |row| row.get::<_, i64>(0))?;
+        for id in rows {
+            map.insert(id? as u64, true);
+        }
+
+        return Ok(map);
+    }
+
+    async fn get_all_items(
+        &mut self,
+        collection: &str,
+        sort_key: &str,
+        filter: &Filter,
+    ) -> StoreResult<ListResult> {
+        return self
+            .get_items(
+                collection,
+                u64::MAX,
+                u64::MAX,
+                sort_key,
+                filter,
+                u64::MAX,
+                u64::MAX,
+            )
+            .await;
+    }
+
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        let text: Option<String> = self
+            .conn()?
+            .query_row(
+                "SELECT data FROM items WHERE collection = ?1 AND id = ?2",
+                params![collection, id as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match text {
+            Some(t) => Ok(Some(serde_json::from_str::<Item>(&t)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_items(
+        &mut self,
+        collection: &str,
+        id_min: u64,
+        id_max: u64,
+        sort_key: &str,
+        filter: &Filter,
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<ListResult> {
+        let filter = &filter.clone().hide_deleted();
+        let mut lr = ListResult {
+            map: HashMap::new(),
+            total_count: 0,
+        };
+
+        let eff_id_min: i64 = if id_min == u64::MAX {
+            0
+        } else {
+            std::cmp::min(id_min, i64::MAX as u64) as i64
+        };
+        let eff_id_max: i64 = std::cmp::min(id_max, i64::MAX as u64) as i64;
+        let eff_skip: i64 = if skip == u64::MAX {
+            0
+        } else {
+            std::cmp::min(skip, i64::MAX as u64) as i64
+        };
+        let eff_limit: i64 = std::cmp::min(limit, i64::MAX as u64) as i64;
+
+        debug!(
+            "Getting {} in range {} - {} skip {} limit {} sort key {} filter {}",
+            &collection, eff_id_min, eff_id_max, eff_skip, eff_limit, sort_key, filter
+        );
+
+        let mut sql_params: Vec<SqlValue> = vec![
+            SqlValue::Text(collection.to_string()),
+            SqlValue::Integer(eff_id_min),
+            SqlValue::Integer(eff_id_max),
+        ];
+        let mut cond = "collection = ? AND id >= ? AND id <= ?".to_string();
+
+        if !filter.is_all() {
+            match Self::filter_to_sql(filter, &mut sql_params) {
+                Some(c) => {
+                    cond = cond + " AND (" + &c + ")";
+                }
+                None => {
+                    return Err(StoreError::Invalid(format!(
+                        "Unsupported filter: {}",
+                        filter
+                    )));
+                }
+            }
+        }
+
+        let conn = self.conn()?;
+
+        let count: i64 = conn.query_row(
+            &("SELECT COUNT(*) FROM items WHERE ".to_owned() + &cond),
+            params_from_iter(sql_params.iter()),
+            |row| row.get(0),
+        )?;
+        lr.total_count = count as u64;
+
+        let query = "SELECT id, data FROM items WHERE ".to_owned()
+            + &cond
+            + " ORDER BY "
+            + &Self::sort_to_sql(sort_key)
+            + " LIMIT ? OFFSET ?";
+        sql_params.push(SqlValue::Integer(eff_limit));
+        sql_params.push(SqlValue::Integer(eff_skip));
+
+        let mut stmt = conn.prepare(&query)?;
+        let rows = stmt.query_map(params_from_iter(sql_params.iter()), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (id, text) = row?;
+            lr.map
+                .insert(id as u64, serde_json::from_str::<Item>(&text)?);
+        }
+
+        debug!(
+            " - result: {} items, total {}",
+            lr.map.len(),
+            lr.total_count
+        );
+        return Ok(lr);
+    }
+
+    async fn set_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+    ) -> StoreResult<u64> {
+        let mut itm = exp_itm.clone();
+        if itm.bools.contains_key("__security_preserve") {
+            itm.bools.remove("__security_preserve");
+        }
+
+        if !self.collections.contains_key(collection) {
+            self.create_collection(collection)?;
+        }
+
+        if itm.id == u64::MAX {
+            itm.id = self.get_counter(collection)? + 1;
+        }
+
+        let old_itm = self.get_item(collection, itm.id).await?;
+        let mut new_itm = itm.clone();
+        if !old_itm.is_none() && merge {
+            new_itm = old_itm.as_ref().unwrap().clone();
+            new_itm.merge(&itm);
+        }
+        bump_version(&mut new_itm, &old_itm);
+
+        let s = serde_json::to_string(&new_itm)?;
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO items (collection, id, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT(collection, id) DO UPDATE SET data = excluded.data",
+            params![collection, new_itm.id as i64, s],
+        )?;
+
+        conn.execute(
+            "UPDATE collections SET cnt = MAX(cnt, ?2) WHERE name = ?1",
+            params![collection, new_itm.id as i64],
+        )?;
+        return Ok(new_itm.id);
+    }
+
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<()> {
+        let cnt = self.conn()?.execute(
+            "DELETE FROM items WHERE collection = ?1 AND id = ?2",
+            params![collection, id as i64],
+        )?;
+        if cnt == 0 {
+            return Err(StoreError::NotFound(format!("{} item {}", collection, id)));
+        }
+        return Ok(());
+    }
+
+    async fn set_items(
+        &mut self,
+        collection: &str,
+        itms: &Vec<Item>,
+        merge: bool,
+    ) -> StoreResult<Vec<u64>> {
+        // Single transaction makes one disk sync for the whole batch
+        self.conn()?.execute_batch("SAVEPOINT bulk")?;
+        let mut ids: Vec<u64> = Vec::new();
+        for itm in itms {
+            match self.set_item(collection, itm, merge).await {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    let _res = self.conn()?.execute_batch("ROLLBACK TO bulk; RELEASE bulk");
+                    return Err(e);
+                }
+            }
+        }
+        self.conn()?.execute_batch("RELEASE bulk")?;
+        return Ok(ids);
+    }
+
+    async fn del_items(&mut self, collection: &str, ids: &Vec<u64>) -> StoreResult<u64> {
+        let mut cnt = 0;
+        self.conn()?.execute_batch("SAVEPOINT bulk")?;
+        for id in ids {
+            match self.del_item(collection, *id).await {
+                Ok(()) => cnt += 1,
+                Err(StoreError::NotFound(_)) => {}
+                Err(e) => {
+                    let _res = self.conn()?.execute_batch("ROLLBACK TO bulk; RELEASE bulk");
+                    return Err(e);
+                }
+            }
+        }
+        self.conn()?.execute_batch("RELEASE bulk")?;
+        return Ok(cnt);
+    }
+
+    async fn begin_transaction(&mut self) -> StoreResult<()> {
+        let conn = self.conn()?;
+        if !conn.is_autocommit() {
+            return Err(StoreError::Unavailable(
+                "Transaction is already running".to_string(),
+            ));
+        }
+        conn.execute_batch("BEGIN")?;
+        return Ok(());
+    }
+
+    async fn commit_transaction(&mut self) -> StoreResult<()> {
+        let conn = self.conn()?;
+        if let Err(e) = conn.execute_batch("COMMIT") {
+            let _res = conn.execute_batch("ROLLBACK");
+            return Err(e.into());
+        }
+        return Ok(());
+    }
+
+    async fn abort_transaction(&mut self) {
+        if let Some(conn) = self.connection.as_ref() {
+            if let Err(e) = conn.lock().execute_batch("ROLLBACK") {
+                error!("Failed to abort transaction: {}", e);
+            }
+        }
+    }
+
+    async fn purge_deleted(&mut self, older_than: i64) -> StoreResult<u64> {
+        let mut cnt = 0;
+        let filter = Filter::eq(DELETED_FIELD, Value::Bool(true)).and(Filter::Cmp(
+            DELETED_AT_FIELD.to_string(),
+            Cmp::Lt,
+            Value::from(older_than),
+        ));
+
+        for collection in self.get_collections().await? {
+            let lr = self.get_all_items(&collection, "id", &filter).await?;
+            let ids: Vec<u64> = lr.map.keys().cloned().collect();
+            cnt += self.del_items(&collection, &ids).await?;
+        }
+
+        return Ok(cnt);
+    }
+
+    async fn get_credentials(&mut self) -> String {
+        return self.local_path.clone() + "/credentials.json";
+    }
+
+    async fn get_pickle(&mut self) -> String {
+        return self.local_path.clone() + "/token.pickle";
+    }
+
+    async fn get_internals(&mut self) -> StoreResult<Item> {
+        self.import_document("internals", "internals.js")?;
+        return Ok(self.get_document("internals")?.unwrap_or(Item::new()));
+    }
+
+    async fn get_settings(&mut self) -> StoreResult<Item> {
+        self.import_document("settings", "settings.js")?;
+        return Ok(self.get_document("settings")?.unwrap_or(Item::new()));
+    }
+
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()> {
+        return self.store_document("settings", "settings.js", &itm);
+    }
+
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()> {
+        return self.store_document("internals", "internals.js", &itm);
+    }
+}
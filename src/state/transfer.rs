@@ -0,0 +1,580 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::store::{next_page, read_page, Store, StoreResult};
+use isabelle_dm::data_model::item::Item;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+/// Number of record errors kept in import report
+const ERROR_LIMIT: usize = 100;
+
+/// Format of exported and imported records
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One item JSON per line
+    Jsonl,
+
+    /// Header with field names (`id`, `strs.<name>`, `u64s.<name>`,
+    /// `bools.<name>`, `strstrs.<name>` as JSON object), then one item per row
+    Csv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown format: {}", name)),
+        }
+    }
+
+    /// Get MIME type of exported data
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Jsonl => "application/x-ndjson",
+            Format::Csv => "text/csv",
+        }
+    }
+
+    /// Get file extension of exported data
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Jsonl => "jsonl",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// How imported items with IDs already existing in the collection are
+/// handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conflict {
+    /// Keep existing item
+    Skip,
+
+    /// Replace existing item
+    Overwrite,
+
+    /// Merge imported fields into existing item
+    Merge,
+}
+
+impl Conflict {
+    pub fn parse(name: &str) -> Result<Conflict, String> {
+        match name {
+            "skip" => Ok(Conflict::Skip),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "merge" => Ok(Conflict::Merge),
+            _ => Err(format!("Unknown conflict handling: {}", name)),
+        }
+    }
+}
+
+/// Get CSV columns covering all fields of the collection items, reading
+/// them page by page
+pub async fn csv_columns(store: &mut dyn Store, collection: &str) -> StoreResult<Vec<String>> {
+    let mut columns: BTreeSet<(usize, String)> = BTreeSet::new();
+    let mut id_min = Some(0);
+    while let Some(id) = id_min {
+        let itms = read_page(store, collection, id).await?;
+        for itm in &itms {
+            columns.extend(itm.strs.keys().map(|k| (0, format!("strs.{}", k))));
+            columns.extend(itm.u64s.keys().map(|k| (1, format!("u64s.{}", k))));
+            columns.extend(itm.bools.keys().map(|k| (2, format!("bools.{}", k))));
+            columns.extend(itm.strstrs.keys().map(|k| (3, format!("strstrs.{}", k))));
+        }
+        id_min = next_page(&itms);
+    }
+
+    let mut res = vec!["id".to_string()];
+    res.extend(columns.into_iter().map(|(_group, column)| column));
+    return Ok(res);
+}
+
+/// Quote CSV field if needed
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+/// Get CSV line of given fields
+fn csv_line(fields: &Vec<String>) -> String {
+    let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+    return fields.join(",") + "\n";
+}
+
+/// Get CSV header line
+pub fn csv_header(columns: &Vec<String>) -> String {
+    return csv_line(columns);
+}
+
+/// Get exported record of the item, ending with newline
+pub fn format_item(itm: &Item, format: Format, columns: &Vec<String>) -> String {
+    if format == Format::Jsonl {
+        return serde_json::to_string(itm).unwrap() + "\n";
+    }
+
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|column| match column.split_once('.') {
+            Some(("strs", name)) => itm.strs.get(name).cloned().unwrap_or_default(),
+            Some(("u64s", name)) => itm.u64s.get(name).map_or("".to_string(), |v| v.to_string()),
+            Some(("bools", name)) => itm
+                .bools
+                .get(name)
+                .map_or("".to_string(), |v| v.to_string()),
+            Some(("strstrs", name)) => itm
+                .strstrs
+                .get(name)
+                .map_or("".to_string(), |v| serde_json::to_string(v).unwrap()),
+            _ => itm.id.to_string(),
+        })
+        .collect();
+    return csv_line(&fields);
+}
+
+/// Split CSV record into fields
+fn parse_csv_record(record: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    return fields;
+}
+
+/// Splitter of incoming data into records, fed chunk by chunk. CSV records
+/// may span several lines inside quotes.
+pub struct RecordSplitter {
+    format: Format,
+    buf: Vec<u8>,
+}
+
+impl RecordSplitter {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Add the chunk of incoming data
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Take the next complete record. At the end of data the rest is taken
+    /// as the last record.
+    pub fn next_record(&mut self, eof: bool) -> Option<Result<String, String>> {
+        loop {
+            let mut quoted = false;
+            let mut end = None;
+            for (pos, b) in self.buf.iter().enumerate() {
+                match b {
+                    b'"' if self.format == Format::Csv => quoted = !quoted,
+                    b'\n' if !quoted => {
+                        end = Some(pos);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            let end = match end {
+                Some(end) => end,
+                None if eof && !self.buf.is_empty() => self.buf.len(),
+                None => return None,
+            };
+            let rest = self.buf.split_off(std::cmp::min(end + 1, self.buf.len()));
+            let record = std::mem::replace(&mut self.buf, rest);
+            let record = match String::from_utf8(record) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(format!("Bad UTF-8: {}", e))),
+            };
+            let record = record.trim_end_matches(|c| c == '\n' || c == '\r');
+            if record.trim() != "" {
+                return Some(Ok(record.to_string()));
+            }
+        }
+    }
+}
+
+/// Parser of imported records
+pub struct RecordParser {
+    format: Format,
+    columns: Option<Vec<String>>,
+}
+
+impl RecordParser {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            columns: None,
+        }
+    }
+
+    /// Parse the record into item, none for CSV header. Items without ID
+    /// get u64::MAX.
+    pub fn parse(&mut self, record: &str) -> Result<Option<Item>, String> {
+        if self.format == Format::Jsonl {
+            return serde_json::from_str::<Item>(record)
+                .map(Some)
+                .map_err(|e| format!("Bad JSON: {}", e));
+        }
+
+        let fields = parse_csv_record(record);
+        let columns = match &self.columns {
+            Some(columns) => columns,
+            None => {
+                self.columns = Some(fields);
+                return Ok(None);
+            }
+        };
+        if fields.len() != columns.len() {
+            return Err(format!(
+                "Expected {} fields, got {}",
+                columns.len(),
+                fields.len()
+            ));
+        }
+
+        let mut itm = Item::new();
+        itm.id = u64::MAX;
+        for (column, value) in columns.iter().zip(fields.into_iter()) {
+            if value == "" {
+                continue;
+            }
+            match column.split_once('.') {
+                Some(("strs", name)) => {
+                    itm.strs.insert(name.to_string(), value);
+                }
+                Some(("u64s", name)) => {
+                    let v = value
+                        .parse::<u64>()
+                        .map_err(|e| format!("Bad {}: {}", column, e))?;
+                    itm.u64s.insert(name.to_string(), v);
+                }
+                Some(("bools", name)) => {
+                    let v = value
+                        .parse::<bool>()
+                        .map_err(|e| format!("Bad {}: {}", column, e))?;
+                    itm.bools.insert(name.to_string(), v);
+                }
+                Some(("strstrs", name)) => {
+                    let v = serde_json::from_str::<HashMap<String, String>>(&value)
+                        .map_err(|e| format!("Bad {}: {}", column, e))?;
+                    itm.strstrs.insert(name.to_string(), v);
+                }
+                _ if column == "id" => {
+                    itm.id = value.parse::<u64>().map_err(|e| format!("Bad id: {}", e))?;
+                }
+                _ => return Err(format!("Unknown column: {}", column)),
+            }
+        }
+        return Ok(Some(itm));
+    }
+}
+
+/// Report of the import
+#[derive(Debug, Default, Serialize)]
+pub struct ImportStats {
+    /// Number of written items
+    pub imported: u64,
+
+    /// Number of items skipped as existing
+    pub skipped: u64,
+
+    /// Number of rejected records
+    pub failed: u64,
+
+    /// Errors of the first rejected records
+    pub errors: Vec<String>,
+}
+
+impl ImportStats {
+    /// Count rejected record
+    pub fn fail(&mut self, record: u64, error: &str) {
+        self.failed += 1;
+        if self.errors.len() < ERROR_LIMIT {
+            self.errors.push(format!("Record {}: {}", record, error));
+        }
+    }
+}
+
+/// Decide how the imported item is written: none if it must be skipped,
+/// otherwise whether it's merged into existing item
+pub async fn resolve_conflict(
+    store: &mut dyn Store,
+    collection: &str,
+    itm: &Item,
+    conflict: Conflict,
+) -> StoreResult<Option<bool>> {
+    if itm.id == u64::MAX || store.get_item(collection, itm.id).await?.is_none() {
+        return Ok(Some(false));
+    }
+    match conflict {
+        Conflict::Skip => return Ok(None),
+        Conflict::Overwrite => return Ok(Some(false)),
+        Conflict::Merge => return Ok(Some(true)),
+    }
+}
+
+/// Export all items of the collection, deleted ones included
+pub async fn export_collection(
+    store: &mut dyn Store,
+    collection: &str,
+    format: Format,
+    out: &mut dyn Write,
+) -> StoreResult<u64> {
+    let columns = if format == Format::Csv {
+        let columns = csv_columns(store, collection).await?;
+        out.write_all(csv_header(&columns).as_bytes())?;
+        columns
+    } else {
+        Vec::new()
+    };
+
+    let mut cnt = 0;
+    let mut id_min = Some(0);
+    while let Some(id) = id_min {
+        let itms = read_page(store, collection, id).await?;
+        for itm in &itms {
+            out.write_all(format_item(itm, format, &columns).as_bytes())?;
+            cnt += 1;
+        }
+        id_min = next_page(&itms);
+    }
+    out.flush()?;
+    return Ok(cnt);
+}
+
+/// Import items into the collection directly, without hooks
+pub async fn import_collection(
+    store: &mut dyn Store,
+    collection: &str,
+    format: Format,
+    conflict: Conflict,
+    input: &mut dyn Read,
+) -> StoreResult<ImportStats> {
+    store.ensure_collection(collection).await?;
+
+    let mut stats = ImportStats::default();
+    let mut splitter = RecordSplitter::new(format);
+    let mut parser = RecordParser::new(format);
+    let mut chunk = vec![0; 64 * 1024];
+    let mut record_no = 0;
+    loop {
+        let len = input.read(&mut chunk)?;
+        splitter.push(&chunk[..len]);
+        while let Some(record) = splitter.next_record(len == 0) {
+            record_no += 1;
+            let itm = match record.and_then(|record| parser.parse(&record)) {
+                Ok(Some(itm)) => itm,
+                Ok(None) => continue,
+                Err(e) => {
+                    stats.fail(record_no, &e);
+                    continue;
+                }
+            };
+
+            match resolve_conflict(store, collection, &itm, conflict).await? {
+                Some(merge) => {
+                    store.set_item(collection, &itm, merge).await?;
+                    stats.imported += 1;
+                }
+                None => stats.skipped += 1,
+            }
+        }
+        if len == 0 {
+            break;
+        }
+    }
+    return Ok(stats);
+}
+
+/// Export the collection to the file or to standard output
+pub async fn export_command(
+    store: &mut dyn Store,
+    collection: &str,
+    format: &str,
+    output: &Option<String>,
+) -> Result<u64, String> {
+    let format = Format::parse(format)?;
+    if !store
+        .get_collections()
+        .await
+        .map_err(|e| e.to_string())?
+        .contains(&collection.to_string())
+    {
+        return Err(format!("Collection {} doesn't exist", collection));
+    }
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| format!("Can't create {}: {}", path, e))?,
+        )),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    return export_collection(store, collection, format, out.as_mut())
+        .await
+        .map_err(|e| e.to_string());
+}
+
+/// Import items into the collection from the file or from standard input
+pub async fn import_command(
+    store: &mut dyn Store,
+    collection: &str,
+    format: &str,
+    input: &Option<String>,
+    conflict: &str,
+) -> Result<ImportStats, String> {
+    let format = Format::parse(format)?;
+    let conflict = Conflict::parse(conflict)?;
+    let mut input: Box<dyn Read> = match input {
+        Some(path) => {
+            Box::new(File::open(path).map_err(|e| format!("Can't open {}: {}", path, e))?)
+        }
+        None => Box::new(std::io::stdin()),
+    };
+    return import_collection(store, collection, format, conflict, input.as_mut())
+        .await
+        .map_err(|e| e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(splitter: &mut RecordSplitter, eof: bool) -> Vec<String> {
+        let mut res: Vec<String> = Vec::new();
+        while let Some(record) = splitter.next_record(eof) {
+            res.push(record.unwrap());
+        }
+        return res;
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(
+            csv_line(&vec!["1".to_string(), "a,b".to_string(), "".to_string()]),
+            "1,\"a,b\",\n"
+        );
+    }
+
+    #[test]
+    fn csv_records_are_parsed() {
+        assert_eq!(parse_csv_record("1,abc,"), vec!["1", "abc", ""]);
+        assert_eq!(
+            parse_csv_record("\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\""),
+            vec!["a,b", "say \"hi\"", "two\nlines"]
+        );
+
+        let fields = vec!["x,\"y\"\n".to_string(), "z".to_string()];
+        let line = csv_line(&fields);
+        assert_eq!(parse_csv_record(line.trim_end_matches('\n')), fields);
+    }
+
+    #[test]
+    fn records_are_split() {
+        let mut splitter = RecordSplitter::new(Format::Csv);
+        splitter.push(b"id,strs.note\r\n1,\"first\nsec");
+        assert_eq!(records(&mut splitter, false), vec!["id,strs.note"]);
+
+        // Quoted newline doesn't end the record
+        splitter.push(b"ond\"\n\n2,third");
+        assert_eq!(records(&mut splitter, false), vec!["1,\"first\nsecond\""]);
+        assert_eq!(records(&mut splitter, true), vec!["2,third"]);
+        assert!(splitter.next_record(true).is_none());
+
+        // Quotes mean nothing in JSON lines
+        let mut splitter = RecordSplitter::new(Format::Jsonl);
+        splitter.push(b"{\"a\":\"\\\"\"}\n{}\n");
+        assert_eq!(
+            records(&mut splitter, false),
+            vec!["{\"a\":\"\\\"\"}", "{}"]
+        );
+
+        let mut splitter = RecordSplitter::new(Format::Jsonl);
+        splitter.push(b"\xff\n");
+        assert!(splitter.next_record(false).unwrap().is_err());
+    }
+
+    #[test]
+    fn csv_items_are_parsed() {
+        let mut itm = Item::new();
+        itm.id = 5;
+        itm.strs
+            .insert("name".to_string(), "Smith, \"Bob\"".to_string());
+        itm.u64s.insert("age".to_string(), 30);
+        itm.bools.insert("admin".to_string(), true);
+        itm.strstrs.insert(
+            "tags".to_string(),
+            HashMap::from([("a".to_string(), "b".to_string())]),
+        );
+        let columns: Vec<String> = ["id", "strs.name", "u64s.age", "bools.admin", "strstrs.tags"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+
+        let mut parser = RecordParser::new(Format::Csv);
+        let header = csv_header(&columns);
+        assert!(parser.parse(header.trim_end()).unwrap().is_none());
+
+        let record = format_item(&itm, Format::Csv, &columns);
+        let parsed = parser.parse(record.trim_end()).unwrap().unwrap();
+        assert_eq!(parsed.id, 5);
+        assert_eq!(parsed.strs, itm.strs);
+        assert_eq!(parsed.u64s, itm.u64s);
+        assert_eq!(parsed.bools, itm.bools);
+        assert_eq!(parsed.strstrs, itm.strstrs);
+
+        // Empty fields are skipped, missing ID is marked
+        let parsed = parser.parse(",Bob,,,").unwrap().unwrap();
+        assert_eq!(parsed.id, u64::MAX);
+        assert!(parsed.u64s.is_empty());
+
+        assert!(parser.parse("1,Bob").is_err());
+        assert!(parser.parse("1,Bob,old,,").is_err());
+        assert!(parser.parse("1,Bob,,maybe,").is_err());
+    }
+}
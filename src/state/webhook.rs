@@ -0,0 +1,510 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::data::Data;
+use crate::state::events::ItemEvent;
+use crate::state::query::{Cmp, Filter};
+use crate::state::state::State;
+use crate::state::store::{Store, StoreResult};
+use actix_web::{rt, web};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use isabelle_dm::data_model::item::Item;
+use log::{error, info};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Collection keeping webhooks registered by admins
+pub const WEBHOOK_COLLECTION: &str = "__webhook";
+
+/// Collection keeping webhook deliveries: pending ones, the log of
+/// delivered ones and dead letters
+pub const DELIVERY_COLLECTION: &str = "__webhook_delivery";
+
+/// Delivery waiting for the (next) attempt
+pub const STATUS_PENDING: &str = "pending";
+
+/// Delivery accepted by the target
+pub const STATUS_DELIVERED: &str = "delivered";
+
+/// Delivery given up after all attempts failed
+pub const STATUS_DEAD: &str = "dead";
+
+/// Header keeping HMAC-SHA256 signature of "<timestamp>.<body>"
+pub const SIGNATURE_HEADER: &str = "X-Isabelle-Signature";
+
+/// Header keeping UNIX timestamp of the attempt
+pub const TIMESTAMP_HEADER: &str = "X-Isabelle-Timestamp";
+
+/// Header keeping the action that triggered delivery
+pub const EVENT_HEADER: &str = "X-Isabelle-Event";
+
+/// Header keeping delivery ID, the same for all attempts
+pub const DELIVERY_HEADER: &str = "X-Isabelle-Delivery";
+
+/// Actions webhooks can be registered for
+const ACTIONS: [&str; 4] = ["create", "modify", "delete", "restore"];
+
+/// Number of attempts before delivery becomes dead letter
+const MAX_ATTEMPTS: u64 = 8;
+
+/// Delay before the first retry in seconds, doubled for every next one
+const RETRY_DELAY: u64 = 10;
+
+/// Longest delay between retries in seconds
+const MAX_RETRY_DELAY: u64 = 3600;
+
+/// Time given to the target to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Period of checks for deliveries due for retry
+const RETRY_CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// Number of delivered deliveries kept in the log
+const LOG_LIMIT: usize = 1000;
+
+/// Webhook registered by admin
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub id: u64,
+
+    /// Target URL receiving POST requests
+    pub url: String,
+
+    /// Watched collection
+    pub collection: String,
+
+    /// Watched actions, all of them if empty
+    pub actions: Vec<String>,
+
+    /// Secret key of signatures
+    pub secret: String,
+
+    /// Disabled webhooks get no deliveries
+    pub active: bool,
+}
+
+impl Webhook {
+    /// Read webhook from its item: strs.url, strs.collection, strs.actions
+    /// (comma-separated), strs.secret and bools.active
+    pub fn from_item(itm: &Item) -> Self {
+        Self {
+            id: itm.id,
+            url: itm.safe_str("url", ""),
+            collection: itm.safe_str("collection", ""),
+            actions: itm
+                .safe_str("actions", "")
+                .split(',')
+                .map(|action| action.trim().to_string())
+                .filter(|action| action != "")
+                .collect(),
+            secret: itm.safe_str("secret", ""),
+            active: itm.safe_bool("active", true),
+        }
+    }
+
+    /// Check if the webhook can be registered
+    pub fn check(&self) -> Result<(), String> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(format!("Bad webhook URL: {}", self.url));
+        }
+        if self.collection == "" || self.collection.starts_with("__") {
+            return Err(format!("Bad webhook collection: {}", self.collection));
+        }
+        if let Some(action) = self
+            .actions
+            .iter()
+            .find(|action| !ACTIONS.contains(&action.as_str()))
+        {
+            return Err(format!("Unknown webhook action: {}", action));
+        }
+        if self.secret == "" {
+            return Err("Webhook secret is empty".to_string());
+        }
+        return Ok(());
+    }
+
+    /// Check if the webhook watches the change
+    pub fn matches(&self, collection: &str, action: &str) -> bool {
+        return self.active
+            && self.collection == collection
+            && (self.actions.is_empty() || self.actions.iter().any(|a| a == action));
+    }
+}
+
+/// Result of a single delivery attempt
+pub struct Attempt {
+    /// HTTP status of the answer, zero if there was none
+    pub http_status: u64,
+
+    /// Error, none if the target accepted the delivery
+    pub error: Option<String>,
+}
+
+/// Sign the body sent at given time with the secret
+pub fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    return format!("sha256={}", hex);
+}
+
+/// Get delay before the retry following given number of attempts
+fn retry_delay(attempts: u64) -> u64 {
+    let shift = attempts.saturating_sub(1).min(32) as u32;
+    return RETRY_DELAY.saturating_mul(1 << shift).min(MAX_RETRY_DELAY);
+}
+
+/// Get all registered webhooks
+pub async fn get_webhooks(srv: &mut Data) -> StoreResult<HashMap<u64, Webhook>> {
+    srv.rw.ensure_collection(WEBHOOK_COLLECTION).await?;
+    let lr = srv
+        .rw
+        .get_all_items(WEBHOOK_COLLECTION, "id", &Filter::All)
+        .await?;
+    return Ok(lr
+        .map
+        .iter()
+        .map(|(id, itm)| (*id, Webhook::from_item(itm)))
+        .collect());
+}
+
+/// Build pending delivery of the payload to the webhook
+pub fn new_delivery(
+    hook: &Webhook,
+    collection: &str,
+    id: u64,
+    action: &str,
+    payload: &str,
+) -> Item {
+    let now = Utc::now().timestamp() as u64;
+    let mut delivery = Item::new();
+    delivery.id = u64::MAX;
+    delivery.u64s.insert("webhook_id".to_string(), hook.id);
+    delivery.u64s.insert("item_id".to_string(), id);
+    delivery.u64s.insert("attempts".to_string(), 0);
+    delivery.u64s.insert("created".to_string(), now);
+    delivery.u64s.insert("next_attempt".to_string(), now);
+    delivery
+        .strs
+        .insert("collection".to_string(), collection.to_string());
+    delivery
+        .strs
+        .insert("action".to_string(), action.to_string());
+    delivery
+        .strs
+        .insert("payload".to_string(), payload.to_string());
+    delivery
+        .strs
+        .insert("status".to_string(), STATUS_PENDING.to_string());
+    return delivery;
+}
+
+/// Queue deliveries of the change to webhooks watching it. Returns number
+/// of queued deliveries.
+pub async fn enqueue(srv: &mut Data, event: &ItemEvent) -> StoreResult<u64> {
+    let hooks = get_webhooks(srv).await?;
+    let mut payload = serde_json::to_value(event)?;
+    payload["item"] = serde_json::to_value(&event.item)?;
+    payload["timestamp"] = json!(Utc::now().timestamp());
+    let payload = payload.to_string();
+
+    srv.rw.ensure_collection(DELIVERY_COLLECTION).await?;
+    let mut cnt = 0;
+    for hook in hooks.values() {
+        if !hook.matches(&event.collection, &event.action) {
+            continue;
+        }
+        let delivery = new_delivery(hook, &event.collection, event.id, &event.action, &payload);
+        srv.rw
+            .set_item(DELIVERY_COLLECTION, &delivery, false)
+            .await?;
+        cnt += 1;
+    }
+    return Ok(cnt);
+}
+
+/// Send the delivery to the webhook once
+pub async fn send(client: &reqwest::Client, delivery: &Item, hook: &Webhook) -> Attempt {
+    let body = delivery.safe_str("payload", "");
+    let timestamp = Utc::now().timestamp() as u64;
+    let res = client
+        .post(&hook.url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, delivery.safe_str("action", ""))
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign(&hook.secret, timestamp, &body))
+        .body(body)
+        .send()
+        .await;
+
+    match res {
+        Ok(resp) => {
+            let status = resp.status();
+            return Attempt {
+                http_status: status.as_u16() as u64,
+                error: if status.is_success() {
+                    None
+                } else {
+                    Some(format!("Target answered with HTTP {}", status.as_u16()))
+                },
+            };
+        }
+        Err(e) => {
+            return Attempt {
+                http_status: 0,
+                error: Some(e.to_string()),
+            };
+        }
+    }
+}
+
+/// Build HTTP client of deliveries
+pub fn new_client() -> reqwest::Client {
+    return reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or(reqwest::Client::new());
+}
+
+/// Record result of the attempt: the delivery is either done, scheduled
+/// for retry with growing delay or becomes dead letter
+pub async fn record_attempt(
+    srv: &mut Data,
+    delivery: &mut Item,
+    attempt: &Attempt,
+) -> StoreResult<()> {
+    let now = Utc::now().timestamp() as u64;
+    let attempts = *delivery.u64s.get("attempts").unwrap_or(&0) + 1;
+    delivery.u64s.insert("attempts".to_string(), attempts);
+    delivery.u64s.insert("last_attempt".to_string(), now);
+    delivery
+        .u64s
+        .insert("http_status".to_string(), attempt.http_status);
+
+    let status = match &attempt.error {
+        None => {
+            info!("Webhook delivery {} done", delivery.id);
+            delivery.strs.insert("error".to_string(), "".to_string());
+            STATUS_DELIVERED
+        }
+        Some(e) => {
+            delivery.strs.insert("error".to_string(), e.clone());
+            if attempts >= MAX_ATTEMPTS {
+                error!(
+                    "Webhook delivery {} failed {} times, giving up: {}",
+                    delivery.id, attempts, e
+                );
+                STATUS_DEAD
+            } else {
+                info!(
+                    "Webhook delivery {} failed, retrying later: {}",
+                    delivery.id, e
+                );
+                delivery
+                    .u64s
+                    .insert("next_attempt".to_string(), now + retry_delay(attempts));
+                STATUS_PENDING
+            }
+        }
+    };
+    delivery
+        .strs
+        .insert("status".to_string(), status.to_string());
+    srv.rw
+        .set_item(DELIVERY_COLLECTION, delivery, false)
+        .await?;
+    return Ok(());
+}
+
+/// Give up the delivery without further attempts
+async fn bury(srv: &mut Data, delivery: &mut Item, error: &str) -> StoreResult<()> {
+    delivery
+        .strs
+        .insert("status".to_string(), STATUS_DEAD.to_string());
+    delivery.strs.insert("error".to_string(), error.to_string());
+    srv.rw
+        .set_item(DELIVERY_COLLECTION, delivery, false)
+        .await?;
+    return Ok(());
+}
+
+/// Get deliveries due for an attempt along with their webhooks. Deliveries
+/// of removed or disabled webhooks become dead letters.
+async fn due_deliveries(srv: &mut Data) -> StoreResult<Vec<(Item, Webhook)>> {
+    srv.rw.ensure_collection(DELIVERY_COLLECTION).await?;
+    let now = Utc::now().timestamp();
+    let filter = Filter::eq("strs.status", Value::from(STATUS_PENDING)).and(Filter::Cmp(
+        "u64s.next_attempt".to_string(),
+        Cmp::Lte,
+        json!(now),
+    ));
+    let lr = srv
+        .rw
+        .get_all_items(DELIVERY_COLLECTION, "id", &filter)
+        .await?;
+    if lr.map.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let hooks = get_webhooks(srv).await?;
+    let mut due: Vec<(Item, Webhook)> = Vec::new();
+    for (_id, mut delivery) in lr.map {
+        let hook_id = *delivery.u64s.get("webhook_id").unwrap_or(&u64::MAX);
+        match hooks.get(&hook_id) {
+            Some(hook) if hook.active => due.push((delivery, hook.clone())),
+            Some(_hook) => bury(srv, &mut delivery, "Webhook is disabled").await?,
+            None => bury(srv, &mut delivery, "Webhook is removed").await?,
+        }
+    }
+    due.sort_by_key(|(delivery, _hook)| delivery.id);
+    return Ok(due);
+}
+
+/// Remove the oldest delivered deliveries beyond the log limit
+async fn trim_log(srv: &mut Data) -> StoreResult<()> {
+    let filter = Filter::eq("strs.status", Value::from(STATUS_DELIVERED));
+    let lr = srv
+        .rw
+        .get_all_items(DELIVERY_COLLECTION, "id", &filter)
+        .await?;
+    if lr.map.len() <= LOG_LIMIT {
+        return Ok(());
+    }
+
+    let mut ids: Vec<u64> = lr.map.into_keys().collect();
+    ids.sort();
+    ids.truncate(ids.len() - LOG_LIMIT);
+    srv.rw.del_items(DELIVERY_COLLECTION, &ids).await?;
+    return Ok(());
+}
+
+/// Record results of finished attempts. Webhook state is written with the
+/// next borrow if it's busy, so that nothing is delivered twice.
+async fn record_attempts(srv: &mut Data, attempts: &mut Vec<(Item, Attempt)>) {
+    for (mut delivery, attempt) in attempts.drain(..) {
+        if let Err(e) = record_attempt(srv, &mut delivery, &attempt).await {
+            error!("Failed to record webhook delivery {}: {}", delivery.id, e);
+        }
+    }
+    if let Err(e) = trim_log(srv).await {
+        error!("Failed to trim webhook delivery log: {}", e);
+    }
+}
+
+/// Attempt all deliveries that are due. The state isn't borrowed while
+/// targets are being contacted, and is left alone while another task
+/// sharing the thread borrows it.
+async fn deliver_due(
+    data: &web::Data<State>,
+    client: &reqwest::Client,
+    attempts: &mut Vec<(Item, Attempt)>,
+) {
+    let due = {
+        let srv_lock = data.server.lock();
+        let mut srv = match srv_lock.try_borrow_mut() {
+            Ok(srv) => srv,
+            Err(_) => return,
+        };
+        match due_deliveries(&mut srv).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to get webhook deliveries: {}", e);
+                return;
+            }
+        }
+    };
+    if due.is_empty() {
+        return;
+    }
+
+    for (delivery, hook) in due {
+        let attempt = send(client, &delivery, &hook).await;
+        attempts.push((delivery, attempt));
+    }
+
+    let srv_lock = data.server.lock();
+    if let Ok(mut srv) = srv_lock.try_borrow_mut() {
+        record_attempts(&mut srv, attempts).await;
+    }
+}
+
+/// Queue deliveries of item changes and deliver them until the event bus
+/// is gone
+pub async fn run_webhooks(data: web::Data<State>) {
+    let client = new_client();
+    let mut receiver = {
+        let srv_lock = data.server.lock();
+        let srv = srv_lock.borrow();
+        srv.events.subscribe()
+    };
+    let mut events: Vec<ItemEvent> = Vec::new();
+    let mut attempts: Vec<(Item, Attempt)> = Vec::new();
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => events.push(event),
+                Err(RecvError::Lagged(cnt)) => {
+                    error!("Webhooks missed {} events", cnt);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = rt::time::sleep(RETRY_CHECK_PERIOD) => {}
+        }
+
+        // Events and results are kept until the state can be borrowed
+        {
+            let srv_lock = data.server.lock();
+            let mut srv = match srv_lock.try_borrow_mut() {
+                Ok(srv) => srv,
+                Err(_) => continue,
+            };
+            for event in events.drain(..) {
+                if let Err(e) = enqueue(&mut srv, &event).await {
+                    error!(
+                        "Failed to queue webhook deliveries of {} item {}: {}",
+                        event.collection, event.id, e
+                    );
+                }
+            }
+            if !attempts.is_empty() {
+                record_attempts(&mut srv, &mut attempts).await;
+            }
+        }
+
+        deliver_due(&data, &client, &mut attempts).await;
+    }
+}